@@ -0,0 +1,47 @@
+extern crate gnunet;
+
+use std::net::SocketAddr;
+use gnunet::dns2gns::Dns2Gns;
+
+fn print_help(executable: String) {
+    println!("Usage: {} [listen-address [upstream-dns-server]]", executable);
+    println!("Defaults to listening on 127.0.0.1:5353 and forwarding to 9.9.9.9:53");
+}
+
+fn parse_addr(executable: &str, arg: Option<String>, default: &str) -> Option<SocketAddr> {
+    let arg = arg.unwrap_or(default.to_string());
+    match arg.parse() {
+        Ok(addr) => Some(addr),
+        Err(_)   => {
+            println!("Invalid address: {}", arg);
+            print_help(executable.to_string());
+            None
+        },
+    }
+}
+
+fn main() {
+    let mut args = std::env::args();
+    let executable = args.next().unwrap();
+    let listen = match parse_addr(&executable, args.next(), "127.0.0.1:5353") {
+        Some(addr) => addr,
+        None       => return,
+    };
+    let upstream = match parse_addr(&executable, args.next(), "9.9.9.9:53") {
+        Some(addr) => addr,
+        None       => return,
+    };
+    match args.next() {
+        Some(x) => {
+            println!("Unexpected argument: {}", x);
+            print_help(executable);
+            return;
+        },
+        None  => (),
+    }
+
+    let config = gnunet::Cfg::default().unwrap();
+    let server = Dns2Gns::connect(&config, upstream).unwrap();
+    println!("Resolving GNS names on {}, forwarding other queries to {}", listen, upstream);
+    server.run(listen).unwrap();
+}
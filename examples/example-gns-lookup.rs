@@ -3,7 +3,9 @@ extern crate gjio;
 extern crate gj;
 
 use gnunet::util::async;
+use gnunet::service::Deadline;
 use std::rc::Rc;
+use std::time::Duration;
 
 fn print_help(executable: String) {
     println!("Usage: {} domain.name.gnu", executable);
@@ -33,10 +35,16 @@ fn main() {
         let config = gnunet::Cfg::default().unwrap();
         let mut event_port = async::EventPort::new().unwrap();
         let network = event_port.get_network();
+        let deadline = Deadline::new(&event_port.get_timer(), Duration::from_secs(10).into());
 
-        let record_promise = gnunet::gns::lookup_in_master(&config, &network, Rc::new(domain), gnunet::gns::RecordType::A, None);
-        let record = record_promise.wait(wait_scope, &mut event_port).unwrap();
-        println!("{}", record);
+        let record_promise = gnunet::gns::lookup_in_master(&config, &network, Rc::new(domain), gnunet::gns::RecordType::A, None, deadline);
+        let records = record_promise.wait(wait_scope, &mut event_port).unwrap();
+        if records.is_empty() {
+            println!("No records found");
+        }
+        for record in records {
+            println!("{}", record);
+        }
         Ok(())
     }).expect("top level");
 }
@@ -0,0 +1,47 @@
+extern crate gnunet;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use gnunet::gns::socks::SocksProxy;
+use gnunet::service::Deadline;
+use gnunet::util::async;
+
+fn print_help(executable: String) {
+    println!("Usage: {} [listen-address]", executable);
+    println!("Defaults to listening on 127.0.0.1:1080");
+}
+
+fn main() {
+    let mut args = std::env::args();
+    let executable = args.next().unwrap();
+    let arg = args.next().unwrap_or("127.0.0.1:1080".to_string());
+    let listen: SocketAddr = match arg.parse() {
+        Ok(addr) => addr,
+        Err(_)   => {
+            println!("Invalid address: {}", arg);
+            print_help(executable);
+            return;
+        },
+    };
+    match args.next() {
+        Some(x) => {
+            println!("Unexpected argument: {}", x);
+            print_help(executable);
+            return;
+        },
+        None  => (),
+    }
+
+    async::EventLoop::top_level(move |wait_scope| -> Result<(), ::std::io::Error> {
+        let config = gnunet::Cfg::default().unwrap();
+        let mut event_port = async::EventPort::new().unwrap();
+        let network = event_port.get_network();
+        let deadline = Deadline::new(&event_port.get_timer(), Duration::from_secs(10).into());
+
+        let mut proxy = SocksProxy::connect(&config, &network).wait(wait_scope, &mut event_port).unwrap();
+        proxy.set_lookup_deadline(deadline);
+        let listener = try!(network.get_tcp_address(listen).listen());
+        println!("SOCKS5 proxy resolving GNS names listening on {}", listen);
+        proxy.serve(listener).wait(wait_scope, &mut event_port)
+    }).expect("top level");
+}
@@ -9,7 +9,7 @@ fn main() {
         let network = event_port.get_network();
 
         // identity example
-        let ego = gnunet::get_default_ego(&config, Rc::new("gns-master".to_string()), &network)
+        let ego = gnunet::get_default_ego(&config, Rc::new("gns-master".to_string()), &network, None)
             .wait(wait_scope, &mut event_port).unwrap();
         println!("{}", ego);
         Ok(())
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use Cfg;
+use EcdsaPublicKey;
+use gns::{Record, RecordType, LocalOptions, ConnectLookupError};
+use sync;
+
+/// A blocking handle to the GNS service.
+pub struct Gns {
+    cfg: Cfg,
+}
+
+impl Gns {
+    /// Create a handle to the GNS service configured in `cfg`.
+    pub fn new(cfg: &Cfg) -> Gns {
+        Gns {
+            cfg: cfg.clone(),
+        }
+    }
+
+    /// Lookup the records of type `record_type` for `name` in the zone `zone`, waiting at most
+    /// `timeout` for the result. See `sync::Gns::lookup`.
+    pub fn lookup(&self,
+                  name: &str,
+                  zone: EcdsaPublicKey,
+                  record_type: RecordType,
+                  options: LocalOptions,
+                  timeout: Duration) -> Result<Vec<Record>, ConnectLookupError> {
+        let gns = try!(sync::Gns::connect(&self.cfg));
+        Ok(try!(gns.lookup(name, zone, record_type, options, timeout)))
+    }
+}
@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use Cfg;
+use identity::{Ego, ConnectGetDefaultEgoError};
+use sync;
+
+/// A blocking handle to the identity service.
+pub struct Identity {
+    cfg: Cfg,
+}
+
+impl Identity {
+    /// Create a handle to the identity service configured in `cfg`.
+    pub fn new(cfg: &Cfg) -> Identity {
+        Identity {
+            cfg: cfg.clone(),
+        }
+    }
+
+    /// Get the default ego of the service `name`, eg. `"gns-master"`, waiting at most `timeout`.
+    /// See `sync::Identity::get_default_ego`.
+    pub fn get_default_ego(&self, name: &str, timeout: Duration) -> Result<Ego, ConnectGetDefaultEgoError> {
+        let identity = try!(sync::Identity::connect(&self.cfg));
+        Ok(try!(identity.get_default_ego(name, timeout)))
+    }
+}
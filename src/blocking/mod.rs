@@ -0,0 +1,66 @@
+//! Blocking versions of the client APIs, for scripts and command line tools.
+//!
+//! These are thin wrappers over the handles in `sync`: every call connects a new handle, waits for
+//! the reply until the timeout, then disconnects. This makes them convenient but slower than
+//! keeping a `sync` handle or a promise-based connection open. A call which does not complete in
+//! time fails with the `TimedOut` error of the underlying API, eg. `gns::LookupError::TimedOut`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use gnunet::Cfg;
+//! use gnunet::blocking;
+//! use gnunet::gns::{RecordType, LocalOptions};
+//!
+//! let config = Cfg::default().unwrap();
+//! let timeout = Duration::from_secs(5);
+//! let ego = blocking::Identity::new(&config).get_default_ego("gns-master", timeout).unwrap();
+//! let records = blocking::Gns::new(&config).lookup("gnu.org",
+//!                                                  ego.get_public_key(),
+//!                                                  RecordType::A,
+//!                                                  LocalOptions::LocalMaster,
+//!                                                  timeout).unwrap();
+//! for record in records {
+//!     println!("{}", record);
+//! }
+//! ```
+
+pub use self::gns::Gns;
+pub use self::identity::Identity;
+
+mod gns;
+mod identity;
+pub mod peerinfo;
+
+#[test]
+fn test_lookup_timeout() {
+    use std::io;
+    use std::time::Duration;
+    use gj::Promise;
+    use Cfg;
+    use std::str::FromStr;
+    use EcdsaPublicKey;
+    use gns::{ConnectLookupError, LookupError, LookupMessage, RecordType, LocalOptions};
+    use sync::Worker;
+    use testing::{MockService, TestPeer};
+
+    // a GNS service which accepts lookups but never answers them
+    let peer = Worker::spawn(|network| {
+        let mut service = MockService::new("gns");
+        service.on_message(|_: LookupMessage, _| Ok(()));
+        let mut peer = pry!(TestPeer::new());
+        pry!(peer.start(service, network));
+        Promise::<TestPeer, io::Error>::ok(peer)
+    }).unwrap();
+    let cfg = peer.call(|peer: &mut TestPeer, _| Promise::<Cfg, io::Error>::ok(peer.cfg().clone())).unwrap();
+
+    let zone = EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+    let res = Gns::new(&cfg).lookup("www.gnu", zone, RecordType::A, LocalOptions::Default,
+                                    Duration::from_millis(50));
+    match res {
+        Err(ConnectLookupError::Lookup { cause: LookupError::TimedOut }) => (),
+        Err(e) => panic!("expected a timeout, got: {}", e),
+        Ok(_) => panic!("the lookup was answered"),
+    }
+}
@@ -0,0 +1,24 @@
+//! Blocking queries of the peerinfo service.
+
+use std::time::Duration;
+
+use Cfg;
+use Hello;
+use peerinfo::PeerIdentity;
+use peerinfo::peerinfo::PeerInfoError;
+use sync;
+
+/// List all the peers known to the peerinfo service, waiting at most `timeout`. See
+/// `sync::PeerInfo::get_peers_vec`.
+pub fn list(cfg: &Cfg, timeout: Duration) -> Result<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+    let pis = try!(sync::PeerInfo::connect(cfg));
+    pis.get_peers_vec(timeout)
+}
+
+/// Get the peer with the identity `peer`, or `None` if the service does not know it, waiting at
+/// most `timeout`. See `sync::PeerInfo::get_peer`.
+pub fn get(cfg: &Cfg, peer: &PeerIdentity, timeout: Duration)
+           -> Result<Option<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+    let pis = try!(sync::PeerInfo::connect(cfg));
+    pis.get_peer(peer, timeout)
+}
@@ -78,6 +78,11 @@ error_def! CfgGetRelativeTimeError {
                 => "The value is not a valid relative time" ("Reason: {}", cause),
 }
 
+error_def! CfgGetStringError {
+    NoSection   => "The config does not contain a section with that name",
+    NoKey       => "The config section does not contain that key",
+}
+
 error_def! CfgGetFilenameError {
     NoSection   => "The config does not contain a section with that name",
     NoKey       => "The config section does contain that key",
@@ -293,6 +298,20 @@ impl Cfg {
         }
     }
 
+    /// Get the value of `key` in `section` as it is written in the config, without expanding
+    /// `$`-variables.
+    pub fn get_string(&self, section: &str, key: &str) -> Result<String, CfgGetStringError> {
+        use self::CfgGetStringError::*;
+
+        match self.data.get(section) {
+            Some(map) => match map.get(key) {
+                Some(value) => Ok(value.clone()),
+                None        => Err(NoKey),
+            },
+            None    => Err(NoSection),
+        }
+    }
+
     pub fn get_filename(&self, section: &str, key: &str) -> Result<PathBuf, CfgGetFilenameError> {
         use self::CfgGetFilenameError::*;
 
@@ -318,7 +337,7 @@ impl Cfg {
                 std::mem::swap(val, &mut value);
                 return Some(value);
             }
-            map.insert(section.into_owned(), value);
+            map.insert(key.into_owned(), value);
             return None;
         }
 
@@ -490,5 +509,30 @@ mod tests {
         let expanded = unwrap_result!(cfg.expand_dollar(unexpanded));
         assert_eq!(expanded, "foo in_paths in_env in_env_wub_blah");
     }
+
+    #[test]
+    fn test_get_string() {
+        let mut cfg = Cfg::empty();
+
+        assert!(cfg.set_string("gns", "HOSTNAME", String::from("localhost")).is_none());
+        assert!(cfg.set_string("gns", "PORT", String::from("2102")).is_none());
+        assert_eq!(cfg.set_string("gns", "PORT", String::from("2103")), Some(String::from("2102")));
+        assert_eq!(unwrap_result!(cfg.get_string("gns", "HOSTNAME")), "localhost");
+        assert_eq!(unwrap_result!(cfg.get_int("gns", "PORT")), 2103);
+        assert!(cfg.get_string("gns", "UNIXPATH").is_err());
+        assert!(cfg.get_string("identity", "HOSTNAME").is_err());
+    }
+
+    #[test]
+    fn test_set_string() {
+        let mut cfg = Cfg::empty();
+
+        // adding a key to a section which already exists must store it under the key
+        assert!(cfg.set_string("gns", "UNIXPATH", String::from("/tmp/gns.sock")).is_none());
+        assert!(cfg.set_string("gns", "PORT", String::from("2102")).is_none());
+        assert_eq!(unwrap_result!(cfg.get_string("gns", "PORT")), "2102");
+        assert_eq!(unwrap_result!(cfg.get_string("gns", "UNIXPATH")), "/tmp/gns.sock");
+        assert!(cfg.get_string("gns", "gns").is_err());
+    }
 }
 
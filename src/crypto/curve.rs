@@ -0,0 +1,208 @@
+//! Arithmetic on Ed25519, the twisted Edwards curve whose points are GNUnet's ECDSA public keys.
+//!
+//! None of this is constant time. It is only used on public values, to derive public keys and to
+//! verify signatures.
+
+use num::bigint::BigUint;
+use num::{One, Zero};
+
+/// A point in extended coordinates, `x = X/Z`, `y = Y/Z`, `x*y = T/Z`.
+#[derive(Clone)]
+pub struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+    t: BigUint,
+}
+
+/// The parameters of the curve.
+pub struct Curve {
+    p: BigUint,
+    d: BigUint,
+    d2: BigUint,
+    sqrt_m1: BigUint,
+    order: BigUint,
+}
+
+fn num(s: &str) -> BigUint {
+    BigUint::parse_bytes(s.as_bytes(), 10).unwrap()
+}
+
+/// `b` to the power `e` modulo `m`.
+pub fn pow_mod(b: &BigUint, e: &BigUint, m: &BigUint) -> BigUint {
+    let mut r = BigUint::one();
+    for byte in e.to_bytes_be() {
+        for i in (0..8).rev() {
+            r = &r * &r % m;
+            if (byte >> i) & 1 == 1 {
+                r = &r * b % m;
+            }
+        }
+    }
+    r
+}
+
+/// The inverse of `x` modulo the prime `m`.
+pub fn invert(x: &BigUint, m: &BigUint) -> BigUint {
+    pow_mod(x, &(m - num("2")), m)
+}
+
+fn is_odd(x: &BigUint) -> bool {
+    x.to_bytes_le()[0] & 1 == 1
+}
+
+impl Curve {
+    pub fn new() -> Curve {
+        let p = (BigUint::one() << 255) - num("19");
+        let d = &p - &num("121665") * invert(&num("121666"), &p) % &p;
+        let d2 = &d * num("2") % &p;
+        let sqrt_m1 = pow_mod(&num("2"), &((&p - BigUint::one()) >> 2), &p);
+        Curve {
+            p: p,
+            d: d,
+            d2: d2,
+            sqrt_m1: sqrt_m1,
+            order: (BigUint::one() << 252) + num("27742317777372353535851937790883648493"),
+        }
+    }
+
+    /// The number of points generated by the base point, a prime.
+    pub fn order(&self) -> &BigUint {
+        &self.order
+    }
+
+    /// The base point.
+    pub fn base(&self) -> Point {
+        let mut encoded = [0x66u8; 32];
+        encoded[0] = 0x58;
+        self.decode(&encoded).unwrap()
+    }
+
+    fn identity(&self) -> Point {
+        Point {
+            x: BigUint::zero(),
+            y: BigUint::one(),
+            z: BigUint::one(),
+            t: BigUint::zero(),
+        }
+    }
+
+    // a - b, both already reduced
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + &self.p - b) % &self.p
+    }
+
+    /// Decode a point in the compact form used by Ed25519 and GNUnet: `y` in little endian with
+    /// the lowest bit of `x` in the top bit. Returns `None` if this is not a point on the curve.
+    pub fn decode(&self, encoded: &[u8; 32]) -> Option<Point> {
+        let p = &self.p;
+        let mut bytes = *encoded;
+        let sign = bytes[31] >> 7 == 1;
+        bytes[31] &= 0x7f;
+        let y = BigUint::from_bytes_le(&bytes);
+        if y >= *p {
+            return None;
+        }
+        // x^2 = (y^2 - 1) / (d*y^2 + 1)
+        let y2 = &y * &y % p;
+        let u = self.sub(&y2, &BigUint::one());
+        let v = (&self.d * &y2 + BigUint::one()) % p;
+        let xx = &u * invert(&v, p) % p;
+        let mut x = pow_mod(&xx, &((p + num("3")) >> 3), p);
+        if &x * &x % p != xx {
+            x = &x * &self.sqrt_m1 % p;
+        }
+        if &x * &x % p != xx {
+            return None;
+        }
+        if x.is_zero() && sign {
+            return None;
+        }
+        if is_odd(&x) != sign {
+            x = p - &x;
+        }
+        let t = &x * &y % p;
+        Some(Point {
+            x: x,
+            y: y,
+            z: BigUint::one(),
+            t: t,
+        })
+    }
+
+    /// The affine coordinates of `point`.
+    pub fn affine(&self, point: &Point) -> (BigUint, BigUint) {
+        let zi = invert(&point.z, &self.p);
+        (&point.x * &zi % &self.p, &point.y * &zi % &self.p)
+    }
+
+    /// Encode `point` in compact form, see `decode`.
+    pub fn encode(&self, point: &Point) -> [u8; 32] {
+        let (x, y) = self.affine(point);
+        let mut encoded = [0u8; 32];
+        for (e, b) in encoded.iter_mut().zip(y.to_bytes_le()) {
+            *e = b;
+        }
+        if is_odd(&x) {
+            encoded[31] |= 0x80;
+        }
+        encoded
+    }
+
+    /// The sum of two points. The formula is complete, so this also doubles points.
+    pub fn add(&self, a: &Point, b: &Point) -> Point {
+        let p = &self.p;
+        let aa = self.sub(&a.y, &a.x) * self.sub(&b.y, &b.x) % p;
+        let bb = (&a.y + &a.x) * (&b.y + &b.x) % p;
+        let cc = &a.t * &self.d2 % p * &b.t % p;
+        let dd = &a.z * &b.z * num("2") % p;
+        let e = self.sub(&bb, &aa);
+        let f = self.sub(&dd, &cc);
+        let g = (&dd + &cc) % p;
+        let h = (&bb + &aa) % p;
+        Point {
+            x: &e * &f % p,
+            y: &g * &h % p,
+            z: &f * &g % p,
+            t: &e * &h % p,
+        }
+    }
+
+    /// `k` times `point`.
+    pub fn mul(&self, k: &BigUint, point: &Point) -> Point {
+        let mut r = self.identity();
+        for byte in k.to_bytes_be() {
+            for i in (0..8).rev() {
+                r = self.add(&r, &r);
+                if (byte >> i) & 1 == 1 {
+                    r = self.add(&r, point);
+                }
+            }
+        }
+        r
+    }
+}
+
+#[test]
+fn test_curve() {
+    let curve = Curve::new();
+    let base = curve.base();
+    let mut encoded = [0x66u8; 32];
+    encoded[0] = 0x58;
+    assert_eq!(curve.encode(&base), encoded);
+    assert_eq!(curve.encode(&curve.mul(curve.order(), &base)), curve.encode(&curve.identity()));
+    let two = curve.add(&base, &base);
+    assert_eq!(curve.encode(&curve.mul(&num("3"), &base)), curve.encode(&curve.add(&two, &base)));
+
+    // the public key of the first test vector of RFC 8032, from its clamped secret scalar
+    let k = BigUint::parse_bytes(b"4fe94d9006f020a5a3c080d96827fffd3c010ac0f12e7a42cb33284f86837c30", 16).unwrap();
+    let public = [0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a,
+                  0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a];
+    assert_eq!(curve.encode(&curve.mul(&k, &base)), public);
+    assert_eq!(curve.encode(&curve.decode(&public).unwrap()), public);
+
+    // there is no point with y = 2
+    let mut encoded = [0u8; 32];
+    encoded[0] = 2;
+    assert!(curve.decode(&encoded).is_none());
+}
@@ -3,12 +3,18 @@ use std::fmt::{self, Debug, Formatter};
 use std::str::from_utf8;
 use std::io::{self, Read, Write};
 
+use byteorder::{BigEndian, ByteOrder};
+use num::bigint::BigUint;
+use num::Zero;
+
+use crypto::curve::{self, Curve};
 use crypto::hashcode::HashCode;
+use crypto::kdf::kdf;
 use util::strings::{data_to_string, string_to_data};
 
 
 /// A 256bit ECDSA public key.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct EcdsaPublicKey {
     data: [u8; 32]
 }
@@ -19,10 +25,110 @@ impl EcdsaPublicKey {
         w.write_all(&self.data)
     }
 
+    /// Deserialize a key from a byte stream.
+    pub fn deserialize<T>(r: &mut T) -> Result<EcdsaPublicKey, io::Error> where T: Read {
+        let mut pk = EcdsaPublicKey {
+            data: [0; 32]
+        };
+        try!(r.read_exact(&mut pk.data[..]));
+        Ok(pk)
+    }
+
     /// Compute the hash of this key.
     pub fn hash(&self) -> HashCode {
         HashCode::from_buffer(&self.data)
     }
+
+    /// Derive the public key for `label` in `context` from this key, as
+    /// `GNUNET_CRYPTO_ecdsa_public_key_derive` does. GNS derives the key signing the records of a
+    /// label this way, with context `"gns"`.
+    pub fn derive(&self, label: &str, context: &str) -> Result<EcdsaPublicKey, EcdsaPublicKeyError> {
+        let curve = Curve::new();
+        let point = match curve.decode(&self.data) {
+            Some(point) => point,
+            None        => return Err(EcdsaPublicKeyError::NotOnCurve),
+        };
+        let mut hc = [0u8; 64];
+        kdf(&mut hc, b"key-derivation", &self.data, &[label.as_bytes(), context.as_bytes()]);
+        let h = BigUint::from_bytes_be(&hc) % curve.order();
+        Ok(EcdsaPublicKey {
+            data: curve.encode(&curve.mul(&h, &point)),
+        })
+    }
+
+    /// Check that `sig` is a signature by this key over `signed`, as
+    /// `GNUNET_CRYPTO_ecdsa_verify` does.
+    ///
+    /// `signed` is a GNUnet signature purpose: its size as a big-endian `u32` (including the
+    /// eight byte header), the purpose as a big-endian `u32` and then the signed data. Signatures
+    /// of data with a purpose other than `purpose` are rejected.
+    pub fn verify(&self, purpose: u32, signed: &[u8], sig: &EcdsaSignature) -> bool {
+        if signed.len() < 8
+            || BigEndian::read_u32(&signed[..4]) as usize != signed.len()
+            || BigEndian::read_u32(&signed[4..8]) != purpose
+        {
+            return false;
+        }
+        let curve = Curve::new();
+        let q = match curve.decode(&self.data) {
+            Some(q) => q,
+            None    => return false,
+        };
+        let n = curve.order();
+        let r = BigUint::from_bytes_be(&sig.r);
+        let s = BigUint::from_bytes_be(&sig.s);
+        if r.is_zero() || s.is_zero() || r >= *n || s >= *n {
+            return false;
+        }
+        // libgcrypt truncates the hash to the bit length of the group order
+        let e = BigUint::from_bytes_be(HashCode::from_buffer(signed).as_slice()) >> (512 - n.bits());
+        let w = curve::invert(&s, n);
+        let u1 = &e * &w % n;
+        let u2 = &r * &w % n;
+        let point = curve.add(&curve.mul(&u1, &curve.base()), &curve.mul(&u2, &q));
+        let (x, _) = curve.affine(&point);
+        x % n == r
+    }
+}
+
+/// Errors returned by operations on an `EcdsaPublicKey`.
+error_def! EcdsaPublicKeyError {
+    NotOnCurve => "The key is not a point on the curve",
+}
+
+/// A 512bit ECDSA signature.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct EcdsaSignature {
+    r: [u8; 32],
+    s: [u8; 32],
+}
+
+impl EcdsaSignature {
+    /// Serialize the signature to a byte stream.
+    pub fn serialize<T>(&self, w: &mut T) -> Result<(), io::Error> where T: Write {
+        try!(w.write_all(&self.r));
+        w.write_all(&self.s)
+    }
+
+    /// Deserialize a signature from a byte stream.
+    pub fn deserialize<T>(r: &mut T) -> Result<EcdsaSignature, io::Error> where T: Read {
+        let mut sig = EcdsaSignature {
+            r: [0; 32],
+            s: [0; 32],
+        };
+        try!(r.read_exact(&mut sig.r[..]));
+        try!(r.read_exact(&mut sig.s[..]));
+        Ok(sig)
+    }
+}
+
+impl Debug for EcdsaSignature {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut data = [0u8; 64];
+        data[..32].copy_from_slice(&self.r);
+        data[32..].copy_from_slice(&self.s);
+        fmt::Display::fmt(&data_to_string(&data), f)
+    }
 }
 
 /// Error generated when attempting to parse an ecdsa public key
@@ -130,3 +236,44 @@ fn test_ecdsa_to_from_string() {
     assert!(s0 == &s1[..]);
 }
 
+
+#[test]
+fn test_ecdsa_derive_verify() {
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    // the secret scalar of the first test vector of RFC 8032, stored big-endian as GNUnet does,
+    // and the public key the RFC gives for it
+    let scalar = from_hex("4fe94d9006f020a5a3c080d96827fffd3c010ac0f12e7a42cb33284f86837c30");
+    let private = EcdsaPrivateKey::deserialize(&mut &scalar[..]).unwrap();
+    let zone = private.get_public();
+    assert_eq!(zone.data.to_vec(), from_hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"));
+
+    // the derived public key belongs to the derived private key h*d, whose public key is computed
+    // by rust-crypto rather than by `Curve`
+    let derived = zone.derive("www", "gns").unwrap();
+    let curve = Curve::new();
+    let mut hc = [0u8; 64];
+    kdf(&mut hc, b"key-derivation", &zone.data, &[b"www", b"gns"]);
+    let h = BigUint::from_bytes_be(&hc) % curve.order();
+    let d = h * BigUint::from_bytes_be(&private.data) % curve.order();
+    let mut derived_private = EcdsaPrivateKey::zeros();
+    let d = d.to_bytes_be();
+    derived_private.data[32 - d.len()..].copy_from_slice(&d);
+    assert_eq!(derived, derived_private.get_public());
+    assert_eq!(derived.data.to_vec(), from_hex("332b1264b7538c1a2dbeb16dc66cdb1e9e55bbe3b9f03798e46c9ac7eb58e886"));
+
+    // "hello" with purpose 15, signed with the RFC 8032 key by a Python implementation of
+    // libgcrypt's ECDSA separate from this crate
+    let signed = [0, 0, 0, 13, 0, 0, 0, 15, b'h', b'e', b'l', b'l', b'o'];
+    let sig = EcdsaSignature::deserialize(&mut &from_hex(
+        "05e61b209d4e807f2a7baba72b98dcb9b3505f8b7f547a5ab238acc3ca818f35\
+         007bfb958c00b2568db4c0aec2dfa68d2f5011f388fd616772589ee3cb53367d")[..]).unwrap();
+    assert!(zone.verify(15, &signed, &sig));
+    assert!(!zone.verify(16, &signed, &sig));
+    assert!(!derived.verify(15, &signed, &sig));
+    let mut tampered = signed;
+    tampered[8] = b'j';
+    assert!(!zone.verify(15, &tampered, &sig));
+}
@@ -0,0 +1,19 @@
+//! GNUnet's key derivation function.
+
+use rcrypto::digest::Digest;
+use rcrypto::hkdf::{hkdf_extract, hkdf_expand};
+use rcrypto::sha2::{Sha256, Sha512};
+
+/// Fill `out` with key material derived from the source key material `skm`, the salt `xts` and
+/// the context chunks `ctx`, as `GNUNET_CRYPTO_kdf` does.
+///
+/// This is HKDF with HMAC-SHA512 for extraction and HMAC-SHA256 for expansion.
+pub fn kdf(out: &mut [u8], xts: &[u8], skm: &[u8], ctx: &[&[u8]]) {
+    let mut prk = [0u8; 64];
+    hkdf_extract(Sha512::new(), xts, skm, &mut prk);
+    let mut info = Vec::new();
+    for chunk in ctx {
+        info.extend_from_slice(chunk);
+    }
+    hkdf_expand(Sha256::new(), &prk, &info, out);
+}
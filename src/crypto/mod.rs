@@ -1,7 +1,11 @@
 pub use self::ecdsa::EcdsaPublicKey;
 pub use self::ecdsa::EcdsaPrivateKey;
+pub use self::ecdsa::EcdsaSignature;
 pub use self::hashcode::HashCode;
 
 pub mod ecdsa;
 pub mod hashcode;
-
+pub mod kdf;
+pub mod symmetric;
+mod curve;
+mod twofish;
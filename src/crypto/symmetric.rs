@@ -0,0 +1,93 @@
+//! GNUnet's symmetric encryption: AES-256 in CFB mode, then Twofish-256 in CFB mode.
+
+use rcrypto::aessafe::AesSafe256Encryptor;
+use rcrypto::symmetriccipher::BlockEncryptor;
+
+use crypto::twofish::Twofish;
+
+/// A session key, the AES key followed by the Twofish key.
+pub struct SymmetricSessionKey {
+    pub aes_key: [u8; 32],
+    pub twofish_key: [u8; 32],
+}
+
+/// An initialization vector, the AES IV followed by the Twofish IV.
+pub struct SymmetricInitializationVector {
+    pub aes_iv: [u8; 16],
+    pub twofish_iv: [u8; 16],
+}
+
+impl SymmetricSessionKey {
+    /// Split 64 bytes of key material into a session key.
+    pub fn from_bytes(bytes: &[u8; 64]) -> SymmetricSessionKey {
+        let mut key = SymmetricSessionKey {
+            aes_key: [0; 32],
+            twofish_key: [0; 32],
+        };
+        key.aes_key.copy_from_slice(&bytes[..32]);
+        key.twofish_key.copy_from_slice(&bytes[32..]);
+        key
+    }
+}
+
+impl SymmetricInitializationVector {
+    /// Split 32 bytes of key material into an initialization vector.
+    pub fn from_bytes(bytes: &[u8; 32]) -> SymmetricInitializationVector {
+        let mut iv = SymmetricInitializationVector {
+            aes_iv: [0; 16],
+            twofish_iv: [0; 16],
+        };
+        iv.aes_iv.copy_from_slice(&bytes[..16]);
+        iv.twofish_iv.copy_from_slice(&bytes[16..]);
+        iv
+    }
+}
+
+/// Encrypt `data` as `GNUNET_CRYPTO_symmetric_encrypt` does.
+pub fn encrypt(data: &[u8], key: &SymmetricSessionKey, iv: &SymmetricInitializationVector) -> Vec<u8> {
+    let mut data = data.to_vec();
+    cfb(&AesSafe256Encryptor::new(&key.aes_key), &iv.aes_iv, &mut data, true);
+    cfb(&Twofish::new(&key.twofish_key), &iv.twofish_iv, &mut data, true);
+    data
+}
+
+/// Decrypt `data` as `GNUNET_CRYPTO_symmetric_decrypt` does.
+pub fn decrypt(data: &[u8], key: &SymmetricSessionKey, iv: &SymmetricInitializationVector) -> Vec<u8> {
+    let mut data = data.to_vec();
+    cfb(&Twofish::new(&key.twofish_key), &iv.twofish_iv, &mut data, false);
+    cfb(&AesSafe256Encryptor::new(&key.aes_key), &iv.aes_iv, &mut data, false);
+    data
+}
+
+// CFB mode with a full block of feedback, in place
+fn cfb<C: BlockEncryptor>(cipher: &C, iv: &[u8; 16], data: &mut [u8], encrypt: bool) {
+    let mut feedback = *iv;
+    let mut stream = [0u8; 16];
+    for chunk in data.chunks_mut(16) {
+        cipher.encrypt_block(&feedback, &mut stream);
+        for (i, b) in chunk.iter_mut().enumerate() {
+            let c = *b ^ stream[i];
+            feedback[i] = if encrypt { c } else { *b };
+            *b = c;
+        }
+    }
+}
+
+#[test]
+fn test_cfb() {
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    // the CFB128-AES256 vector of NIST SP 800-38A, F.3.17
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&from_hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4"));
+    let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    let plain = from_hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+    let cipher = from_hex("dc7e84bfda79164b7ecd8486985d386039ffed143b28b1c832113c6331e5407b");
+    let mut data = plain.clone();
+    cfb(&AesSafe256Encryptor::new(&key), &iv, &mut data, true);
+    assert_eq!(data, cipher);
+    cfb(&AesSafe256Encryptor::new(&key), &iv, &mut data, false);
+    assert_eq!(data, plain);
+}
@@ -0,0 +1,201 @@
+//! The Twofish block cipher, which GNUnet layers over AES for symmetric encryption.
+//!
+//! rust-crypto does not implement Twofish. Only encryption is needed since GNUnet uses it in CFB
+//! mode.
+
+use byteorder::{LittleEndian, ByteOrder};
+use rcrypto::symmetriccipher::BlockEncryptor;
+
+// the 4-bit permutations the q0 and q1 S-boxes are built from
+const Q0_T: [[u8; 16]; 4] = [
+    [0x8, 0x1, 0x7, 0xD, 0x6, 0xF, 0x3, 0x2, 0x0, 0xB, 0x5, 0x9, 0xE, 0xC, 0xA, 0x4],
+    [0xE, 0xC, 0xB, 0x8, 0x1, 0x2, 0x3, 0x5, 0xF, 0x4, 0xA, 0x6, 0x7, 0x0, 0x9, 0xD],
+    [0xB, 0xA, 0x5, 0xE, 0x6, 0xD, 0x9, 0x0, 0xC, 0x8, 0xF, 0x3, 0x2, 0x4, 0x7, 0x1],
+    [0xD, 0x7, 0xF, 0x4, 0x1, 0x2, 0x6, 0xE, 0x9, 0xB, 0x3, 0x0, 0x8, 0x5, 0xC, 0xA],
+];
+const Q1_T: [[u8; 16]; 4] = [
+    [0x2, 0x8, 0xB, 0xD, 0xF, 0x7, 0x6, 0xE, 0x3, 0x1, 0x9, 0x4, 0x0, 0xA, 0xC, 0x5],
+    [0x1, 0xE, 0x2, 0xB, 0x4, 0xC, 0x3, 0x7, 0x6, 0xD, 0xA, 0x5, 0xF, 0x9, 0x0, 0x8],
+    [0x4, 0xC, 0x7, 0x5, 0x1, 0x6, 0x9, 0xA, 0x0, 0xE, 0xD, 0x8, 0x2, 0xB, 0x3, 0xF],
+    [0xB, 0x9, 0x5, 0x1, 0xC, 0x3, 0xD, 0xE, 0x6, 0x4, 0x7, 0xF, 0x2, 0x0, 0x8, 0xA],
+];
+
+const MDS: [[u8; 4]; 4] = [
+    [0x01, 0xEF, 0x5B, 0x5B],
+    [0x5B, 0xEF, 0xEF, 0x01],
+    [0xEF, 0x5B, 0x01, 0xEF],
+    [0xEF, 0x01, 0xEF, 0x5B],
+];
+
+const RS: [[u8; 8]; 4] = [
+    [0x01, 0xA4, 0x55, 0x87, 0x5A, 0x58, 0xDB, 0x9E],
+    [0xA4, 0x56, 0x82, 0xF3, 0x1E, 0xC6, 0x68, 0xE5],
+    [0x02, 0xA1, 0xFC, 0xC1, 0x47, 0xAE, 0x3D, 0x19],
+    [0xA4, 0x55, 0x87, 0x5A, 0x58, 0xDB, 0x9E, 0x03],
+];
+
+fn ror4(x: u8, n: u32) -> u8 {
+    ((x >> n) | (x << (4 - n))) & 0xf
+}
+
+fn q_perm(t: &[[u8; 16]; 4], x: u8) -> u8 {
+    let (a0, b0) = (x >> 4, x & 0xf);
+    let (a1, b1) = (a0 ^ b0, a0 ^ ror4(b0, 1) ^ ((8 * a0) & 0xf));
+    let (a2, b2) = (t[0][a1 as usize], t[1][b1 as usize]);
+    let (a3, b3) = (a2 ^ b2, a2 ^ ror4(b2, 1) ^ ((8 * a2) & 0xf));
+    let (a4, b4) = (t[2][a3 as usize], t[3][b3 as usize]);
+    16 * b4 + a4
+}
+
+// multiplication in GF(2^8) modulo `poly`
+fn gf_mul(mut a: u8, mut b: u8, poly: u16) -> u8 {
+    let mut r = 0;
+    while b != 0 {
+        if b & 1 == 1 {
+            r ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= (poly & 0xff) as u8;
+        }
+        b >>= 1;
+    }
+    r
+}
+
+fn byte(x: u32, i: usize) -> u8 {
+    (x >> (8 * i)) as u8
+}
+
+/// A Twofish key schedule, for keys of 128, 192 or 256 bits.
+pub struct Twofish {
+    q0: [u8; 256],
+    q1: [u8; 256],
+    k: [u32; 40],
+    s: Vec<u32>,
+}
+
+impl Twofish {
+    /// Create the key schedule for `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is not 16, 24 or 32 bytes long.
+    pub fn new(key: &[u8]) -> Twofish {
+        assert!(key.len() == 16 || key.len() == 24 || key.len() == 32);
+        let mut tf = Twofish {
+            q0: [0; 256],
+            q1: [0; 256],
+            k: [0; 40],
+            s: Vec::new(),
+        };
+        for x in 0..256 {
+            tf.q0[x] = q_perm(&Q0_T, x as u8);
+            tf.q1[x] = q_perm(&Q1_T, x as u8);
+        }
+
+        let mut me = Vec::new();
+        let mut mo = Vec::new();
+        for chunk in key.chunks(8) {
+            me.push(LittleEndian::read_u32(&chunk[..4]));
+            mo.push(LittleEndian::read_u32(&chunk[4..]));
+            let mut s = 0u32;
+            for (r, rs) in RS.iter().enumerate() {
+                let mut x = 0;
+                for (c, b) in chunk.iter().enumerate() {
+                    x ^= gf_mul(rs[c], *b, 0x14D);
+                }
+                s |= (x as u32) << (8 * r);
+            }
+            tf.s.insert(0, s);
+        }
+
+        let rho = 0x01010101u32;
+        for i in 0..20 {
+            let a = tf.h(2 * i * rho, &me);
+            let b = tf.h((2 * i + 1) * rho, &mo).rotate_left(8);
+            tf.k[2 * i as usize] = a.wrapping_add(b);
+            tf.k[2 * i as usize + 1] = a.wrapping_add(b.wrapping_mul(2)).rotate_left(9);
+        }
+        tf
+    }
+
+    fn h(&self, x: u32, l: &[u32]) -> u32 {
+        let (q0, q1) = (&self.q0, &self.q1);
+        let mut y = [byte(x, 0), byte(x, 1), byte(x, 2), byte(x, 3)];
+        if l.len() == 4 {
+            y = [q1[y[0] as usize] ^ byte(l[3], 0),
+                 q0[y[1] as usize] ^ byte(l[3], 1),
+                 q0[y[2] as usize] ^ byte(l[3], 2),
+                 q1[y[3] as usize] ^ byte(l[3], 3)];
+        }
+        if l.len() >= 3 {
+            y = [q1[y[0] as usize] ^ byte(l[2], 0),
+                 q1[y[1] as usize] ^ byte(l[2], 1),
+                 q0[y[2] as usize] ^ byte(l[2], 2),
+                 q0[y[3] as usize] ^ byte(l[2], 3)];
+        }
+        y = [q1[(q0[(q0[y[0] as usize] ^ byte(l[1], 0)) as usize] ^ byte(l[0], 0)) as usize],
+             q0[(q0[(q1[y[1] as usize] ^ byte(l[1], 1)) as usize] ^ byte(l[0], 1)) as usize],
+             q1[(q1[(q0[y[2] as usize] ^ byte(l[1], 2)) as usize] ^ byte(l[0], 2)) as usize],
+             q0[(q1[(q1[y[3] as usize] ^ byte(l[1], 3)) as usize] ^ byte(l[0], 3)) as usize]];
+
+        let mut z = 0u32;
+        for (i, row) in MDS.iter().enumerate() {
+            let mut zi = 0;
+            for (j, m) in row.iter().enumerate() {
+                zi ^= gf_mul(*m, y[j], 0x169);
+            }
+            z |= (zi as u32) << (8 * i);
+        }
+        z
+    }
+}
+
+impl BlockEncryptor for Twofish {
+    fn block_size(&self) -> usize {
+        16
+    }
+
+    fn encrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        let k = &self.k;
+        let mut r = [0u32; 4];
+        for i in 0..4 {
+            r[i] = LittleEndian::read_u32(&input[4 * i..]) ^ k[i];
+        }
+        for round in 0..16 {
+            let t0 = self.h(r[0], &self.s);
+            let t1 = self.h(r[1].rotate_left(8), &self.s);
+            let f0 = t0.wrapping_add(t1).wrapping_add(k[2 * round + 8]);
+            let f1 = t0.wrapping_add(t1.wrapping_mul(2)).wrapping_add(k[2 * round + 9]);
+            r = [(r[2] ^ f0).rotate_right(1), r[3].rotate_left(1) ^ f1, r[0], r[1]];
+        }
+        // undo the swap of the last round
+        let r = [r[2], r[3], r[0], r[1]];
+        for i in 0..4 {
+            LittleEndian::write_u32(&mut output[4 * i..], r[i] ^ k[i + 4]);
+        }
+    }
+}
+
+#[test]
+fn test_twofish() {
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+    let vectors = [
+        ("00000000000000000000000000000000",
+         "9F589F5CF6122C32B6BFEC2F2AE8C35A"),
+        ("0123456789ABCDEFFEDCBA98765432100011223344556677",
+         "CFD1D2E5A9BE9CDF501F13B892BD2248"),
+        ("0123456789ABCDEFFEDCBA987654321000112233445566778899AABBCCDDEEFF",
+         "37527BE0052334B89F0CFCCAE87CFA20"),
+    ];
+    for &(key, cipher) in vectors.iter() {
+        let tf = Twofish::new(&from_hex(key));
+        let mut out = [0u8; 16];
+        tf.encrypt_block(&[0u8; 16], &mut out);
+        assert_eq!(&out[..], &from_hex(cipher)[..]);
+    }
+}
@@ -0,0 +1,456 @@
+//! A DNS server which answers queries for GNS names, so that applications which only speak DNS
+//! can resolve them.
+//!
+//! Queries for names under a GNS TLD are resolved with `GNS::lookup`, the DNS QTYPE being used as
+//! the GNS record type and ANY queries asking for the records of every type. These are names
+//! ending in `.zkey`, names under a TLD mapped to a zone in the `[gns]` section of the
+//! configuration (see `gns::resolver`) and names under the TLDs added with `Dns2Gns::add_tld`
+//! (`.gnu` by default), which are looked up in the master zone. All other queries are forwarded
+//! unchanged to an upstream DNS server, or answered with SERVFAIL if it does not answer. Only
+//! standard queries of the IN class are answered, anything else gets NOTIMP.
+//!
+//! Queries are served over both UDP and TCP. UDP queries are answered by a fixed number of threads,
+//! TCP connections each get their own thread, up to a fixed number of connections beyond which
+//! new ones are closed. GNS lookups go through a single connection to the GNS service shared by
+//! all threads.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use gnunet::Cfg;
+//! use gnunet::dns2gns::Dns2Gns;
+//!
+//! let config = Cfg::default().unwrap();
+//! let server = Dns2Gns::connect(&config, "9.9.9.9:53".parse().unwrap()).unwrap();
+//! server.run("127.0.0.1:5353".parse().unwrap()).unwrap();
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::u16;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use Cfg;
+use EcdsaPublicKey;
+use identity;
+use service;
+use sync;
+use time;
+use dnsparser::{self, Data, Flags, Packet};
+use gns::{self, Record, RecordType, Resolution, ZoneError};
+
+/// Errors returned by `Dns2Gns::connect`.
+error_def! Dns2GnsError {
+    IdentityConnect { #[from] cause: identity::ConnectError }
+        => "Failed to connect to the identity service" ("Reason: {}", cause),
+    GetDefaultEgo { #[from] cause: identity::GetDefaultEgoError }
+        => "Failed to retrieve the default identity for gns-master" ("Reason: {}", cause),
+    GnsConnect { #[from] cause: service::ConnectError }
+        => "Failed to connect to the GNS service" ("Reason: {}", cause),
+}
+
+/// A DNS server resolving GNS names.
+pub struct Dns2Gns {
+    gns: sync::Gns,
+    cfg: Cfg,
+    master: EcdsaPublicKey,
+    upstream: SocketAddr,
+    timeout: Duration,
+    lookup_timeout: Duration,
+    tlds: Vec<String>,
+}
+
+impl Dns2Gns {
+    /// Connect to the GNS service configured in `cfg` and fetch the master zone. Names not in GNS
+    /// are forwarded to the DNS server at `upstream`.
+    pub fn connect(cfg: &Cfg, upstream: SocketAddr) -> Result<Dns2Gns, Dns2GnsError> {
+        let timeout = Duration::from_secs(5);
+        let ego = try!(try!(sync::Identity::connect(cfg)).get_default_ego("gns-master", timeout));
+        let gns = try!(sync::Gns::connect(cfg));
+        Ok(Dns2Gns {
+            gns: gns,
+            cfg: cfg.clone(),
+            master: ego.get_public_key(),
+            upstream: upstream,
+            timeout: timeout,
+            lookup_timeout: timeout,
+            tlds: vec!["gnu".to_string()],
+        })
+    }
+
+    /// Resolve the names under `tld` in the master zone.
+    pub fn add_tld(&mut self, tld: &str) {
+        let tld = tld.trim_matches('.').to_lowercase();
+        if !self.tlds.contains(&tld) {
+            self.tlds.push(tld);
+        }
+    }
+
+    /// How long to wait for the upstream server to answer a forwarded query. Five seconds by
+    /// default.
+    pub fn set_upstream_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// How long to wait for the GNS service to answer a lookup before answering the query with
+    /// SERVFAIL. Five seconds by default.
+    pub fn set_lookup_timeout(&mut self, timeout: Duration) {
+        self.lookup_timeout = timeout;
+    }
+
+    /// Answer the DNS message `query`, received over TCP if `tcp` is true. Returns `None` if the
+    /// message should be ignored because it is not a query. Queries which could not be forwarded
+    /// or looked up are answered with SERVFAIL.
+    pub fn answer(&self, query: &[u8], tcp: bool) -> Option<Vec<u8>> {
+        let max_len = if tcp { u16::MAX as usize } else { dnsparser::MAX_UDP_SIZE };
+        let packet = match Packet::parse(query) {
+            Ok(packet) => packet,
+            // anything with the header of a query gets an answer, as from any DNS server
+            Err(_) if query.len() >= 12 && query[2] & 0x80 == 0 => {
+                let mut response = Packet::new(BigEndian::read_u16(query));
+                response.flags.response = true;
+                response.flags.return_code = dnsparser::RCODE_FORMAT_ERROR;
+                return response.build(max_len).ok();
+            },
+            Err(_) => return None,
+        };
+        if packet.flags.response {
+            return None;
+        }
+        if packet.queries.len() != 1 {
+            return reply(&packet, dnsparser::RCODE_FORMAT_ERROR, Vec::new()).build(max_len).ok();
+        }
+        let q = packet.queries[0].clone();
+        if packet.flags.opcode != dnsparser::OPCODE_QUERY || q.qclass != dnsparser::CLASS_IN {
+            return reply(&packet, dnsparser::RCODE_NOT_IMPLEMENTED, Vec::new()).build(max_len).ok();
+        }
+        let resolution = match self.resolution(&q.name) {
+            Ok(Some(resolution)) => resolution,
+            Ok(None)             => {
+                let res = if tcp { self.forward_tcp(query) } else { self.forward_udp(query) };
+                return match res {
+                    Ok(response) => Some(response),
+                    Err(_)       => reply(&packet, dnsparser::RCODE_SERVER_FAILURE, Vec::new()).build(max_len).ok(),
+                };
+            },
+            Err(_)               => {
+                return reply(&packet, dnsparser::RCODE_NAME_ERROR, Vec::new()).build(max_len).ok();
+            },
+        };
+
+        let record_type = match q.qtype {
+            dnsparser::TYPE_ANY => RecordType::Other(gns::RECORD_TYPE_ANY),
+            qtype               => RecordType::from_u32(qtype as u32),
+        };
+        let records = match self.gns.lookup(&resolution.name, resolution.zone, record_type, resolution.options, self.lookup_timeout) {
+            Ok(records) => records,
+            Err(_)      => {
+                return reply(&packet, dnsparser::RCODE_SERVER_FAILURE, Vec::new()).build(max_len).ok();
+            },
+        };
+        let now = time::Absolute::now();
+        let answers = gns::live_records(&records, now).iter().filter_map(|record| {
+            // values of types which only exist in GNS cannot be sent
+            let data = match record.value().ok().and_then(|value| Data::from_gns(&value)) {
+                Some(data) => data,
+                None       => return None,
+            };
+            Some(dnsparser::Record {
+                name: q.name.clone(),
+                class: dnsparser::CLASS_IN,
+                ttl: ttl(record, now),
+                data: data,
+            })
+        }).collect();
+        reply(&packet, dnsparser::RCODE_NO_ERROR, answers).build(max_len).ok()
+    }
+
+    /// Serve DNS queries arriving on `socket` until receiving from it fails.
+    pub fn serve_udp(self, socket: UdpSocket) -> io::Result<()> {
+        serve_udp(Arc::new(self), socket)
+    }
+
+    /// Serve DNS queries on the connections accepted by `listener` until accepting fails.
+    pub fn serve_tcp(self, listener: TcpListener) -> io::Result<()> {
+        serve_tcp(Arc::new(self), listener)
+    }
+
+    /// Serve DNS queries over UDP and TCP on `addr`.
+    pub fn run(self, addr: SocketAddr) -> io::Result<()> {
+        let socket = try!(UdpSocket::bind(addr));
+        let listener = try!(TcpListener::bind(addr));
+        let server = Arc::new(self);
+        let tcp_server = server.clone();
+        try!(thread::Builder::new().name("dns2gns-tcp".to_string()).spawn(move || {
+            serve_tcp(tcp_server, listener)
+        }));
+        serve_udp(server, socket)
+    }
+
+    // the zone to look `name` up in, or `None` for names which are not in GNS
+    fn resolution(&self, name: &str) -> Result<Option<Resolution>, ZoneError> {
+        if let Some(resolution) = try!(gns::zone_for_name(&self.cfg, name)) {
+            return Ok(Some(resolution));
+        }
+        let name = name.trim_right_matches('.');
+        let tld = name.rsplit('.').next().unwrap_or("").to_lowercase();
+        if self.tlds.contains(&tld) {
+            Ok(Some(Resolution::in_master(name, self.master)))
+        }
+        else {
+            Ok(None)
+        }
+    }
+
+    fn forward_udp(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let local = match self.upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = try!(UdpSocket::bind(local));
+        try!(socket.set_read_timeout(Some(self.timeout)));
+        try!(socket.send_to(query, self.upstream));
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let (n, from) = try!(socket.recv_from(&mut buf));
+            // ignore anything which is not the answer to our query
+            if from == self.upstream && n >= 2 && buf[..2] == query[..2] {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+        }
+    }
+
+    fn forward_tcp(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = try!(TcpStream::connect(self.upstream));
+        try!(stream.set_read_timeout(Some(self.timeout)));
+        try!(write_tcp_message(&mut stream, query));
+        match try!(read_tcp_message(&mut stream)) {
+            Some(response) => Ok(response),
+            None           => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the upstream server closed the connection")),
+        }
+    }
+}
+
+// every thread answers one query at a time, the queries arriving while all of them are busy wait
+// in the socket's receive buffer
+fn serve_udp(server: Arc<Dns2Gns>, socket: UdpSocket) -> io::Result<()> {
+    for i in 1..UDP_THREADS {
+        let server = server.clone();
+        let socket = try!(socket.try_clone());
+        try!(thread::Builder::new().name(format!("dns2gns-udp-{}", i)).spawn(move || {
+            answer_udp(&server, &socket)
+        }));
+    }
+    answer_udp(&server, &socket)
+}
+
+fn answer_udp(server: &Dns2Gns, socket: &UdpSocket) -> io::Result<()> {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        let (n, from) = try!(socket.recv_from(&mut buf));
+        if let Some(response) = server.answer(&buf[..n], false) {
+            let _ = socket.send_to(&response, from);
+        }
+    }
+}
+
+const UDP_THREADS: usize = 8;
+
+// every connection gets its own thread, connections accepted while `TCP_CONNECTIONS` are open are
+// closed right away
+fn serve_tcp(server: Arc<Dns2Gns>, listener: TcpListener) -> io::Result<()> {
+    let open = Arc::new(AtomicUsize::new(0));
+    loop {
+        let (mut stream, _) = try!(listener.accept());
+        if open.fetch_add(1, Ordering::SeqCst) >= TCP_CONNECTIONS {
+            open.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+        let connection = Connection(open.clone());
+        let server = server.clone();
+        // if the thread cannot be started the connection is closed and no longer counted
+        let _ = thread::Builder::new().name("dns2gns-tcp-connection".to_string()).spawn(move || -> io::Result<()> {
+            let _connection = connection;
+            try!(stream.set_read_timeout(Some(Duration::from_secs(TCP_IDLE_SECS))));
+            // answer queries in order until the client closes the connection
+            while let Some(query) = try!(read_tcp_message(&mut stream)) {
+                if let Some(response) = server.answer(&query, true) {
+                    try!(write_tcp_message(&mut stream, &response));
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+// DNS messages sent over TCP are preceded by their length
+fn read_tcp_message<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let len = match r.read_u16::<BigEndian>() {
+        Ok(len) => len as usize,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut buf = vec![0u8; len];
+    try!(r.read_exact(&mut buf));
+    Ok(Some(buf))
+}
+
+fn write_tcp_message<W: Write>(w: &mut W, msg: &[u8]) -> io::Result<()> {
+    if msg.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "DNS message too long"));
+    }
+    try!(w.write_u16::<BigEndian>(msg.len() as u16));
+    w.write_all(msg)
+}
+
+const TCP_IDLE_SECS: u64 = 30;
+
+const TCP_CONNECTIONS: usize = 32;
+
+// an open TCP connection, counted in `serve_tcp` until dropped
+struct Connection(Arc<AtomicUsize>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// a reply to `query` from a recursive resolver
+fn reply(query: &Packet, rcode: u8, answers: Vec<dnsparser::Record>) -> Packet {
+    Packet {
+        id: query.id,
+        flags: Flags {
+            response: true,
+            opcode: query.flags.opcode,
+            recursion_desired: query.flags.recursion_desired,
+            recursion_available: true,
+            return_code: rcode,
+            ..Flags::default()
+        },
+        queries: query.queries.clone(),
+        answers: answers,
+        authority: Vec::new(),
+        additional: Vec::new(),
+    }
+}
+
+fn ttl(record: &Record, now: time::Absolute) -> u32 {
+    let secs = record.expires_at(now).remaining(now).as_micros() / 1_000_000;
+    if secs > i32::max_value() as u64 { i32::max_value() as u32 } else { secs as u32 }
+}
+
+#[test]
+fn test_reply() {
+    use std::net::Ipv4Addr;
+
+    // a query for the AAAA records of www.gnu with recursion desired
+    let query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0,
+                     3, b'w', b'w', b'w', 3, b'g', b'n', b'u', 0, 0, 28, 0, 1];
+    let packet = Packet::parse(&query).unwrap();
+    let answer = dnsparser::Record {
+        name: "www.gnu".to_string(),
+        class: dnsparser::CLASS_IN,
+        ttl: 60,
+        data: Data::A(Ipv4Addr::new(10, 0, 0, 1)),
+    };
+    let response = reply(&packet, dnsparser::RCODE_NO_ERROR, vec![answer]).build(dnsparser::MAX_UDP_SIZE).unwrap();
+    assert_eq!(&response[..4], &[0x12, 0x34, 0x81, 0x80]);
+    assert_eq!(&response[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(&response[12..query.len()], &query[12..]);
+    assert_eq!(&response[query.len()..], &[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+}
+
+#[test]
+fn test_answer() {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use gj::Promise;
+    use EcdsaPrivateKey;
+    use gns::RecordFlags;
+    use sync::Worker;
+    use testing::{self, TestPeer};
+
+    // the mock services run on a worker thread while `answer` blocks this one
+    let peer = Worker::spawn(|network| {
+        let mut records = HashMap::new();
+        let value = gns::RecordData::A(Ipv4Addr::new(10, 0, 0, 1));
+        records.insert("www.gnu".to_string(), vec![Record::from_value(&value, u64::max_value(), RecordFlags::empty()).unwrap()]);
+        let mut defaults = HashMap::new();
+        defaults.insert("gns-master".to_string(), "master".to_string());
+        let egos = vec![("master".to_string(), EcdsaPrivateKey::zeros())];
+
+        let mut peer = pry!(TestPeer::new());
+        pry!(peer.start(testing::gns(records), network));
+        pry!(peer.start(testing::identity(egos, defaults), network));
+        Promise::<TestPeer, io::Error>::ok(peer)
+    }).unwrap();
+    let cfg = peer.call(|peer: &mut TestPeer, _| Promise::<Cfg, io::Error>::ok(peer.cfg().clone())).unwrap();
+    // the upstream server does not exist, nothing forwarded is answered
+    let mut server = Dns2Gns::connect(&cfg, "127.0.0.1:9".parse().unwrap()).unwrap();
+    server.set_upstream_timeout(Duration::from_millis(100));
+    let server = Arc::new(server);
+
+    let query = |name: &str, qtype: u16, qclass: u16| {
+        let mut packet = Packet::new(0x1234);
+        packet.queries.push(dnsparser::Query {
+            name: name.to_string(),
+            qtype: qtype,
+            qclass: qclass,
+        });
+        let response = server.answer(&packet.build(dnsparser::MAX_UDP_SIZE).unwrap(), false).unwrap();
+        Packet::parse(&response).unwrap()
+    };
+
+    let response = query("www.gnu", dnsparser::TYPE_A, dnsparser::CLASS_IN);
+    assert_eq!(response.id, 0x1234);
+    assert!(response.flags.response);
+    assert_eq!(response.flags.return_code, dnsparser::RCODE_NO_ERROR);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].name, "www.gnu");
+    assert_eq!(response.answers[0].data, Data::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+    // ANY queries get the records of every type
+    let response = query("www.gnu", dnsparser::TYPE_ANY, dnsparser::CLASS_IN);
+    assert_eq!(response.flags.return_code, dnsparser::RCODE_NO_ERROR);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].data, Data::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+    let response = query("ftp.gnu", dnsparser::TYPE_A, dnsparser::CLASS_IN);
+    assert_eq!(response.flags.return_code, dnsparser::RCODE_NO_ERROR);
+    assert!(response.answers.is_empty());
+
+    let response = query("www.gnu", dnsparser::TYPE_A, 3);
+    assert_eq!(response.flags.return_code, dnsparser::RCODE_NOT_IMPLEMENTED);
+
+    // the class is checked before the name
+    let response = query("www.XYZ.zkey", dnsparser::TYPE_A, 3);
+    assert_eq!(response.flags.return_code, dnsparser::RCODE_NOT_IMPLEMENTED);
+    let response = query("www.XYZ.zkey", dnsparser::TYPE_A, dnsparser::CLASS_IN);
+    assert_eq!(response.flags.return_code, dnsparser::RCODE_NAME_ERROR);
+
+    let response = query("example.org", dnsparser::TYPE_A, dnsparser::CLASS_IN);
+    assert_eq!(response.id, 0x1234);
+    assert_eq!(response.flags.return_code, dnsparser::RCODE_SERVER_FAILURE);
+
+    // responses are ignored
+    let mut packet = Packet::new(1);
+    packet.flags.response = true;
+    assert!(server.answer(&packet.build(dnsparser::MAX_UDP_SIZE).unwrap(), false).is_none());
+
+    // TCP connections over the limit are closed right away
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tcp_server = server.clone();
+    thread::spawn(move || serve_tcp(tcp_server, listener));
+    let open: Vec<_> = (0..TCP_CONNECTIONS).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    let mut closed = TcpStream::connect(addr).unwrap();
+    closed.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(closed.read(&mut [0; 2]).unwrap(), 0);
+    drop(open);
+}
@@ -0,0 +1,797 @@
+//! Parsing and building DNS packets.
+//!
+//! This is a Rust version of GNUnet's dnsparser library. `Packet::parse` reads a DNS message,
+//! following compressed names, and `Packet::build` writes one, compressing names where DNS allows
+//! it. Records of the common types are given as typed `Data`, all others as raw bytes.
+//!
+//! GNS stores the values of records of DNS types in DNS wire format, so `Data::from_gns` and
+//! `Record::to_gns` convert between these and `gns::RecordData`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use gnunet::dnsparser::{self, Packet, Query};
+//!
+//! let mut packet = Packet::new(0x1234);
+//! packet.flags.recursion_desired = true;
+//! packet.queries.push(Query {
+//!     name: "www.gnu".to_string(),
+//!     qtype: dnsparser::TYPE_AAAA,
+//!     qclass: dnsparser::CLASS_IN,
+//! });
+//! let buf = packet.build(dnsparser::MAX_UDP_SIZE).unwrap();
+//! assert_eq!(Packet::parse(&buf).unwrap(), packet);
+//! ```
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str;
+use std::u16;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use ll;
+use gns::{RecordData, RecordDataError, RecordType};
+
+/// The longest DNS name, excluding the final dot.
+pub const MAX_NAME_LENGTH: usize = ll::GNUNET_DNSPARSER_MAX_NAME_LENGTH as usize;
+
+/// The longest label of a DNS name.
+pub const MAX_LABEL_LENGTH: usize = 63;
+
+/// The largest DNS message that may be sent over UDP without EDNS.
+pub const MAX_UDP_SIZE: usize = 512;
+
+/// An IPv4 address.
+pub const TYPE_A: u16 = 1;
+/// An authoritative name server.
+pub const TYPE_NS: u16 = 2;
+/// The canonical name of an alias.
+pub const TYPE_CNAME: u16 = 5;
+/// The start of a zone of authority.
+pub const TYPE_SOA: u16 = 6;
+/// A domain name pointer, used for reverse lookups.
+pub const TYPE_PTR: u16 = 12;
+/// A mail exchange.
+pub const TYPE_MX: u16 = 15;
+/// Text strings.
+pub const TYPE_TXT: u16 = 16;
+/// An IPv6 address.
+pub const TYPE_AAAA: u16 = 28;
+/// The location of a service.
+pub const TYPE_SRV: u16 = 33;
+/// A TLS certificate association.
+pub const TYPE_TLSA: u16 = 52;
+/// Records of every type, only valid in queries.
+pub const TYPE_ANY: u16 = 255;
+
+/// The Internet class, the only one in use.
+pub const CLASS_IN: u16 = 1;
+
+/// A standard query.
+pub const OPCODE_QUERY: u8 = 0;
+
+/// The query was answered.
+pub const RCODE_NO_ERROR: u8 = 0;
+/// The query could not be parsed.
+pub const RCODE_FORMAT_ERROR: u8 = 1;
+/// The server failed to answer the query.
+pub const RCODE_SERVER_FAILURE: u8 = 2;
+/// The name does not exist.
+pub const RCODE_NAME_ERROR: u8 = 3;
+/// The server does not support this kind of query.
+pub const RCODE_NOT_IMPLEMENTED: u8 = 4;
+/// The server refuses to answer the query.
+pub const RCODE_REFUSED: u8 = 5;
+
+// compression pointers can only refer to the start of a packet
+const MAX_POINTER: usize = 0x3fff;
+
+/// Errors returned by `Packet::parse`.
+error_def! ParseError {
+    Truncated
+        => "The packet ended unexpectedly",
+    InvalidName
+        => "The packet contains a malformed name",
+    InvalidRecord { rtype: u16 }
+        => "The packet contains a malformed record" ("A record of type {} is malformed.", rtype),
+}
+
+/// Errors returned by `Packet::build`.
+error_def! BuildError {
+    InvalidName { name: String }
+        => "A name cannot be written to a packet" ("The name \"{}\" is too long or has an empty or overlong label.", name),
+    StringTooLong { len: usize }
+        => "A TXT string is too long" ("A TXT string of {} bytes is longer than 255 bytes.", len),
+    TooLong { len: usize }
+        => "The packet is too long" ("The packet needs at least {} bytes.", len),
+}
+
+/// The flags of a DNS message header.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// The message is a response rather than a query.
+    pub response: bool,
+    /// The kind of query, eg. `OPCODE_QUERY`.
+    pub opcode: u8,
+    /// The response comes from a server authoritative for the name.
+    pub authoritative: bool,
+    /// The message was cut short to fit the transport.
+    pub truncated: bool,
+    /// The client asks the server to resolve the query recursively.
+    pub recursion_desired: bool,
+    /// The server supports recursive queries.
+    pub recursion_available: bool,
+    /// The data of the response was validated with DNSSEC.
+    pub authenticated_data: bool,
+    /// The client asks the server not to validate the data with DNSSEC.
+    pub checking_disabled: bool,
+    /// The result of the query, one of the `RCODE_*` constants.
+    pub return_code: u8,
+}
+
+impl Flags {
+    fn from_u16(x: u16) -> Flags {
+        Flags {
+            response: x & 0x8000 != 0,
+            opcode: ((x >> 11) & 0xf) as u8,
+            authoritative: x & 0x0400 != 0,
+            truncated: x & 0x0200 != 0,
+            recursion_desired: x & 0x0100 != 0,
+            recursion_available: x & 0x0080 != 0,
+            authenticated_data: x & 0x0020 != 0,
+            checking_disabled: x & 0x0010 != 0,
+            return_code: (x & 0xf) as u8,
+        }
+    }
+
+    fn to_u16(&self) -> u16 {
+        let bit = |b: bool, mask: u16| if b { mask } else { 0 };
+        bit(self.response, 0x8000) |
+        ((self.opcode as u16 & 0xf) << 11) |
+        bit(self.authoritative, 0x0400) |
+        bit(self.truncated, 0x0200) |
+        bit(self.recursion_desired, 0x0100) |
+        bit(self.recursion_available, 0x0080) |
+        bit(self.authenticated_data, 0x0020) |
+        bit(self.checking_disabled, 0x0010) |
+        (self.return_code as u16 & 0xf)
+    }
+}
+
+/// A question of a DNS message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+    /// The name asked about, without the final dot.
+    pub name: String,
+    /// The type of the records wanted, eg. `TYPE_A`.
+    pub qtype: u16,
+    /// The class of the records wanted, usually `CLASS_IN`.
+    pub qclass: u16,
+}
+
+/// The value of a DNS resource record.
+///
+/// Names are given as dot-separated strings without a trailing dot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Data {
+    /// An IPv4 address.
+    A(Ipv4Addr),
+    /// The name of an authoritative name server.
+    NS(String),
+    /// The canonical name of an alias.
+    CNAME(String),
+    /// The start of a zone of authority.
+    SOA {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    /// The name an address maps back to.
+    PTR(String),
+    /// A mail exchange, lower `preference`s are tried first.
+    MX {
+        preference: u16,
+        host: String,
+    },
+    /// The character strings of a TXT record, each at most 255 bytes long.
+    TXT(Vec<Vec<u8>>),
+    /// An IPv6 address.
+    AAAA(Ipv6Addr),
+    /// The `target` host and `port` of a service.
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// A TLS certificate association, see `gns::RecordData::TLSA`.
+    TLSA {
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        data: Vec<u8>,
+    },
+    /// The RDATA of a record of any other type.
+    Raw {
+        rtype: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl Data {
+    /// The type of a record with this value.
+    pub fn record_type(&self) -> u16 {
+        match *self {
+            Data::A(_)               => TYPE_A,
+            Data::NS(_)              => TYPE_NS,
+            Data::CNAME(_)           => TYPE_CNAME,
+            Data::SOA { .. }         => TYPE_SOA,
+            Data::PTR(_)             => TYPE_PTR,
+            Data::MX { .. }          => TYPE_MX,
+            Data::TXT(_)             => TYPE_TXT,
+            Data::AAAA(_)            => TYPE_AAAA,
+            Data::SRV { .. }         => TYPE_SRV,
+            Data::TLSA { .. }        => TYPE_TLSA,
+            Data::Raw { rtype, .. }  => rtype,
+        }
+    }
+
+    /// The value of a GNS record as a DNS value. Returns `None` for values of types which only
+    /// exist in GNS, such as PKEY.
+    pub fn from_gns(value: &RecordData) -> Option<Data> {
+        let rtype = value.record_type().to_u32();
+        if rtype > u16::MAX as u32 {
+            return None;
+        }
+        match *value {
+            // GNS stores the text itself
+            RecordData::TXT(ref text) => {
+                let mut strings: Vec<Vec<u8>> = text.as_bytes().chunks(255).map(|s| s.to_vec()).collect();
+                if strings.is_empty() {
+                    strings.push(Vec::new());
+                }
+                Some(Data::TXT(strings))
+            },
+            ref value => {
+                let rdata = match value.encode() {
+                    Ok(rdata) => rdata,
+                    Err(_)    => return None,
+                };
+                let mut p = Parser::new(&rdata[..]);
+                p.data(rtype as u16, rdata.len()).ok()
+            },
+        }
+    }
+}
+
+/// A DNS resource record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// The name the record belongs to, without the final dot.
+    pub name: String,
+    /// The class of the record, usually `CLASS_IN`.
+    pub class: u16,
+    /// The time to live, in seconds.
+    pub ttl: u32,
+    /// The value of the record, which also gives its type.
+    pub data: Data,
+}
+
+impl Record {
+    /// The type of this record.
+    pub fn record_type(&self) -> u16 {
+        self.data.record_type()
+    }
+
+    /// The value of this record as a GNS record value.
+    pub fn to_gns(&self) -> Result<RecordData, RecordDataError> {
+        match self.data {
+            Data::TXT(ref strings) => {
+                let text: Vec<u8> = strings.iter().flat_map(|s| s.iter().cloned()).collect();
+                match String::from_utf8(text) {
+                    Ok(text) => Ok(RecordData::TXT(text)),
+                    Err(_)   => Err(RecordDataError::InvalidString),
+                }
+            },
+            ref data => {
+                // GNS names are never compressed
+                let mut b = Builder::new(false);
+                if let Err(_) = b.data(data) {
+                    return Err(RecordDataError::Parse {
+                        record_type: RecordType::from_u32(data.record_type() as u32),
+                        value: format!("{:?}", data),
+                    });
+                }
+                RecordData::decode(RecordType::from_u32(data.record_type() as u32), &b.buf[..])
+            },
+        }
+    }
+}
+
+/// A DNS message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    /// Chosen by the client and copied into the response.
+    pub id: u16,
+    /// The flags of the header.
+    pub flags: Flags,
+    /// The questions, usually exactly one.
+    pub queries: Vec<Query>,
+    /// The records answering the questions.
+    pub answers: Vec<Record>,
+    /// The records of the authoritative name servers.
+    pub authority: Vec<Record>,
+    /// Other records which may help the client, eg. the addresses of the name servers.
+    pub additional: Vec<Record>,
+}
+
+impl Packet {
+    /// An empty query with the id `id`.
+    pub fn new(id: u16) -> Packet {
+        Packet {
+            id: id,
+            flags: Flags::default(),
+            queries: Vec::new(),
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    /// Parse the DNS message `buf`. Any data following the message is ignored.
+    pub fn parse(buf: &[u8]) -> Result<Packet, ParseError> {
+        let mut p = Parser::new(buf);
+        let id = try!(p.u16());
+        let flags = Flags::from_u16(try!(p.u16()));
+        let mut counts = [0u16; 4];
+        for count in counts.iter_mut() {
+            *count = try!(p.u16());
+        }
+        let mut queries = Vec::new();
+        for _ in 0..counts[0] {
+            queries.push(Query {
+                name: try!(p.name()),
+                qtype: try!(p.u16()),
+                qclass: try!(p.u16()),
+            });
+        }
+        let mut packet = Packet {
+            id: id,
+            flags: flags,
+            queries: queries,
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+        };
+        for _ in 0..counts[1] {
+            packet.answers.push(try!(p.record()));
+        }
+        for _ in 0..counts[2] {
+            packet.authority.push(try!(p.record()));
+        }
+        for _ in 0..counts[3] {
+            packet.additional.push(try!(p.record()));
+        }
+        Ok(packet)
+    }
+
+    /// Write this message, compressing names.
+    ///
+    /// If the message is longer than `max_len` bytes, for example `MAX_UDP_SIZE` for a reply sent
+    /// over UDP, it is sent without its records and with the truncated flag set, as a DNS server
+    /// would do. If it is still too long `BuildError::TooLong` is returned.
+    pub fn build(&self, max_len: usize) -> Result<Vec<u8>, BuildError> {
+        let buf = try!(self.build_sections(self.flags, &self.answers, &self.authority, &self.additional));
+        if buf.len() <= max_len {
+            return Ok(buf);
+        }
+        let mut flags = self.flags;
+        flags.truncated = true;
+        let buf = try!(self.build_sections(flags, &[], &[], &[]));
+        if buf.len() > max_len {
+            return Err(BuildError::TooLong { len: buf.len() });
+        }
+        Ok(buf)
+    }
+
+    fn build_sections(&self, flags: Flags, answers: &[Record], authority: &[Record], additional: &[Record])
+                      -> Result<Vec<u8>, BuildError> {
+        let mut b = Builder::new(true);
+        b.u16(self.id);
+        b.u16(flags.to_u16());
+        for len in [self.queries.len(), answers.len(), authority.len(), additional.len()].iter() {
+            if *len > u16::MAX as usize {
+                return Err(BuildError::TooLong { len: u16::MAX as usize + 1 });
+            }
+            b.u16(*len as u16);
+        }
+        for q in self.queries.iter() {
+            try!(b.name(&q.name, true));
+            b.u16(q.qtype);
+            b.u16(q.qclass);
+        }
+        for record in answers.iter().chain(authority.iter()).chain(additional.iter()) {
+            try!(b.record(record));
+        }
+        if b.buf.len() > u16::MAX as usize {
+            return Err(BuildError::TooLong { len: b.buf.len() });
+        }
+        Ok(b.buf)
+    }
+}
+
+struct Parser<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(buf: &'a [u8]) -> Parser<'a> {
+        Parser {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.pos + len > self.buf.len() {
+            return Err(ParseError::Truncated);
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(try!(self.bytes(1))[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        Ok(BigEndian::read_u16(try!(self.bytes(2))))
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        Ok(BigEndian::read_u32(try!(self.bytes(4))))
+    }
+
+    // read a possibly compressed name, leaving the position after its first part
+    fn name(&mut self) -> Result<String, ParseError> {
+        let mut labels: Vec<&str> = Vec::new();
+        let mut len = 0;
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // every pointer must point backwards, which guarantees termination
+        let mut limit = pos;
+        loop {
+            let n = match self.buf.get(pos) {
+                Some(&n) => n as usize,
+                None     => return Err(ParseError::Truncated),
+            };
+            if n & 0xc0 == 0xc0 {
+                let target = match self.buf.get(pos + 1) {
+                    Some(&lo) => ((n & 0x3f) << 8) | lo as usize,
+                    None      => return Err(ParseError::Truncated),
+                };
+                if target >= limit {
+                    return Err(ParseError::InvalidName);
+                }
+                if !jumped {
+                    self.pos = pos + 2;
+                    jumped = true;
+                }
+                limit = target;
+                pos = target;
+                continue;
+            }
+            if n > MAX_LABEL_LENGTH {
+                return Err(ParseError::InvalidName);
+            }
+            if n == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Ok(labels.join("."));
+            }
+            if pos + 1 + n > self.buf.len() {
+                return Err(ParseError::Truncated);
+            }
+            len += n + 1;
+            if len > MAX_NAME_LENGTH + 1 {
+                return Err(ParseError::InvalidName);
+            }
+            match str::from_utf8(&self.buf[pos + 1..pos + 1 + n]) {
+                Ok(label) if !label.contains('.') => labels.push(label),
+                _ => return Err(ParseError::InvalidName),
+            }
+            pos += n + 1;
+        }
+    }
+
+    fn record(&mut self) -> Result<Record, ParseError> {
+        let name = try!(self.name());
+        let rtype = try!(self.u16());
+        let class = try!(self.u16());
+        let ttl = try!(self.u32());
+        let len = try!(self.u16()) as usize;
+        Ok(Record {
+            name: name,
+            class: class,
+            ttl: ttl,
+            data: try!(self.data(rtype, len)),
+        })
+    }
+
+    // read RDATA of `len` bytes, which must be used up exactly
+    fn data(&mut self, rtype: u16, len: usize) -> Result<Data, ParseError> {
+        if self.pos + len > self.buf.len() {
+            return Err(ParseError::Truncated);
+        }
+        let end = self.pos + len;
+        let invalid = |e| match e {
+            ParseError::Truncated => ParseError::InvalidRecord { rtype: rtype },
+            e                     => e,
+        };
+        // the record must not be read past its end
+        let data = {
+            let mut p = Parser {
+                buf: &self.buf[..end],
+                pos: self.pos,
+            };
+            let data = try!(p.typed_data(rtype).map_err(&invalid));
+            if p.pos != end {
+                return Err(ParseError::InvalidRecord { rtype: rtype });
+            }
+            data
+        };
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn typed_data(&mut self, rtype: u16) -> Result<Data, ParseError> {
+        Ok(match rtype {
+            TYPE_A => {
+                let b = try!(self.bytes(4));
+                Data::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            },
+            TYPE_AAAA => {
+                let mut s = [0u16; 8];
+                for x in s.iter_mut() {
+                    *x = try!(self.u16());
+                }
+                Data::AAAA(Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]))
+            },
+            TYPE_NS    => Data::NS(try!(self.name())),
+            TYPE_CNAME => Data::CNAME(try!(self.name())),
+            TYPE_PTR   => Data::PTR(try!(self.name())),
+            TYPE_SOA   => Data::SOA {
+                mname: try!(self.name()),
+                rname: try!(self.name()),
+                serial: try!(self.u32()),
+                refresh: try!(self.u32()),
+                retry: try!(self.u32()),
+                expire: try!(self.u32()),
+                minimum: try!(self.u32()),
+            },
+            TYPE_MX => Data::MX {
+                preference: try!(self.u16()),
+                host: try!(self.name()),
+            },
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while self.pos < self.buf.len() {
+                    let len = try!(self.u8()) as usize;
+                    strings.push(try!(self.bytes(len)).to_vec());
+                }
+                Data::TXT(strings)
+            },
+            TYPE_SRV => Data::SRV {
+                priority: try!(self.u16()),
+                weight: try!(self.u16()),
+                port: try!(self.u16()),
+                target: try!(self.name()),
+            },
+            TYPE_TLSA => Data::TLSA {
+                usage: try!(self.u8()),
+                selector: try!(self.u8()),
+                matching_type: try!(self.u8()),
+                data: try!(self.rest()).to_vec(),
+            },
+            rtype => Data::Raw {
+                rtype: rtype,
+                data: try!(self.rest()).to_vec(),
+            },
+        })
+    }
+
+    fn rest(&mut self) -> Result<&'a [u8], ParseError> {
+        let len = self.buf.len() - self.pos;
+        self.bytes(len)
+    }
+}
+
+struct Builder {
+    buf: Vec<u8>,
+    // the offsets of the names written so far, for compression
+    names: Option<HashMap<String, usize>>,
+}
+
+impl Builder {
+    fn new(compress: bool) -> Builder {
+        Builder {
+            buf: Vec::new(),
+            names: if compress { Some(HashMap::new()) } else { None },
+        }
+    }
+
+    fn u16(&mut self, x: u16) {
+        self.buf.write_u16::<BigEndian>(x).unwrap();
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.buf.write_u32::<BigEndian>(x).unwrap();
+    }
+
+    // write a name, compressing it if allowed for the place it is written to
+    fn name(&mut self, name: &str, compress: bool) -> Result<(), BuildError> {
+        let invalid = || BuildError::InvalidName { name: name.to_string() };
+        let trimmed = name.trim_right_matches('.');
+        if trimmed.len() > MAX_NAME_LENGTH {
+            return Err(invalid());
+        }
+        let labels: Vec<&str> = if trimmed.is_empty() { Vec::new() } else { trimmed.split('.').collect() };
+        if labels.iter().any(|l| l.is_empty() || l.len() > MAX_LABEL_LENGTH) {
+            return Err(invalid());
+        }
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".").to_lowercase();
+            if let Some(ref mut names) = self.names {
+                if compress {
+                    if let Some(&offset) = names.get(&suffix) {
+                        self.buf.write_u16::<BigEndian>(0xc000 | offset as u16).unwrap();
+                        return Ok(());
+                    }
+                }
+                if self.buf.len() <= MAX_POINTER {
+                    names.insert(suffix, self.buf.len());
+                }
+            }
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(labels[i].as_bytes());
+        }
+        self.buf.push(0);
+        Ok(())
+    }
+
+    fn record(&mut self, record: &Record) -> Result<(), BuildError> {
+        try!(self.name(&record.name, true));
+        self.u16(record.record_type());
+        self.u16(record.class);
+        self.u32(record.ttl);
+        let len_pos = self.buf.len();
+        self.u16(0);
+        try!(self.data(&record.data));
+        let len = self.buf.len() - len_pos - 2;
+        if len > u16::MAX as usize {
+            return Err(BuildError::TooLong { len: self.buf.len() });
+        }
+        BigEndian::write_u16(&mut self.buf[len_pos..len_pos + 2], len as u16);
+        Ok(())
+    }
+
+    // names in the RDATA of types defined after RFC 1035 must not be compressed
+    fn data(&mut self, data: &Data) -> Result<(), BuildError> {
+        match *data {
+            Data::A(ref addr)     => self.buf.extend_from_slice(&addr.octets()),
+            Data::AAAA(ref addr)  => {
+                for s in addr.segments().iter() {
+                    self.u16(*s);
+                }
+            },
+            Data::NS(ref name) |
+            Data::CNAME(ref name) |
+            Data::PTR(ref name)   => try!(self.name(name, true)),
+            Data::SOA { ref mname, ref rname, serial, refresh, retry, expire, minimum } => {
+                try!(self.name(mname, true));
+                try!(self.name(rname, true));
+                for x in [serial, refresh, retry, expire, minimum].iter() {
+                    self.u32(*x);
+                }
+            },
+            Data::MX { preference, ref host } => {
+                self.u16(preference);
+                try!(self.name(host, true));
+            },
+            Data::TXT(ref strings) => {
+                for s in strings.iter() {
+                    if s.len() > 255 {
+                        return Err(BuildError::StringTooLong { len: s.len() });
+                    }
+                    self.buf.push(s.len() as u8);
+                    self.buf.extend_from_slice(&s[..]);
+                }
+            },
+            Data::SRV { priority, weight, port, ref target } => {
+                self.u16(priority);
+                self.u16(weight);
+                self.u16(port);
+                try!(self.name(target, false));
+            },
+            Data::TLSA { usage, selector, matching_type, ref data } => {
+                self.buf.extend_from_slice(&[usage, selector, matching_type]);
+                self.buf.extend_from_slice(&data[..]);
+            },
+            Data::Raw { ref data, .. } => self.buf.extend_from_slice(&data[..]),
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_packet_round_trip() {
+    let mut packet = Packet::new(7);
+    packet.flags = Flags {
+        response: true,
+        authoritative: true,
+        recursion_desired: true,
+        return_code: RCODE_NO_ERROR,
+        ..Flags::default()
+    };
+    packet.queries.push(Query { name: "example.gnu".to_string(), qtype: TYPE_MX, qclass: CLASS_IN });
+    let record = |name: &str, data| Record { name: name.to_string(), class: CLASS_IN, ttl: 3600, data: data };
+    packet.answers = vec![
+        record("example.gnu", Data::MX { preference: 10, host: "mail.example.gnu".to_string() }),
+        record("example.gnu", Data::TXT(vec![b"v=spf1".to_vec(), Vec::new()])),
+        record("_sip._udp.example.gnu", Data::SRV { priority: 1, weight: 2, port: 5060, target: "sip.example.gnu".to_string() }),
+    ];
+    packet.authority = vec![
+        record("example.gnu", Data::SOA {
+            mname: "ns.example.gnu".to_string(),
+            rname: "hostmaster.example.gnu".to_string(),
+            serial: 1, refresh: 2, retry: 3, expire: 4, minimum: 5,
+        }),
+    ];
+    packet.additional = vec![
+        record("mail.example.gnu", Data::A(Ipv4Addr::new(10, 0, 0, 1))),
+        record("mail.example.gnu", Data::AAAA("2001:db8::1".parse().unwrap())),
+        record("example.gnu", Data::TLSA { usage: 3, selector: 1, matching_type: 1, data: vec![0xab; 32] }),
+        record("", Data::Raw { rtype: 41, data: Vec::new() }),
+    ];
+
+    let buf = packet.build(u16::MAX as usize).unwrap();
+    assert_eq!(Packet::parse(&buf).unwrap(), packet);
+    // the answers refer to the question's name
+    assert_eq!(&buf[29..31], &[0xc0, 12]);
+
+    let truncated = Packet::parse(&packet.build(100).unwrap()).unwrap();
+    assert!(truncated.flags.truncated);
+    assert_eq!(truncated.queries, packet.queries);
+    assert!(truncated.answers.is_empty() && truncated.additional.is_empty());
+
+    assert_eq!(Packet::parse(&buf[..buf.len() - 1]).err(), Some(ParseError::Truncated));
+    let looped = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1];
+    assert_eq!(Packet::parse(&looped).err(), Some(ParseError::InvalidName));
+}
+
+#[test]
+fn test_gns_conversions() {
+    let values = vec![
+        RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        RecordData::MX { preference: 10, host: "mail.example.gnu".to_string() },
+        RecordData::TXT(::std::iter::repeat("x").take(300).collect()),
+        RecordData::TLSA { usage: 3, selector: 1, matching_type: 1, data: vec![1, 2, 3] },
+        RecordData::Other { record_type: 99, data: vec![1, 2, 3] },
+    ];
+    for value in values {
+        let data = Data::from_gns(&value).unwrap();
+        let record = Record { name: "example.gnu".to_string(), class: CLASS_IN, ttl: 60, data: data };
+        assert_eq!(record.to_gns().unwrap(), value);
+    }
+    assert_eq!(Data::from_gns(&RecordData::NICK("alice".to_string())), None);
+    let srv = Data::SRV { priority: 1, weight: 2, port: 443, target: "www.example.gnu".to_string() };
+    let record = Record { name: "_443._tcp.example.gnu".to_string(), class: CLASS_IN, ttl: 60, data: srv.clone() };
+    assert_eq!(Data::from_gns(&record.to_gns().unwrap()), Some(srv));
+}
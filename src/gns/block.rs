@@ -0,0 +1,206 @@
+//! GNS blocks, the signed and encrypted record sets published in the DHT.
+//!
+//! The records of a label are encrypted with a key derived from the public key of the zone and
+//! the label, and signed with the private key derived from the same two. Blocks are stored in the
+//! DHT under the hash of the derived public key, so only someone who knows both the zone and the
+//! label can find a block and read its records. Anyone can check its signature though.
+
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use crypto::kdf::kdf;
+use crypto::symmetric::{self, SymmetricSessionKey, SymmetricInitializationVector};
+use time;
+use util::io::ReadUtil;
+use EcdsaPublicKey;
+use EcdsaSignature;
+use HashCode;
+use super::{Record, records_deserialize};
+
+/// The signature purpose of GNS blocks.
+pub const SIGNATURE_PURPOSE_GNS_RECORD_SIGN: u32 = 15;
+
+/// The context GNS uses to derive the key of a label from the zone key.
+pub const KEY_DERIVATION_CONTEXT: &'static str = "gns";
+
+// size and purpose fields of the signed data, followed by the expiration
+const SIGNED_HEADER_SIZE: usize = 4 + 4 + 8;
+
+/// Errors returned when reading or decrypting a GNS block.
+error_def! BlockError {
+    Io { #[from] cause: io::Error }
+        => "There was an I/O error reading the block" ("Specifically {}", cause),
+    InvalidPurpose { purpose: u32 }
+        => "The block is not signed for GNS" ("The block has signature purpose {}.", purpose),
+    InvalidSize { size: u32 }
+        => "The signed size of the block is invalid" ("The block has signed size {}.", size),
+    InvalidZoneKey
+        => "The zone key is not a valid public key",
+    WrongKey
+        => "The block was not signed with the key of the given zone and label",
+    InvalidSignature
+        => "The signature of the block is invalid",
+    MalformedRecords
+        => "The decrypted records are malformed",
+}
+
+/// A signed and encrypted set of records for a label.
+#[derive(Clone, Debug)]
+pub struct Block {
+    /// The signature over the expiration and encrypted records.
+    pub signature: EcdsaSignature,
+    /// The key derived from the zone and label, which signed the block.
+    pub derived_key: EcdsaPublicKey,
+    /// When the block expires.
+    pub expiration: time::Absolute,
+    /// The encrypted records.
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    /// Deserialize a block in the format `GNUNET_GNSRECORD_Block` has in the DHT.
+    pub fn deserialize<T>(r: &mut T) -> Result<Block, BlockError> where T: Read {
+        let signature = try!(EcdsaSignature::deserialize(r));
+        let derived_key = try!(EcdsaPublicKey::deserialize(r));
+        let size = try!(r.read_u32::<BigEndian>());
+        let purpose = try!(r.read_u32::<BigEndian>());
+        if purpose != SIGNATURE_PURPOSE_GNS_RECORD_SIGN {
+            return Err(BlockError::InvalidPurpose { purpose: purpose });
+        }
+        if (size as usize) < SIGNED_HEADER_SIZE {
+            return Err(BlockError::InvalidSize { size: size });
+        }
+        let expiration = time::Absolute::from_micros(try!(r.read_u64::<BigEndian>()));
+        let data = try!(r.read_exact_alloc(size as usize - SIGNED_HEADER_SIZE));
+        Ok(Block {
+            signature: signature,
+            derived_key: derived_key,
+            expiration: expiration,
+            data: data,
+        })
+    }
+
+    /// Serialize the block, in the same format read by `Block::deserialize`.
+    pub fn serialize<T>(&self, w: &mut T) -> Result<(), io::Error> where T: Write {
+        try!(self.signature.serialize(w));
+        try!(self.derived_key.serialize(w));
+        w.write_all(&self.signed_data())
+    }
+
+    /// The key the block is stored under in the DHT.
+    pub fn query(&self) -> HashCode {
+        self.derived_key.hash()
+    }
+
+    /// Check the signature of the block.
+    pub fn verify(&self) -> bool {
+        self.derived_key.verify(SIGNATURE_PURPOSE_GNS_RECORD_SIGN, &self.signed_data(), &self.signature)
+    }
+
+    /// Verify the block and decrypt the records of `label` in `zone` from it.
+    ///
+    /// Fails if the block does not hold records of `label` in `zone`.
+    pub fn decrypt(&self, zone: &EcdsaPublicKey, label: &str) -> Result<Vec<Record>, BlockError> {
+        if try!(derive_zone_key(zone, label)) != self.derived_key {
+            return Err(BlockError::WrongKey);
+        }
+        if !self.verify() {
+            return Err(BlockError::InvalidSignature);
+        }
+        let (key, iv) = session_key(zone, label);
+        let plain = symmetric::decrypt(&self.data, &key, &iv);
+
+        // the number of records, followed by the record set
+        if plain.len() < 4 {
+            return Err(BlockError::MalformedRecords);
+        }
+        let count = BigEndian::read_u32(&plain[..4]);
+        let records = try!(records_deserialize(&plain[4..]).map_err(|_| BlockError::MalformedRecords));
+        if records.len() != count as usize {
+            return Err(BlockError::MalformedRecords);
+        }
+        Ok(records)
+    }
+
+    // the data covered by the signature: size, purpose, expiration and encrypted records
+    fn signed_data(&self) -> Vec<u8> {
+        let mut signed = Vec::with_capacity(SIGNED_HEADER_SIZE + self.data.len());
+        signed.write_u32::<BigEndian>((SIGNED_HEADER_SIZE + self.data.len()) as u32).unwrap();
+        signed.write_u32::<BigEndian>(SIGNATURE_PURPOSE_GNS_RECORD_SIGN).unwrap();
+        signed.write_u64::<BigEndian>(self.expiration.as_micros()).unwrap();
+        signed.extend_from_slice(&self.data);
+        signed
+    }
+}
+
+/// Derive the public key signing the records of `label` in `zone`.
+pub fn derive_zone_key(zone: &EcdsaPublicKey, label: &str) -> Result<EcdsaPublicKey, BlockError> {
+    zone.derive(label, KEY_DERIVATION_CONTEXT).map_err(|_| BlockError::InvalidZoneKey)
+}
+
+/// The key the block holding the records of `label` in `zone` is stored under in the DHT, as
+/// `GNUNET_GNSRECORD_query_from_public_key` computes it.
+pub fn query_from_public_key(zone: &EcdsaPublicKey, label: &str) -> Result<HashCode, BlockError> {
+    derive_zone_key(zone, label).map(|key| key.hash())
+}
+
+// the symmetric key the records of `label` in `zone` are encrypted with
+fn session_key(zone: &EcdsaPublicKey, label: &str) -> (SymmetricSessionKey, SymmetricInitializationVector) {
+    let mut zone_data = Vec::with_capacity(32);
+    zone.serialize(&mut zone_data).unwrap();
+    let mut key = [0u8; 64];
+    let mut iv = [0u8; 32];
+    kdf(&mut key, &zone_data, label.as_bytes(), &[b"gns-aes-ctx-key"]);
+    kdf(&mut iv, &zone_data, label.as_bytes(), &[b"gns-aes-ctx-iv"]);
+    (SymmetricSessionKey::from_bytes(&key), SymmetricInitializationVector::from_bytes(&iv))
+}
+
+#[test]
+fn test_block() {
+    use super::{RecordData, RecordType, records_serialize};
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    // the public key of the private key 0x0102...20, with the records of "www" signed by it. The
+    // block was computed by a Python implementation of libgnunetutil's algorithms, separate from
+    // this crate, with the record set padded to 64 bytes as `records_serialize` pads it
+    let zone_data = from_hex("80334024b705b5fd76b1bce1b26d96234ab7b2d989987895fa72c43b3e5c5f85");
+    let zone = EcdsaPublicKey::deserialize(&mut &zone_data[..]).unwrap();
+    let block_data = from_hex(
+        "033bd6c949139d1289bdd7fdc5c19f2a95f9e35e417f289ec7cbb61e2422fdda\
+         034a9602179c1c9d7850b79e8a5d1b3af3229fea37294a32c8b06d1d869636f7\
+         fdc0f9e617df44ce63b8b1e07c16f72353847a4890310fd87d5e69f7c781f2a1\
+         000000540000000f00060a24181e4000\
+         e027018f4c6a20d9e293f64139fb1d3d9ccff21b95c1e8c58758c59587bfad92\
+         73603579c10d6ff41f2acff170506ddd77e98210dcc3e7926e02842d120204da\
+         849c38fb");
+    let query = from_hex(
+        "1335f200370ca1d58885934af77802d5a36f03db3fffd2bbcd88fa008448c827\
+         7b0648337ca403e44dc50efa781f7da6a2ea9d2b4dd26e381827fe1dcf2cc0f9");
+
+    let block = Block::deserialize(&mut &block_data[..]).unwrap();
+    assert_eq!(block.expiration, time::Absolute::from_micros(1700000000000000));
+    assert_eq!(query_from_public_key(&zone, "www").unwrap().as_slice(), &query[..]);
+    assert_eq!(block.query().as_slice(), &query[..]);
+    assert!(block.verify());
+
+    let records = block.decrypt(&zone, "www").unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].record_type(), RecordType::A);
+    assert_eq!(records[0].value().unwrap(), RecordData::A("10.0.0.1".parse().unwrap()));
+    assert_eq!(records[1].record_type(), RecordType::TXT);
+    assert_eq!(records[1].data(), b"hello");
+    assert_eq!(block.data.len(), 4 + records_serialize(&records).len());
+
+    let mut serialized = Vec::new();
+    block.serialize(&mut serialized).unwrap();
+    assert_eq!(serialized, block_data);
+
+    assert!(block.decrypt(&zone, "ftp").is_err());
+    let mut tampered = block.clone();
+    tampered.data[0] ^= 1;
+    assert!(!tampered.verify());
+    assert!(tampered.decrypt(&zone, "www").is_err());
+}
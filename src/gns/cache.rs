@@ -0,0 +1,390 @@
+//! A client-side cache of GNS lookup results.
+//!
+//! Every `GNS::lookup` is a round trip to the GNS service. `LookupCache` wraps a `GNS` handle and
+//! keeps the records of each lookup until the first of them expires, so repeated lookups of the
+//! same names are answered locally. Lookups made while an identical one is still waiting for the
+//! service share its result instead of sending another request.
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use gj::{Promise, PromiseFulfiller, TaskSet, TaskReaper};
+
+use service::dispatch::Deadline;
+use service::message::CopyError;
+use time;
+use EcdsaPrivateKey;
+use EcdsaPublicKey;
+use super::{GNS, LocalOptions, LookupError, Record, RecordType};
+
+/// Counters of how lookups through a `LookupCache` were answered.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups sent to the service.
+    pub misses: u64,
+    /// Lookups which waited for an identical lookup already sent to the service.
+    pub collapsed: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    zone: EcdsaPublicKey,
+    record_type: RecordType,
+    options: LocalOptions,
+}
+
+struct Entry {
+    records: Vec<Record>,
+    // when the first of the records expires
+    expires: time::Absolute,
+}
+
+struct Waiter {
+    id: u64,
+    fulfiller: PromiseFulfiller<Vec<Record>, LookupError>,
+    // dropping it stops the timer of the waiter's deadline
+    _expire: Option<PromiseFulfiller<(), LookupError>>,
+}
+
+struct InFlight {
+    id: u64,
+    waiters: Vec<Waiter>,
+    // cleared if the name is invalidated while the lookup is in flight
+    store: bool,
+    // dropping it cancels the lookup
+    _cancel: PromiseFulfiller<Vec<Record>, LookupError>,
+}
+
+struct Inner {
+    gns: GNS,
+    entries: HashMap<Key, Entry>,
+    in_flight: HashMap<Key, InFlight>,
+    stats: CacheStats,
+    // ids of the waiters and of the lookups in flight
+    next_id: u64,
+    // expired entries are removed once there are this many entries
+    sweep_at: usize,
+    // the lookups sent to the service and the deadlines of the waiters
+    tasks: TaskSet<(), ()>,
+}
+
+struct Reaper;
+
+impl TaskReaper<(), ()> for Reaper {
+    fn task_failed(&mut self, _: ()) {}
+}
+
+// the number of entries below which expired entries are not swept
+const MIN_SWEEP: usize = 64;
+
+/// A `GNS` handle which caches lookup results.
+///
+/// Results are cached per name, zone, record type and lookup options, until the first of the
+/// returned records expires. Empty results are not cached.
+pub struct LookupCache {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl LookupCache {
+    /// Cache the lookups made through `gns`.
+    pub fn new(gns: GNS) -> LookupCache {
+        LookupCache {
+            inner: Rc::new(RefCell::new(Inner {
+                gns: gns,
+                entries: HashMap::new(),
+                in_flight: HashMap::new(),
+                stats: CacheStats::default(),
+                next_id: 0,
+                sweep_at: MIN_SWEEP,
+                tasks: TaskSet::new(Box::new(Reaper)),
+            })),
+        }
+    }
+
+    /// Lookup a vector of GNS records, see `GNS::lookup`.
+    ///
+    /// If the records are cached the promise resolves to them right away. Otherwise the lookup is
+    /// sent to the service, unless an identical lookup is already waiting for it in which case the
+    /// promise resolves to the result of that lookup. `shorten` is only used if the lookup is
+    /// sent.
+    ///
+    /// If `deadline` passes before the result arrives the promise is rejected with
+    /// `LookupError::TimedOut`, whichever lookup sent the request. The request is cancelled once
+    /// every lookup waiting for it has timed out, or when the cache is dropped, but not when the
+    /// promise is dropped.
+    pub fn lookup(&mut self,
+                  name: Rc<String>,
+                  zone: EcdsaPublicKey,
+                  record_type: RecordType,
+                  options: LocalOptions,
+                  shorten: Option<EcdsaPrivateKey>,
+                  deadline: Option<Deadline>)
+                  -> Promise<Vec<Record>, LookupError>
+    {
+        let key = Key {
+            name: (*name).clone(),
+            zone: zone,
+            record_type: record_type,
+            options: options,
+        };
+        let now = time::Absolute::now();
+        let mut guard = self.inner.borrow_mut();
+        let inner = &mut *guard;
+
+        let cached = match inner.entries.get(&key) {
+            Some(entry) if now < entry.expires => Some(entry.records.clone()),
+            _                                   => None,
+        };
+        if let Some(records) = cached {
+            inner.stats.hits += 1;
+            return Promise::ok(records);
+        }
+        inner.entries.remove(&key);
+
+        let (promise, fulfiller) = Promise::and_fulfiller();
+        let waiter_id = inner.next_id;
+        inner.next_id += 1;
+        let expire = deadline.map(|deadline| {
+            let (stopped, stop) = Promise::<(), LookupError>::and_fulfiller();
+            let weak = Rc::downgrade(&self.inner);
+            let key = key.clone();
+            let expire = deadline.timer().after_delay(deadline.timeout())
+                .map_else(|res| Ok(res.is_ok()))
+                .exclusive_join(stopped.map_else(|_| Ok(false)))
+                .map(move |expired| {
+                    if expired {
+                        LookupCache::expire(weak, key, waiter_id);
+                    }
+                    Ok(())
+                });
+            inner.tasks.add(expire);
+            stop
+        });
+        let waiter = Waiter {
+            id: waiter_id,
+            fulfiller: fulfiller,
+            _expire: expire,
+        };
+        if let Some(in_flight) = inner.in_flight.get_mut(&key) {
+            inner.stats.collapsed += 1;
+            in_flight.waiters.push(waiter);
+            return promise;
+        }
+
+        inner.stats.misses += 1;
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let (cancelled, cancel) = Promise::and_fulfiller();
+        inner.in_flight.insert(key.clone(), InFlight {
+            id: id,
+            waiters: vec![waiter],
+            store: true,
+            _cancel: cancel,
+        });
+        let weak = Rc::downgrade(&self.inner);
+        let request = inner.gns.lookup(name, zone, record_type, options, shorten, None)
+            .exclusive_join(cancelled)
+            .then_else(move |res| {
+                LookupCache::complete(weak, key, id, res);
+                Promise::ok(())
+            });
+        inner.tasks.add(request);
+        promise
+    }
+
+    /// Drop the cached records of `name` in `zone`, for all record types and options.
+    ///
+    /// The results of lookups of `name` in `zone` which are in flight are not cached either.
+    pub fn invalidate(&mut self, name: &str, zone: &EcdsaPublicKey) {
+        let mut inner = self.inner.borrow_mut();
+        let keys: Vec<Key> = inner.entries.keys().filter(|key| key.name == name && key.zone == *zone).cloned().collect();
+        for key in keys {
+            inner.entries.remove(&key);
+        }
+        for (key, in_flight) in inner.in_flight.iter_mut() {
+            if key.name == name && key.zone == *zone {
+                in_flight.store = false;
+            }
+        }
+    }
+
+    /// Drop all cached records.
+    pub fn clear(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.entries.clear();
+        for in_flight in inner.in_flight.values_mut() {
+            in_flight.store = false;
+        }
+    }
+
+    /// How the lookups made so far were answered.
+    pub fn stats(&self) -> CacheStats {
+        self.inner.borrow().stats
+    }
+
+    // the deadline of the waiter `id` has passed, the lookup is cancelled if it was the last one
+    fn expire(inner: Weak<RefCell<Inner>>, key: Key, id: u64) {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None        => return,
+        };
+        let (waiter, in_flight) = {
+            let mut inner = inner.borrow_mut();
+            let (waiter, last) = match inner.in_flight.get_mut(&key) {
+                Some(in_flight) => match in_flight.waiters.iter().position(|w| w.id == id) {
+                    Some(pos) => (in_flight.waiters.remove(pos), in_flight.waiters.is_empty()),
+                    None      => return,
+                },
+                None => return,
+            };
+            let in_flight = if last { inner.in_flight.remove(&key) } else { None };
+            (waiter, in_flight)
+        };
+        drop(in_flight);
+        waiter.fulfiller.reject(LookupError::TimedOut);
+    }
+
+    fn complete(inner: Weak<RefCell<Inner>>, key: Key, id: u64, res: Result<Vec<Record>, LookupError>) {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None        => return,
+        };
+        let waiters = {
+            let mut inner = inner.borrow_mut();
+            match inner.in_flight.get(&key) {
+                Some(in_flight) if in_flight.id == id => (),
+                // cancelled, possibly followed by another lookup of the same key
+                _ => return,
+            }
+            let in_flight = inner.in_flight.remove(&key).unwrap();
+            if let Ok(ref records) = res {
+                let received = time::Absolute::now();
+                let expires = records.iter().map(|r| r.expires_at(received)).min();
+                if let (true, Some(expires)) = (in_flight.store, expires) {
+                    if inner.entries.len() >= inner.sweep_at {
+                        inner.sweep(received);
+                    }
+                    inner.entries.insert(key, Entry {
+                        records: records.clone(),
+                        expires: expires,
+                    });
+                }
+            }
+            in_flight.waiters
+        };
+        match res {
+            Ok(records) => for waiter in waiters {
+                waiter.fulfiller.fulfill(records.clone());
+            },
+            Err(e) => for waiter in waiters {
+                waiter.fulfiller.reject(e.copy_error());
+            },
+        }
+    }
+}
+
+impl Inner {
+    // remove the expired entries, the next sweep is done once the number of entries has doubled
+    fn sweep(&mut self, now: time::Absolute) {
+        let expired: Vec<Key> = self.entries.iter()
+                                            .filter(|&(_, entry)| entry.expires <= now)
+                                            .map(|(key, _)| key.clone())
+                                            .collect();
+        for key in expired {
+            self.entries.remove(&key);
+        }
+        self.sweep_at = max(MIN_SWEEP, self.entries.len() * 2);
+    }
+}
+
+#[test]
+fn test_lookup_cache() {
+    use std::cell::Cell;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use testing::{MockService, TestPeer};
+    use super::{LookupMessage, LookupResultMessage, RecordFlags, RF_RELATIVE_EXPIRATION};
+
+    // "www.gnu" has a record valid forever, "slow.gnu" is never answered and all other names have
+    // one which expires as soon as it is received
+    let requests = Rc::new(Cell::new(0));
+    let counter = requests.clone();
+    let mut service = MockService::new("gns");
+    service.on_message(move |msg: LookupMessage, replies| {
+        counter.set(counter.get() + 1);
+        if msg.name == "slow.gnu" {
+            return Ok(());
+        }
+        let record = match &msg.name[..] {
+            "www.gnu" => Record::new(RecordType::A, u64::max_value(), RecordFlags::empty(), vec![10, 0, 0, 1]),
+            _         => Record::new(RecordType::A, 0, RF_RELATIVE_EXPIRATION, vec![10, 0, 0, 2]),
+        };
+        replies.send(&LookupResultMessage { id: msg.id, records: vec![record] })
+    });
+
+    TestPeer::with_service(service, GNS::connect, |gns, t| {
+        let mut cache = LookupCache::new(gns);
+        let zone = EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+        let www = Rc::new("www.gnu".to_string());
+        let tmp = Rc::new("tmp.gnu".to_string());
+
+        // two concurrent lookups are sent as one
+        let a = cache.lookup(www.clone(), zone, RecordType::A, LocalOptions::Default, None, None);
+        let b = cache.lookup(www.clone(), zone, RecordType::A, LocalOptions::Default, None, None);
+        let results = t.wait(Promise::all(vec![a, b].into_iter())).unwrap();
+        assert_eq!(results[0][0].data(), &[10, 0, 0, 1]);
+        assert_eq!(results[1][0].data(), &[10, 0, 0, 1]);
+        assert_eq!(requests.get(), 1);
+
+        let records = t.wait(cache.lookup(www.clone(), zone, RecordType::A, LocalOptions::Default, None, None)).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(requests.get(), 1);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, collapsed: 1 });
+
+        // other options are a different query
+        t.wait(cache.lookup(www.clone(), zone, RecordType::A, LocalOptions::NoDHT, None, None)).unwrap();
+        assert_eq!(requests.get(), 2);
+
+        // expired records are looked up again
+        for _ in 0..2 {
+            t.wait(cache.lookup(tmp.clone(), zone, RecordType::A, LocalOptions::Default, None, None)).unwrap();
+        }
+        assert_eq!(requests.get(), 4);
+
+        cache.invalidate("www.gnu", &zone);
+        t.wait(cache.lookup(www.clone(), zone, RecordType::A, LocalOptions::Default, None, None)).unwrap();
+        assert_eq!(requests.get(), 5);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 5, collapsed: 1 });
+
+        // collapsed lookups keep their own deadlines, the request is cancelled after the last one
+        let slow = Rc::new("slow.gnu".to_string());
+        let short = Deadline::new(&t.timer, Duration::from_millis(10).into());
+        let long = Deadline::new(&t.timer, Duration::from_millis(100).into());
+        let a = cache.lookup(slow.clone(), zone, RecordType::A, LocalOptions::Default, None, short);
+        let b = cache.lookup(slow.clone(), zone, RecordType::A, LocalOptions::Default, None, long);
+        match t.wait(a) {
+            Err(LookupError::TimedOut) => (),
+            _ => panic!("the lookup did not time out"),
+        }
+        assert_eq!(cache.inner.borrow().in_flight.len(), 1);
+        match t.wait(b) {
+            Err(LookupError::TimedOut) => (),
+            _ => panic!("the lookup did not time out"),
+        }
+        assert!(cache.inner.borrow().in_flight.is_empty());
+        assert_eq!(requests.get(), 6);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 6, collapsed: 2 });
+
+        // expired entries are swept once there are enough of them
+        for i in 0..MIN_SWEEP {
+            let name = Rc::new(format!("tmp{}.gnu", i));
+            t.wait(cache.lookup(name, zone, RecordType::A, LocalOptions::Default, None, None)).unwrap();
+        }
+        assert!(cache.inner.borrow().entries.len() < MIN_SWEEP);
+    });
+}
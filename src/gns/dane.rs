@@ -0,0 +1,283 @@
+//! Checking TLS certificates against the TLSA records published in GNS.
+//!
+//! A service publishes TLSA records for its port under the name of the host, boxed as
+//! `_port._tcp.host` (see `RecordType::BOX`), and usually a LEHO record giving the legacy
+//! hostname its certificate is issued for. A client looks these up and checks the certificate
+//! chain presented by the server against the records, the way `gnunet-gns-proxy` does, using the
+//! LEHO name for SNI and the HTTP `Host` header.
+//!
+//! Only the matching of certificates against records is done here. Records which require the
+//! chain to be validated further say so in the `Verdict`, and that validation is left to the TLS
+//! library.
+
+use std::cmp::min;
+use std::rc::Rc;
+
+use gj::Promise;
+use rcrypto::digest::Digest;
+use rcrypto::sha2::{Sha256, Sha512};
+
+use service::dispatch::Deadline;
+use EcdsaPublicKey;
+use super::{GNS, LocalOptions, LookupError, Record, RecordData, RecordType};
+
+/// TLSA usage: the chain must pass PKIX validation and contain the matching CA certificate.
+pub const USAGE_PKIX_TA: u8 = 0;
+/// TLSA usage: the end entity certificate must match and pass PKIX validation.
+pub const USAGE_PKIX_EE: u8 = 1;
+/// TLSA usage: the chain must lead up to the matching certificate, which is trusted as a CA.
+pub const USAGE_DANE_TA: u8 = 2;
+/// TLSA usage: the end entity certificate must match and is trusted as is.
+pub const USAGE_DANE_EE: u8 = 3;
+
+/// TLSA selector: the full certificate is matched.
+pub const SELECTOR_CERT: u8 = 0;
+/// TLSA selector: the SubjectPublicKeyInfo of the certificate is matched.
+pub const SELECTOR_SPKI: u8 = 1;
+
+/// TLSA matching type: the selected data is compared as is.
+pub const MATCHING_EXACT: u8 = 0;
+/// TLSA matching type: the SHA-256 hash of the selected data is compared.
+pub const MATCHING_SHA256: u8 = 1;
+/// TLSA matching type: the SHA-512 hash of the selected data is compared.
+pub const MATCHING_SHA512: u8 = 2;
+
+/// The outcome of checking a certificate chain against TLSA records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// There are no TLSA records with a usage, selector and matching type known to this module.
+    /// The certificate has to be checked the usual way, if at all.
+    NoUsableRecords,
+    /// The end entity certificate matches a DANE-EE record and is to be trusted without any other
+    /// checks.
+    Trusted,
+    /// A record matches, but the chain still has to be validated.
+    ///
+    /// `anchor` is the position in the chain of the certificate which matched a trust anchor
+    /// record, which the chain has to be validated up to. It is `None` if the end entity
+    /// certificate matched a PKIX-EE record. If `pkix` is true the chain also has to be valid
+    /// according to the usual trusted CAs.
+    ValidateChain {
+        anchor: Option<usize>,
+        pkix: bool,
+    },
+    /// There are usable records but the chain matches none of them. The connection should be
+    /// aborted.
+    Mismatch,
+}
+
+/// The result of `verify`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dane {
+    /// What to make of the certificate chain.
+    pub verdict: Verdict,
+    /// The name to use for SNI and to check the certificate with: the LEHO name of the host if it
+    /// has one, otherwise the hostname itself.
+    pub sni_name: String,
+}
+
+/// Check the certificate chain `chain`, DER encoded with the end entity certificate first,
+/// against the TLSA records among `records`.
+///
+/// DANE-EE matches take precedence over the others, after that the first matching record wins.
+pub fn check_chain(records: &[Record], chain: &[Vec<u8>]) -> Verdict {
+    let mut usable = false;
+    let mut found = None;
+    for record in records {
+        let (usage, selector, matching_type, data) = match record.value() {
+            Ok(RecordData::TLSA { usage, selector, matching_type, data }) => (usage, selector, matching_type, data),
+            _ => continue,
+        };
+        if usage > USAGE_DANE_EE || selector > SELECTOR_SPKI || matching_type > MATCHING_SHA512 {
+            continue;
+        }
+        usable = true;
+        // trust anchors are looked for above the end entity certificate
+        let candidates = match usage {
+            USAGE_PKIX_EE | USAGE_DANE_EE => &chain[..min(chain.len(), 1)],
+            _                             => if chain.is_empty() { chain } else { &chain[1..] },
+        };
+        let position = candidates.iter().position(|cert| cert_matches(cert, selector, matching_type, &data));
+        let verdict = match (usage, position) {
+            (_, None)                   => continue,
+            (USAGE_DANE_EE, Some(_))    => return Verdict::Trusted,
+            (USAGE_PKIX_EE, Some(_))    => Verdict::ValidateChain { anchor: None, pkix: true },
+            (usage, Some(i))            => Verdict::ValidateChain { anchor: Some(i + 1), pkix: usage == USAGE_PKIX_TA },
+        };
+        if found.is_none() {
+            found = Some(verdict);
+        }
+    }
+    match found {
+        Some(verdict)   => verdict,
+        None if usable  => Verdict::Mismatch,
+        None            => Verdict::NoUsableRecords,
+    }
+}
+
+/// Look up the TLSA records of `hostname` for TCP port `port` and its LEHO record in `zone`, and
+/// check the certificate chain `chain` against them, see `check_chain`.
+///
+/// `options` and `deadline` are used for both lookups, see `GNS::lookup`.
+pub fn verify(gns: &mut GNS,
+              zone: EcdsaPublicKey,
+              hostname: &str,
+              port: u16,
+              chain: Vec<Vec<u8>>,
+              options: LocalOptions,
+              deadline: Option<Deadline>)
+              -> Promise<Dane, LookupError>
+{
+    let hostname = hostname.trim_right_matches('.').to_string();
+    let service_name = Rc::new(format!("_{}._tcp.{}", port, hostname));
+    let tlsa = gns.lookup(service_name, zone, RecordType::TLSA, options, None, deadline.clone());
+    let leho = gns.lookup(Rc::new(hostname.clone()), zone, RecordType::LEHO, options, None, deadline);
+    Promise::all(vec![tlsa, leho].into_iter()).map(move |mut results| {
+        let leho = results.pop().unwrap();
+        let tlsa = results.pop().unwrap();
+        let sni_name = leho.iter().filter_map(|r| match r.value() {
+            Ok(RecordData::LEHO(name)) => Some(name),
+            _                          => None,
+        }).next();
+        Ok(Dane {
+            verdict: check_chain(&tlsa, &chain),
+            sni_name: sni_name.unwrap_or(hostname),
+        })
+    })
+}
+
+fn cert_matches(cert: &[u8], selector: u8, matching_type: u8, data: &[u8]) -> bool {
+    let selected = match selector {
+        SELECTOR_CERT => cert,
+        _             => match subject_public_key_info(cert) {
+            Some(spki) => spki,
+            None       => return false,
+        },
+    };
+    match matching_type {
+        MATCHING_EXACT  => selected == data,
+        MATCHING_SHA256 => hash(Sha256::new(), selected) == data,
+        _               => hash(Sha512::new(), selected) == data,
+    }
+}
+
+fn hash<D: Digest>(mut digest: D, data: &[u8]) -> Vec<u8> {
+    digest.input(data);
+    let mut out = vec![0; digest.output_bytes()];
+    digest.result(&mut out);
+    out
+}
+
+// split the DER element at the start of `data` off it, returning its tag, the whole element and
+// its contents
+fn der_next<'a>(data: &mut &'a [u8]) -> Option<(u8, &'a [u8], &'a [u8])> {
+    if data.len() < 2 {
+        return None;
+    }
+    let tag = data[0];
+    let (header, len) = match data[1] {
+        l if l < 0x80 => (2, l as usize),
+        l => {
+            let n = (l & 0x7f) as usize;
+            if n == 0 || n > 4 || data.len() < 2 + n {
+                return None;
+            }
+            (2 + n, data[2..2 + n].iter().fold(0, |len, b| (len << 8) | *b as usize))
+        },
+    };
+    if data.len() - header < len {
+        return None;
+    }
+    let element = &data[..header + len];
+    let contents = &data[header..header + len];
+    *data = &data[header + len..];
+    Some((tag, element, contents))
+}
+
+// the SubjectPublicKeyInfo of a DER encoded X.509 certificate
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let mut data = cert;
+    let mut cert = match der_next(&mut data) {
+        Some((SEQUENCE, _, contents)) => contents,
+        _                             => return None,
+    };
+    let mut tbs = match der_next(&mut cert) {
+        Some((SEQUENCE, _, contents)) => contents,
+        _                             => return None,
+    };
+    // the optional version, then serial number, signature algorithm, issuer, validity and subject
+    if tbs.first() == Some(&VERSION) && der_next(&mut tbs).is_none() {
+        return None;
+    }
+    for _ in 0..5 {
+        if der_next(&mut tbs).is_none() {
+            return None;
+        }
+    }
+    match der_next(&mut tbs) {
+        Some((SEQUENCE, spki, _)) => Some(spki),
+        _                         => None,
+    }
+}
+
+#[test]
+fn test_check_chain() {
+    use super::RecordFlags;
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut ret = vec![tag];
+        if contents.len() < 0x80 {
+            ret.push(contents.len() as u8);
+        } else {
+            ret.extend_from_slice(&[0x82, (contents.len() >> 8) as u8, contents.len() as u8]);
+        }
+        ret.extend_from_slice(contents);
+        ret
+    }
+    // a certificate with only the structure needed to find the SubjectPublicKeyInfo
+    fn cert(name: &str, key: u8) -> (Vec<u8>, Vec<u8>) {
+        let mut bits = vec![key; 200];
+        bits[0] = 0;
+        let spki = der(0x30, &der(0x03, &bits));
+        let fields = vec![
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &[1]),
+            der(0x30, &[]),
+            der(0x30, name.as_bytes()),
+            der(0x30, &[]),
+            der(0x30, name.as_bytes()),
+            spki.clone(),
+        ];
+        (der(0x30, &der(0x30, &fields.concat())), spki)
+    }
+    fn tlsa(usage: u8, selector: u8, matching_type: u8, data: Vec<u8>) -> Record {
+        let value = RecordData::TLSA { usage: usage, selector: selector, matching_type: matching_type, data: data };
+        Record::from_value(&value, 0, RecordFlags::empty()).unwrap()
+    }
+
+    let (leaf, leaf_spki) = cert("leaf", 1);
+    let (ca, _) = cert("ca", 2);
+    let chain = vec![leaf.clone(), ca.clone()];
+    assert_eq!(subject_public_key_info(&leaf), Some(&leaf_spki[..]));
+
+    let ee = tlsa(USAGE_DANE_EE, SELECTOR_SPKI, MATCHING_SHA256, hash(Sha256::new(), &leaf_spki));
+    assert_eq!(check_chain(&[ee.clone()], &chain), Verdict::Trusted);
+    let ee = tlsa(USAGE_DANE_EE, SELECTOR_CERT, MATCHING_SHA512, hash(Sha512::new(), &leaf));
+    assert_eq!(check_chain(&[ee], &chain), Verdict::Trusted);
+
+    let ta = tlsa(USAGE_DANE_TA, SELECTOR_CERT, MATCHING_EXACT, ca.clone());
+    assert_eq!(check_chain(&[ta.clone()], &chain), Verdict::ValidateChain { anchor: Some(1), pkix: false });
+    // a trust anchor record does not match the end entity certificate
+    assert_eq!(check_chain(&[ta], &chain[..1]), Verdict::Mismatch);
+    let pkix = tlsa(USAGE_PKIX_EE, SELECTOR_CERT, MATCHING_SHA256, hash(Sha256::new(), &leaf));
+    assert_eq!(check_chain(&[pkix], &chain), Verdict::ValidateChain { anchor: None, pkix: true });
+
+    let other = tlsa(USAGE_DANE_EE, SELECTOR_CERT, MATCHING_SHA256, vec![0; 32]);
+    assert_eq!(check_chain(&[other], &chain), Verdict::Mismatch);
+    let unknown = tlsa(USAGE_DANE_EE, SELECTOR_CERT, 255, vec![0; 32]);
+    assert_eq!(check_chain(&[unknown], &chain), Verdict::NoUsableRecords);
+    assert_eq!(check_chain(&[], &chain), Verdict::NoUsableRecords);
+}
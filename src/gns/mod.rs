@@ -1,35 +1,42 @@
 //! Module for connecting to and querying the GNUnet GNS service.
 
-use std::collections::HashMap;
-use std::io::{self, Cursor};
+use std::io::{self, Read, Write, Cursor};
 use std::rc::Rc;
-use std::cell::RefCell;
-use byteorder::{BigEndian, ReadBytesExt};
-use num::ToPrimitive;
-use gj::{Promise};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use gj::{Promise, FulfillerDropped};
 use gjio::{Network};
 
 use identity;
 use ll;
-use service::{self, ServiceReader, ServiceWriter, ReadMessageError, MessageTrait, MessageHeader};
+use service::{self, ReadMessageError};
+use service::dispatch::{self, Deadline, Dispatcher, Route, ReconnectPolicy, EventListener};
+use service::message::{self, CopyError, Message, MessageError};
 use EcdsaPublicKey;
 use EcdsaPrivateKey;
 use Cfg;
+pub use self::block::*;
+pub use self::cache::*;
 pub use self::record::*;
+pub use self::record_data::*;
+pub use self::registry::*;
+pub use self::resolver::*;
 
+mod block;
+mod cache;
 mod record;
+mod record_data;
+mod registry;
+mod resolver;
+pub mod dane;
+pub mod socks;
 
 /// A handle to a locally-running instance of the GNS daemon.
 pub struct GNS {
-    service_reader: ServiceReader,
-    service_writer: ServiceWriter,
-    lookup_id: u32,
-    results_table: Rc<RefCell<HashMap<u32, Vec<Record>>>>,
-    read_queue: Rc<RefCell<Option<Promise<(), ()>>>>,
+    dispatcher: Dispatcher,
 }
 
 /// Options for GNS lookups.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum LocalOptions {
     /// Default behaviour. Look in the local cache, then in the DHT.
     Default     = 0,
@@ -40,6 +47,18 @@ pub enum LocalOptions {
     LocalMaster = 2,
 }
 
+impl LocalOptions {
+    /// Creates a `LocalOptions` from the value used on the wire.
+    pub fn from_i16(x: i16) -> Option<LocalOptions> {
+        Some(match x {
+            0 => LocalOptions::Default,
+            1 => LocalOptions::NoDHT,
+            2 => LocalOptions::LocalMaster,
+            _ => return None,
+        })
+    }
+}
+
 /// Possible errors returned by the GNS lookup functions.
 error_def! LookupError {
     InvalidType { tpe: u16 }
@@ -48,8 +67,44 @@ error_def! LookupError {
         => "The domain name was too long" ("The domain name \"{}\" is too long to lookup.", name),
     Io { #[from] cause: io::Error }
         => "There was an I/O error communicating with the service" ("Specifically {}", cause),
-    ReadMessage { #[from] cause: ReadMessageError }
+    ReadMessage { cause: ReadMessageError }
         => "Failed to receive the response from the GNS service" ("Reason: {}", cause),
+    Message { #[from] cause: MessageError }
+        => "Failed to encode or decode a message exchanged with the GNS service" ("Reason: {}", cause),
+    TimedOut
+        => "The lookup did not complete before its deadline",
+    Cancelled
+        => "The lookup was cancelled",
+}
+
+impl From<ReadMessageError> for LookupError {
+    fn from(e: ReadMessageError) -> LookupError {
+        match e {
+            ReadMessageError::TimedOut  => LookupError::TimedOut,
+            ReadMessageError::Cancelled => LookupError::Cancelled,
+            e                           => LookupError::ReadMessage { cause: e },
+        }
+    }
+}
+
+impl FulfillerDropped for LookupError {
+    fn fulfiller_dropped() -> LookupError {
+        LookupError::ReadMessage { cause: ReadMessageError::FulfillerDropped }
+    }
+}
+
+impl CopyError for LookupError {
+    fn copy_error(&self) -> LookupError {
+        match *self {
+            LookupError::InvalidType { tpe }        => LookupError::InvalidType { tpe: tpe },
+            LookupError::NameTooLong { ref name }   => LookupError::NameTooLong { name: name.clone() },
+            LookupError::Io { ref cause }           => LookupError::Io { cause: cause.copy_error() },
+            LookupError::ReadMessage { ref cause }  => LookupError::ReadMessage { cause: cause.copy_error() },
+            LookupError::Message { ref cause }      => LookupError::Message { cause: cause.copy_error() },
+            LookupError::TimedOut                   => LookupError::TimedOut,
+            LookupError::Cancelled                  => LookupError::Cancelled,
+        }
+    }
 }
 
 impl GNS {
@@ -58,23 +113,45 @@ impl GNS {
     /// Returns either a promise to the GNS service or a `service::ConnectError`. `cfg` contains the
     /// configuration to use to connect to the service.
     pub fn connect(cfg: &Cfg, network: &Network) -> Promise<GNS, service::ConnectError> {
-
-        let table = Rc::new(RefCell::new(HashMap::new()));
         service::connect(cfg, "gns", network).lift().map(move |(sr, sw)| {
             Ok(GNS {
-                service_reader: sr,
-                service_writer: sw,
-                lookup_id : 0,
-                results_table: table,
-                read_queue: Rc::new(RefCell::new(None)),
+                dispatcher: Dispatcher::new(sr, sw, Box::new(GNS::route)),
             })
         })
     }
 
+    /// Reconnect to the service according to `policy` whenever the connection is lost. Lookups
+    /// made while disconnected are sent once the connection is re-established.
+    pub fn enable_reconnect(&mut self, cfg: &Cfg, network: &Network, policy: ReconnectPolicy) {
+        self.dispatcher.enable_reconnect(dispatch::connector(cfg, "gns", network), policy);
+    }
+
+    /// Call `listener` whenever the connection to the service is lost or re-established.
+    pub fn on_service_event(&mut self, listener: EventListener) {
+        self.dispatcher.on_event(listener);
+    }
+
+    // lookup results start with the id of the lookup they answer
+    fn route(tpe: u16, body: &[u8]) -> Route {
+        match tpe {
+            ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP_RESULT |
+            ll::GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP_RESULT if body.len() >= 4 => Route::Reply(BigEndian::read_u32(body)),
+            _ => Route::Unsolicited,
+        }
+    }
+
     /// Lookup a vector of GNS records.
     /// A promise of the result is returned.
     ///
-    /// If `shorten` is not `None` then the result is added to the given shorten zone.
+    /// The promise resolves to an empty vector if the name has no records of type `record_type`.
+    ///
+    /// If `shorten` is not `None` then the result is added to the given shorten zone. If
+    /// `deadline` passes before the service answers the lookup fails with
+    /// `LookupError::TimedOut`. Dropping the promise cancels the lookup.
+    ///
+    /// Names of the form `_port._protocol.name`, see `split_service_name`, are resolved by looking
+    /// up the BOX records of `name` and returning the records of type `record_type` boxed for that
+    /// port and protocol.
     ///
     /// # Example
     ///
@@ -88,7 +165,7 @@ impl GNS {
     /// let gns_master = ::std::rc::Rc::new("gns-master".to_string());
     ///
     /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-    ///     let ego = identity::get_default_ego(&config, gns_master, &network).wait(wait_scope, &mut event_port).unwrap();
+    ///     let ego = identity::get_default_ego(&config, gns_master, &network, None).wait(wait_scope, &mut event_port).unwrap();
     ///     let pk = ego.get_public_key();
     ///     let mut gns = GNS::connect(&config, &network).wait(wait_scope, &mut event_port).unwrap();
     ///     let promises = vec!["gnu.org", "gnunet.org", "freebsd.org"].into_iter().map(|d| {
@@ -96,6 +173,7 @@ impl GNS {
     ///                    pk,
     ///                    gns::RecordType::A,
     ///                    gns::LocalOptions::LocalMaster,
+    ///                    None,
     ///                    None)
     ///     });
     ///     let ips = async::Promise::all(promises).wait(wait_scope, &mut event_port).unwrap();
@@ -108,98 +186,88 @@ impl GNS {
                    zone: EcdsaPublicKey,
                    record_type: RecordType,
                    options: LocalOptions,
-                   shorten: Option<EcdsaPrivateKey>)
+                   shorten: Option<EcdsaPrivateKey>,
+                   deadline: Option<Deadline>)
                    -> Promise<Vec<Record>, LookupError>
     {
-        let id = self.lookup_id;
-        self.lookup_id += 1;
-        let msg = pry!(LookupMessage::new(id, zone, options, shorten, record_type, &name));
-        let mut sr = self.service_reader.clone();
-        let map = self.results_table.clone();
-        let read_queue = self.read_queue.clone();
-
-        self.service_writer.send_with_str(msg, &name).lift().then(move |_| {
-            let result = map.borrow_mut().remove(&id);
-            match result {
-                // first check whether we already have the result
-                Some(x) => Promise::ok(x),
-
-                // if there are no results then add task to the read queue
-                None => {
-                    let maybe_queue = read_queue.borrow_mut().take();
-                    let promise = match maybe_queue {
-                        Some(queue) => queue.then_else(move |_| { GNS::lookup_loop(&mut sr, id, map) }),
-                        None        => GNS::lookup_loop(&mut sr, id, map),
-                    };
-
-                    let (p, f) = Promise::and_fulfiller();
-                    *read_queue.borrow_mut() = Some(p);
-
-                    promise.map_else(|r| {
-                        f.resolve(Ok(()));
-                        r
-                    })
-                },
-            }
-        }).lift()
+        let boxed = match split_service_name(&name) {
+            Some((service, protocol, rest)) if record_type != RecordType::BOX => Some((service, protocol, rest)),
+            _ => None,
+        };
+        let id = self.dispatcher.next_id();
+        match boxed {
+            Some((service, protocol, rest)) => {
+                let msg = pry!(LookupMessage::new(id, zone, options, shorten, RecordType::BOX, rest));
+                self.dispatcher.request(id, &msg, deadline).map(move |(tpe, mr)| {
+                    let records = try!(GNS::parse_lookup_result(tpe, mr));
+                    Ok(unbox_records(&records, protocol, service, record_type))
+                })
+            },
+            None => {
+                let msg = pry!(LookupMessage::new(id, zone, options, shorten, record_type, &name));
+                self.dispatcher.request(id, &msg, deadline).map(|(tpe, mr)| {
+                    GNS::parse_lookup_result(tpe, mr)
+                })
+            },
+        }
     }
 
-    fn lookup_loop(sr: &mut ServiceReader, id: u32, map: Rc<RefCell<HashMap<u32, Vec<Record>>>>)
-                   -> Promise<Vec<Record>, LookupError> {
-        let mut sr2 = sr.clone();
-        let map2 = map.clone();
-        sr.read_message()
-            .lift()
-            .then(move |(tpe, mr)| {
-                match GNS::parse_lookup_result(tpe, mr, map) {
-                    Ok(()) => {
-                        // read again if the id is not available yet
-                        let result = map2.borrow_mut().remove(&id);
-                        match result {
-                            Some(x) => return Promise::ok(x),
-                            None    => return GNS::lookup_loop(&mut sr2, id, map2),
-                        };
-                    },
-                    Err(e) => return Promise::err(e),
-                }
-            })
+    /// Find a name for the zone `zone_key` as seen from the zone `root_zone`, usually the master
+    /// zone.
+    ///
+    /// The service searches the delegations reachable from `root_zone` for a PKEY record pointing
+    /// at `zone_key` and returns the name it is found under, eg. `"bob.friends.gnu"`. The promise
+    /// resolves to `None` if no such delegation is known.
+    ///
+    /// `deadline` and cancellation work as for `lookup`.
+    pub fn reverse_lookup(&mut self,
+                          zone_key: EcdsaPublicKey,
+                          root_zone: EcdsaPublicKey,
+                          deadline: Option<Deadline>)
+                          -> Promise<Option<String>, LookupError>
+    {
+        let id = self.dispatcher.next_id();
+        let msg = ReverseLookupMessage {
+            id: id,
+            zone_key: zone_key,
+            root_zone: root_zone,
+        };
+        self.dispatcher.request(id, &msg, deadline).map(|(tpe, mr)| {
+            match tpe {
+                ll::GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP_RESULT => {
+                    let msg: ReverseLookupResultMessage = try!(message::decode(tpe, mr));
+                    Ok(msg.name)
+                },
+                x => Err(LookupError::InvalidType { tpe: x }),
+            }
+        })
     }
 
-    // in this function, results are inserted in to the map
-    fn parse_lookup_result(tpe: u16, mut reader: Cursor<Vec<u8>>, map: Rc<RefCell<HashMap<u32, Vec<Record>>>>)
-                           -> Result<(), LookupError> {
+    fn parse_lookup_result(tpe: u16, reader: Cursor<Vec<u8>>) -> Result<Vec<Record>, LookupError> {
         match tpe {
             ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP_RESULT => {
-                let mut records = Vec::new();
-
-                let id = try!(reader.read_u32::<BigEndian>());
-                let rd_count = try!(reader.read_u32::<BigEndian>());
-                for _ in 0..rd_count {
-                    let rec = try!(Record::deserialize(&mut reader));
-                    records.push(rec);
-                };
-
-                if !records.is_empty() {
-                    map.borrow_mut().insert(id, records);
-                }
+                let msg: LookupResultMessage = try!(message::decode(tpe, reader));
+                Ok(msg.records)
             },
-            x => return Err(LookupError::InvalidType { tpe: x }), // TODO reconnect here instead of returning error
-        };
-        Ok(())
+            x => Err(LookupError::InvalidType { tpe: x }),
+        }
     }
 }
 
-/// Packed struct representing GNUNET_GNS_ClientLookupMessage.
-#[repr(C, packed)]
-struct LookupMessage {
-    header: MessageHeader,
-    id: u32,
-    zone: EcdsaPublicKey,
-    options: i16, // LocalOptions
-    have_key: i16, // 0 or 1
-    record_type: i32, // RecordType
-    shorten_key: EcdsaPrivateKey,
-    // followed by 0-terminated name to look up
+/// GNUNET_GNS_ClientLookupMessage, sent to the service to start a lookup.
+pub struct LookupMessage {
+    /// The request id, echoed back in the `LookupResultMessage`.
+    pub id: u32,
+    /// The zone to start the lookup in.
+    pub zone: EcdsaPublicKey,
+    /// Whether the DHT may be asked.
+    pub options: LocalOptions,
+    /// The type of the records wanted.
+    pub record_type: RecordType,
+    /// The zone to add the shortened result to, if any.
+    pub shorten: Option<EcdsaPrivateKey>,
+    /// The name to look up.
+    pub name: String,
 }
 
 impl LookupMessage {
@@ -210,38 +278,161 @@ impl LookupMessage {
            record_type: RecordType,
            name: &str) -> Result<LookupMessage, LookupError> {
 
-        let name_len = name.len();
-        if name_len > ll::GNUNET_DNSPARSER_MAX_NAME_LENGTH as usize {
+        if name.len() > ll::GNUNET_DNSPARSER_MAX_NAME_LENGTH as usize {
             return Err(LookupError::NameTooLong { name: name.to_string() });
         };
 
-        let msg_len = match (::std::mem::size_of::<LookupMessage>() + name_len + 1).to_u16() {
-            Some(x) => x,
-            _     => return Err(LookupError::NameTooLong { name: name.to_string() }),
+        Ok(LookupMessage {
+            id: id,
+            zone: zone,
+            options: options,
+            record_type: record_type,
+            shorten: shorten,
+            name: name.to_string(),
+        })
+    }
+}
+
+impl Message for LookupMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(self.id));
+        try!(self.zone.serialize(w));
+        try!(w.write_i16::<BigEndian>(self.options as i16));
+        try!(w.write_i16::<BigEndian>(self.shorten.is_some() as i16));
+        try!(w.write_i32::<BigEndian>(self.record_type.to_u32() as i32));
+        match self.shorten {
+            Some(ref sk) => try!(sk.serialize(w)),
+            None         => try!(EcdsaPrivateKey::zeros().serialize(w)),
         };
+        message::write_c_string(w, &self.name)
+    }
 
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<LookupMessage, MessageError> {
+        let tpe = LookupMessage::message_type();
+        let id = try!(r.read_u32::<BigEndian>());
+        let zone = try!(EcdsaPublicKey::deserialize(r));
+        let options = match LocalOptions::from_i16(try!(r.read_i16::<BigEndian>())) {
+            Some(o) => o,
+            None    => return Err(MessageError::InvalidField { tpe: tpe, field: "options" }),
+        };
+        let have_key = try!(r.read_i16::<BigEndian>());
+        let record_type = RecordType::from_u32(try!(r.read_i32::<BigEndian>()) as u32);
+        let shorten_key = try!(EcdsaPrivateKey::deserialize(r));
+        let name = try!(message::read_c_string(r));
         Ok(LookupMessage {
-            header: MessageHeader {
-                len: msg_len.to_be(),
-                tpe: ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP.to_be(),
-            },
-            id: id.to_be(),
+            id: id,
             zone: zone,
-            options: (options as i16).to_be(),
-            have_key: (shorten.is_some() as i16).to_be(),
-            record_type: (record_type as i32).to_be(),
-            shorten_key: match shorten {
-                Some(x) => x,
-                None    => EcdsaPrivateKey::zeros(),
-            }
+            options: options,
+            record_type: record_type,
+            shorten: if have_key != 0 { Some(shorten_key) } else { None },
+            name: name,
         })
     }
 }
 
-impl MessageTrait for LookupMessage {
-    // Note that this does not include the 0-terminated string.
-    fn into_slice(&self) -> &[u8] {
-        message_to_slice!(LookupMessage, self)
+/// GNUNET_GNS_ClientLookupResultMessage, sent by the service with the results of a lookup.
+pub struct LookupResultMessage {
+    /// The id of the `LookupMessage` this answers.
+    pub id: u32,
+    /// The records found, empty if there are none.
+    pub records: Vec<Record>,
+}
+
+impl Message for LookupResultMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP_RESULT
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(self.id));
+        try!(w.write_u32::<BigEndian>(self.records.len() as u32));
+        for record in self.records.iter() {
+            try!(record.serialize(w));
+        }
+        Ok(())
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<LookupResultMessage, MessageError> {
+        let id = try!(r.read_u32::<BigEndian>());
+        let rd_count = try!(r.read_u32::<BigEndian>());
+        let mut records = Vec::new();
+        for _ in 0..rd_count {
+            records.push(try!(Record::deserialize(r)));
+        }
+        Ok(LookupResultMessage {
+            id: id,
+            records: records,
+        })
+    }
+}
+
+/// GNUNET_GNS_ReverseLookupMessage, sent to the service to find the name of a zone.
+pub struct ReverseLookupMessage {
+    /// The request id, echoed back in the `ReverseLookupResultMessage`.
+    pub id: u32,
+    /// The zone whose name is wanted.
+    pub zone_key: EcdsaPublicKey,
+    /// The zone the name is relative to.
+    pub root_zone: EcdsaPublicKey,
+}
+
+impl Message for ReverseLookupMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(self.id));
+        try!(self.zone_key.serialize(w));
+        try!(self.root_zone.serialize(w));
+        Ok(())
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<ReverseLookupMessage, MessageError> {
+        let id = try!(r.read_u32::<BigEndian>());
+        let zone_key = try!(EcdsaPublicKey::deserialize(r));
+        let root_zone = try!(EcdsaPublicKey::deserialize(r));
+        Ok(ReverseLookupMessage {
+            id: id,
+            zone_key: zone_key,
+            root_zone: root_zone,
+        })
+    }
+}
+
+/// GNUNET_GNS_ReverseLookupResultMessage, sent by the service with the name found by a reverse
+/// lookup. The service sends an empty name if it found none.
+pub struct ReverseLookupResultMessage {
+    /// The id of the `ReverseLookupMessage` this answers.
+    pub id: u32,
+    /// The name of the zone, or `None` if the service found none.
+    pub name: Option<String>,
+}
+
+impl Message for ReverseLookupResultMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP_RESULT
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(self.id));
+        match self.name {
+            Some(ref name) => message::write_c_string(w, name),
+            None           => message::write_c_string(w, ""),
+        }
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<ReverseLookupResultMessage, MessageError> {
+        let id = try!(r.read_u32::<BigEndian>());
+        let name = try!(message::read_c_string(r));
+        Ok(ReverseLookupResultMessage {
+            id: id,
+            name: if name.is_empty() { None } else { Some(name) },
+        })
     }
 }
 
@@ -257,9 +448,11 @@ error_def! ConnectLookupError {
 
 /// Lookup a GNS record in the given zone.
 ///
-/// If `shorten` is not `None` then the result is added to the given shorten zone.
-/// The returned promise can only be fulfilled when a record is found.
-/// The user should consider setting a timeout in case no record can be found.
+/// The promise resolves to all the records found, which is an empty vector if the name has no
+/// records of type `record_type`.
+///
+/// If `shorten` is not `None` then the result is added to the given shorten zone. If `deadline`
+/// passes before the service answers the lookup fails with `LookupError::TimedOut`.
 ///
 /// # Example
 ///
@@ -274,46 +467,53 @@ error_def! ConnectLookupError {
 /// let gnu_org = ::std::rc::Rc::new("gnu.org".to_string());
 ///
 /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-///     let ego = identity::get_default_ego(&config, gns_master, &network).wait(wait_scope, &mut event_port).unwrap();
+///     let ego = identity::get_default_ego(&config, gns_master, &network, None).wait(wait_scope, &mut event_port).unwrap();
 ///     let pk = ego.get_public_key();
-///     let record = gns::lookup(&config,
-///                              &network,
-///                              gnu_org,
-///                              pk,
-///                              gns::RecordType::A,
-///                              gns::LocalOptions::LocalMaster,
-///                              None).wait(wait_scope, &mut event_port).unwrap();
-///     println!("Got the IPv4 record for gnu.org: {}", record);
+///     let records = gns::lookup(&config,
+///                               &network,
+///                               gnu_org,
+///                               pk,
+///                               gns::RecordType::A,
+///                               gns::LocalOptions::LocalMaster,
+///                               None,
+///                               None).wait(wait_scope, &mut event_port).unwrap();
+///     for record in records {
+///         println!("Got an IPv4 record for gnu.org: {}", record);
+///     }
 ///     Ok(())
 /// }).expect("top_level");
 /// ```
 ///
 /// # Note
 ///
-/// This is a convenience function that connects to the GNS service, performs the lookup, retrieves
-/// one result, then disconects. If you are performing multiple lookups this function should be
-/// avoided and `GNS::lookup_in_zone` used instead.
+/// This is a convenience function that connects to the GNS service, performs the lookup, then
+/// disconects. If you are performing multiple lookups this function should be avoided and
+/// `GNS::lookup` used instead.
 pub fn lookup(cfg: &Cfg,
               network: &Network,
               name: Rc<String>,
               zone: EcdsaPublicKey,
               record_type: RecordType,
               options: LocalOptions,
-              shorten: Option<EcdsaPrivateKey>) -> Promise<Record, ConnectLookupError> {
+              shorten: Option<EcdsaPrivateKey>,
+              deadline: Option<Deadline>) -> Promise<Vec<Record>, ConnectLookupError> {
     GNS::connect(cfg, network)
         .lift()
         .then(move |mut gns| {
-            println!("connected to GNS");
-            gns.lookup(name, zone, record_type, options, shorten).lift()
-                .map(move |mut result| {
-                    // it's ok to unwrap here because gns.lookup does not stop if it hasn't found a result
-                    Ok(result.pop().unwrap())
+            let lookup_promise = gns.lookup(name, zone, record_type, options, shorten, deadline);
+            lookup_promise.lift()
+                .map(move |records| {
+                    // keep the connection open until the lookup has completed
+                    drop(gns);
+                    Ok(records)
                 })
         })
 }
 
-/// Errors returned by `gns::lookup_in_master`.
+/// Errors returned by `gns::lookup_in_master` and `gns::resolve`.
 error_def! ConnectLookupInMasterError {
+    Zone { #[from] cause: ZoneError }
+        => "Failed to choose the zone to look the name up in" ("Reason: {}", cause),
     GnsLookup { #[from] cause: ConnectLookupError }
         => "Failed to connect to the GNS service and perform the lookup" ("Reason: {}", cause),
     IdentityGetDefaultEgo { #[from] cause: identity::ConnectGetDefaultEgoError }
@@ -324,9 +524,13 @@ error_def! ConnectLookupInMasterError {
 
 /// Lookup a GNS record in the master zone.
 ///
-/// If `shorten` is not `None` then the result is added to the given shorten zone.
-/// The returned promise can only be fulfilled when a record is found.
-/// The user should consider setting a timeout in case no record can be found.
+/// The zone is chosen from the TLD of `name` as described in the `resolver` module, so `.zkey`
+/// names and TLDs configured in the `[gns]` section are looked up in their own zones. Use
+/// `gns::resolve` to find out which zone was used.
+///
+/// The promise resolves to all the records found, see `gns::lookup`. If `shorten` is not `None`
+/// then the result is added to the given shorten zone. `deadline` applies to fetching the default
+/// ego and to the lookup separately.
 ///
 /// # Example
 ///
@@ -340,9 +544,12 @@ error_def! ConnectLookupInMasterError {
 /// let gnu_org = ::std::rc::Rc::new("gnu.org".to_string());
 ///
 /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-///     let record_promise = gnunet::lookup_in_master(&config, &network, gnu_org, gns::RecordType::A, None);
-///     let record = record_promise.wait(wait_scope, &mut event_port).unwrap();
-///     println!("Got the IPv4 record for gnu.org: {}", record);
+///     let record_promise = gnunet::lookup_in_master(&config, &network, gnu_org, gns::RecordType::A, None, None);
+///     let records = record_promise.wait(wait_scope, &mut event_port).unwrap();
+///     match records.first() {
+///         Some(record) => println!("Got the IPv4 record for gnu.org: {}", record),
+///         None         => println!("gnu.org has no IPv4 record"),
+///     }
 ///     Ok(())
 /// }).expect("top_level");
 /// ```
@@ -350,29 +557,121 @@ error_def! ConnectLookupInMasterError {
 /// # Note
 ///
 /// This is a convenience function that connects to the identity service, fetches the default ego
-/// for gns-master, then connects to the GNS service, performs the lookup, retrieves one result,
-/// then disconnects from everything. If you are performing lots of lookups this function should be
+/// for gns-master, then connects to the GNS service, performs the lookup, then disconnects from
+/// everything. If you are performing lots of lookups this function should be
 /// avoided and `GNS::lookup` should be used instead.
 pub fn lookup_in_master(cfg: &Cfg,
                         network: &Network,
                         name: Rc<String>,
                         record_type: RecordType,
-                        shorten: Option<EcdsaPrivateKey>) -> Promise<Record, ConnectLookupInMasterError> {
-    println!("Getting default ego");
+                        shorten: Option<EcdsaPrivateKey>,
+                        deadline: Option<Deadline>) -> Promise<Vec<Record>, ConnectLookupInMasterError> {
+    resolve(cfg, network, name, record_type, shorten, deadline).map(|(_, records)| Ok(records))
+}
+
+/// Lookup a GNS record, choosing the zone from the TLD of `name`.
+///
+/// The promise resolves to the zone, relative name and options the lookup was made with, along
+/// with the records found. The default ego for gns-master is only fetched from the identity
+/// service if `name` is in the master zone. Otherwise this works like `gns::lookup_in_master`.
+pub fn resolve(cfg: &Cfg,
+               network: &Network,
+               name: Rc<String>,
+               record_type: RecordType,
+               shorten: Option<EcdsaPrivateKey>,
+               deadline: Option<Deadline>) -> Promise<(Resolution, Vec<Record>), ConnectLookupInMasterError> {
+    let resolution = match pry!(zone_for_name(cfg, &name)) {
+        Some(resolution) => Promise::ok(resolution),
+        None => {
+            identity::get_default_ego(cfg, Rc::new("gns-master".to_string()), network, deadline.clone())
+                .lift()
+                .map(move |ego| Ok(Resolution::in_master(&name, ego.get_public_key())))
+        },
+    };
     let network2 = network.clone();
     let cfg2 = cfg.clone();
-    identity::get_default_ego(cfg, Rc::new("gns-master".to_string()), network)
-        .lift()
-        .then(move |ego| {
-            let pk = ego.get_public_key();
-            let opt = {
-                let mut it = name.split('.');
-                match (it.next(), it.next(), it.next()) {
-                    (Some(_), Some("gnu"), None)  => LocalOptions::NoDHT,
-                    _                             => LocalOptions::LocalMaster,
-                }
-            };
-            println!("doing lookup");
-            lookup(&cfg2, &network2, name, pk, record_type, opt, shorten).lift()
+    resolution.then(move |resolution| {
+        let name = Rc::new(resolution.name.clone());
+        lookup(&cfg2, &network2, name, resolution.zone, record_type, resolution.options, shorten, deadline)
+            .lift()
+            .map(move |records| Ok((resolution, records)))
+    })
+}
+
+#[test]
+fn test_lookup_result_message_round_trip() {
+    use std::str::FromStr;
+
+    // an A record for 10.0.0.1 followed by an empty TXT record
+    let raw = vec![0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0, 10, 0, 0, 1,
+                   0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 2];
+    let mut records = Vec::new();
+    let mut reader = Cursor::new(raw);
+    records.push(Record::deserialize(&mut reader).unwrap());
+    records.push(Record::deserialize(&mut reader).unwrap());
+
+    let msg = LookupResultMessage { id: 3, records: records };
+    let buf = message::encode(&msg).unwrap();
+    assert_eq!(buf.len(), 4 + 8 + 24 + 20);
+
+    let mut mr = Cursor::new(buf);
+    mr.read_u32::<BigEndian>().unwrap();
+    let decoded: LookupResultMessage = message::decode(LookupResultMessage::message_type(), mr).unwrap();
+    assert_eq!(decoded.id, 3);
+    assert_eq!(decoded.records.len(), 2);
+    assert_eq!(decoded.records[0].record_type(), RecordType::A);
+    assert_eq!(decoded.records[1].record_type(), RecordType::TXT);
+
+    let zone = EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+    let msg = LookupMessage::new(1, zone, LocalOptions::NoDHT, None, RecordType::AAAA, "gnu.org").unwrap();
+    let buf = message::encode(&msg).unwrap();
+    assert_eq!(buf.len(), 80 + "gnu.org".len() + 1);
+}
+
+#[test]
+fn test_reverse_lookup() {
+    use std::str::FromStr;
+    use testing::{MockService, TestPeer};
+
+    let friend = EcdsaPrivateKey::zeros().get_public();
+    let mut service = MockService::new("gns");
+    service.on_message(move |msg: ReverseLookupMessage, replies| {
+        replies.send(&ReverseLookupResultMessage {
+            id: msg.id,
+            name: if msg.zone_key == friend { Some("bob.gnu".to_string()) } else { None },
         })
+    });
+
+    TestPeer::with_service(service, GNS::connect, |mut gns, t| {
+        let master = EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+        assert_eq!(t.wait(gns.reverse_lookup(friend, master, None)).unwrap(), Some("bob.gnu".to_string()));
+        assert_eq!(t.wait(gns.reverse_lookup(master, master, None)).unwrap(), None);
+    });
+}
+
+#[test]
+fn test_lookup_boxed() {
+    use std::str::FromStr;
+    use testing::{MockService, TestPeer};
+
+    let mut service = MockService::new("gns");
+    service.on_message(move |msg: LookupMessage, replies| {
+        assert_eq!(msg.name, "www.gnu");
+        assert_eq!(msg.record_type, RecordType::BOX);
+        let tlsa = RecordData::TLSA { usage: 3, selector: 1, matching_type: 1, data: vec![0xab; 32] };
+        let boxes = vec![(6, 443), (6, 80), (17, 443)].into_iter().map(|(protocol, service)| {
+            let value = RecordData::BOX { protocol: protocol, service: service, value: Box::new(tlsa.clone()) };
+            Record::from_value(&value, 0, RecordFlags::empty()).unwrap()
+        });
+        replies.send(&LookupResultMessage { id: msg.id, records: boxes.collect() })
+    });
+
+    TestPeer::with_service(service, GNS::connect, |mut gns, t| {
+        let zone = EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+        let name = Rc::new("_443._tcp.www.gnu".to_string());
+        let records = t.wait(gns.lookup(name, zone, RecordType::TLSA, LocalOptions::Default, None, None)).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record_type(), RecordType::TLSA);
+        assert_eq!(records[0].to_string(), format!("3 1 1 {}", vec!["ab"; 32].concat()));
+    });
 }
@@ -1,67 +1,80 @@
 use std::str::FromStr;
 use std::fmt::{Debug, Formatter};
 use std::fmt;
-use std::io::{self, Read};
-use std::os::raw::{c_void};
-use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{self, Read, Write};
+use std::ops::BitOr;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 
-use ll::{self, size_t};
 use self::RecordType::*;
+use super::{RecordData, RecordDataError, RecordTypeRegistry};
+use time;
 use util::io::ReadUtil;
 
 /// An enum of the different GNS record types.
 ///
 /// Some of these records exist in the legacy DNS (but are still used in GNS). Others are specific
-/// to GNS. These are marked **Legacy** and **GNS** respectively.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// to GNS. These are marked **Legacy** and **GNS** respectively. Any other type, including
+/// application specific ones, is represented by `Other`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum RecordType {
   /// **Legacy.** Address record. Stores a 32bit IPv4 address.
-  A       = 1,
+  A,
   /// **Legacy.** Name server record. Delegates a DNS zone to use the given authoritative name servers.
-  NS      = 2,
+  NS,
   /// **Legacy.** Canonical name record. Alias of one name to another.
-  CNAME   = 5,
+  CNAME,
   /// **Legacy.** Start of authority record. Specifies authoritative information about a DNS zone.
-  SOA     = 6,
+  SOA,
   /// **Legacy.** Pointer record. Pointer to a canonical name.
-  PTR     = 12,
+  PTR,
   /// **Legacy.** Mail exchange record. Maps a domain name to a list of message transfer agents for that
   /// domain.
-  MX      = 15,
+  MX,
   /// **Legacy.** Text record. Used to store human-readable data and various forms of machine-readable data.
-  TXT     = 16,
+  TXT,
   /// **Legacy.** Address record. Stores a 128bit IPv6 address.
-  AAAA    = 28,
+  AAAA,
   /// **Legacy.** TLSA certificate association. A record for DNS-based Authentication of Named Entities (DANE).
-  TLSA    = 52,
+  TLSA,
 
   /// **GNS.** Petname key record. Used to delegate to other users' zones and give those zones a petname.
-  PKEY    = 65536,
+  PKEY,
   /// **GNS.** Nickname record. Used to give a zone a name.
-  NICK    = 65537,
+  NICK,
   /// **GNS.** Legacy hostname record.
-  LEHO    = 65538,
+  LEHO,
   /// **GNS.** Virtual public network record.
-  VPN     = 65539,
+  VPN,
   /// **GNS.** GNS2DNS record. Used to delegate authority to a legacy DNS zone.
-  GNS2DNS = 65540,
+  GNS2DNS,
+  /// **GNS.** Boxed record. Holds a record, typically SRV or TLSA, for one port and protocol of a
+  /// name. Looked up as `_port._protocol.name`.
+  BOX,
+
+  /// A type not listed above, given by its number. `RecordType::from_u32` never returns `Other`
+  /// for the number of a listed type.
+  Other(u32),
 }
 
+/// The record type number which asks `GNS::lookup` for the records of every type, GNUnet's
+/// `GNUNET_GNSRECORD_TYPE_ANY`. `RecordType::from_u32` turns it into `Other(RECORD_TYPE_ANY)`.
+pub const RECORD_TYPE_ANY: u32 = 0;
+
 impl RecordType {
   /// Creates a RecordType from it's record type number.
   ///
   /// # Example
   ///
   /// ```rust
-  /// use gnunet::gns::RecordType::{self, A};
+  /// use gnunet::gns::RecordType::{self, A, Other};
   ///
   /// let x = RecordType::from_u32(1);
   /// let y = RecordType::from_u32(1234);
-  /// assert!(x == Some(A));
-  /// assert!(y == None);
+  /// assert!(x == A);
+  /// assert!(y == Other(1234));
   /// ```
-  pub fn from_u32(x: u32) -> Option<RecordType> {
-    Some(match x {
+  pub fn from_u32(x: u32) -> RecordType {
+    match x {
       1 => A,
       2 => NS,
       5 => CNAME,
@@ -77,9 +90,34 @@ impl RecordType {
       65538 => LEHO,
       65539 => VPN,
       65540 => GNS2DNS,
+      65541 => BOX,
 
-      _ => return None,
-    })
+      x => Other(x),
+    }
+  }
+
+  /// The record type number.
+  pub fn to_u32(&self) -> u32 {
+    match *self {
+      A       => 1,
+      NS      => 2,
+      CNAME   => 5,
+      SOA     => 6,
+      PTR     => 12,
+      MX      => 15,
+      TXT     => 16,
+      AAAA    => 28,
+      TLSA    => 52,
+
+      PKEY    => 65536,
+      NICK    => 65537,
+      LEHO    => 65538,
+      VPN     => 65539,
+      GNS2DNS => 65540,
+      BOX     => 65541,
+
+      Other(x) => x,
+    }
   }
 }
 
@@ -108,6 +146,7 @@ impl FromStr for RecordType {
       "LEHO"    => Ok(LEHO),
       "VPN"     => Ok(VPN),
       "GNS2DNS" => Ok(GNS2DNS),
+      "BOX"     => Ok(BOX),
       _         => Err(RecordTypeFromStrError::ParsingFailed),
     }
   }
@@ -115,62 +154,436 @@ impl FromStr for RecordType {
 
 impl fmt::Display for RecordType {
   fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-    Debug::fmt(self, f)
+    match *self {
+      Other(x) => write!(f, "{}", x),
+      _        => Debug::fmt(self, f),
+    }
+  }
+}
+
+/// A set of flags describing how a record is to be used.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RecordFlags {
+  bits: u32,
+}
+
+/// The record is not published, it is only used by the zone owner.
+pub const RF_PRIVATE: RecordFlags = RecordFlags { bits: 2 };
+
+/// The expiration time of the record is relative to the time it is received, not absolute.
+pub const RF_RELATIVE_EXPIRATION: RecordFlags = RecordFlags { bits: 8 };
+
+/// The record is only used once all records of the same type without this flag have expired.
+/// Used to publish a replacement ahead of time when changing a record.
+pub const RF_SHADOW: RecordFlags = RecordFlags { bits: 16 };
+
+/// The record was not asked for but is returned because it describes the records that were, eg.
+/// the NICK of the zone.
+pub const RF_SUPPLEMENTAL: RecordFlags = RecordFlags { bits: 32 };
+
+impl RecordFlags {
+  /// No flags set.
+  pub fn empty() -> RecordFlags {
+    RecordFlags { bits: 0 }
+  }
+
+  /// The flags set in `bits`, including ones not known to this crate.
+  pub fn from_bits(bits: u32) -> RecordFlags {
+    RecordFlags { bits: bits }
+  }
+
+  /// The flags as stored in a record.
+  pub fn bits(&self) -> u32 {
+    self.bits
+  }
+
+  /// Whether all the flags in `other` are set.
+  pub fn contains(&self, other: RecordFlags) -> bool {
+    self.bits & other.bits == other.bits
+  }
+}
+
+impl BitOr for RecordFlags {
+  type Output = RecordFlags;
+
+  fn bitor(self, other: RecordFlags) -> RecordFlags {
+    RecordFlags { bits: self.bits | other.bits }
   }
 }
 
+/// When a record expires.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Expiration {
+  /// The record expires at a fixed time.
+  Absolute(time::Absolute),
+  /// The record expires this long after it was received.
+  Relative(time::Relative),
+}
+
 /// A record in the GNU Name System.
-#[allow(dead_code)]
+#[derive(Clone)]
 pub struct Record {
-  data: ll::Struct_GNUNET_GNSRECORD_Data,
-  buff: Vec<u8>,
+  expiration_time: u64,
+  record_type: u32,
+  flags: u32,
+  data: Vec<u8>,
 }
 
 impl Record {
+  /// Create a record of type `record_type` holding `data` in its serialized form.
+  ///
+  /// `expiration_time` is in microseconds and is absolute unless `flags` contains
+  /// `RF_RELATIVE_EXPIRATION`.
+  pub fn new(record_type: RecordType, expiration_time: u64, flags: RecordFlags, data: Vec<u8>) -> Record {
+    Record {
+      expiration_time:  expiration_time,
+      record_type:      record_type.to_u32(),
+      flags:            flags.bits(),
+      data:             data,
+    }
+  }
+
+  /// Create a record holding `value`.
+  pub fn from_value(value: &RecordData, expiration_time: u64, flags: RecordFlags) -> Result<Record, RecordDataError> {
+    let data = try!(value.encode());
+    Ok(Record::new(value.record_type(), expiration_time, flags, data))
+  }
+
   /// Deserialize a record from a byte stream.
   pub fn deserialize<T>(reader: &mut T) -> Result<Record, io::Error> where T: Read {
     let expiration_time = try!(reader.read_u64::<BigEndian>());
-    let data_size = try!(reader.read_u32::<BigEndian>()) as u64;
+    let data_size = try!(reader.read_u32::<BigEndian>());
     let record_type = try!(reader.read_u32::<BigEndian>());
     let flags = try!(reader.read_u32::<BigEndian>());
-    let flags: ll::Enum_GNUNET_GNSRECORD_Flags = unsafe { ::std::mem::transmute(flags) };
-    let buff = try!(reader.read_exact_alloc(data_size as usize));
-    let data = buff.as_ptr() as *const c_void;
+    let data = try!(reader.read_exact_alloc(data_size as usize));
 
     Ok(Record {
-      data: ll::Struct_GNUNET_GNSRECORD_Data {
-        data:             data,
-        expiration_time:  expiration_time,
-        data_size:        data_size as size_t,
-        record_type:      record_type,
-        flags:            flags,
-      },
-      buff: buff,
+      expiration_time:  expiration_time,
+      record_type:      record_type,
+      flags:            flags,
+      data:             data,
     })
   }
 
+  /// Serialize a record to a byte stream, in the same format read by `Record::deserialize`.
+  pub fn serialize<T>(&self, writer: &mut T) -> Result<(), io::Error> where T: Write {
+    try!(writer.write_u64::<BigEndian>(self.expiration_time));
+    try!(writer.write_u32::<BigEndian>(self.data.len() as u32));
+    try!(writer.write_u32::<BigEndian>(self.record_type));
+    try!(writer.write_u32::<BigEndian>(self.flags));
+    writer.write_all(&self.data[..])
+  }
+
+  /// The number of bytes `Record::serialize` writes for this record.
+  pub fn serialized_size(&self) -> usize {
+    8 + 4 + 4 + 4 + self.data.len()
+  }
+
+  /// The serialized value of the record.
+  pub fn data(&self) -> &[u8] {
+    &self.data[..]
+  }
+
+  /// Decode the value of the record.
+  ///
+  /// Values of types not known to this crate are returned as `RecordData::Other`, use
+  /// `RecordTypeRegistry::decode` to check those.
+  pub fn value(&self) -> Result<RecordData, RecordDataError> {
+    RecordData::decode(self.record_type(), &self.data[..])
+  }
+
+  /// Format the value of the record the way the `gnunet-gns` command line tool does.
+  ///
+  /// Values of types not known to this crate are checked and formatted by their handler in
+  /// `registry`, and cannot be formatted without one.
+  pub fn value_to_string(&self, registry: Option<&RecordTypeRegistry>) -> Result<String, RecordDataError> {
+    match (self.record_type(), registry) {
+      (Other(_), Some(registry)) => registry.record_value(self).and_then(|value| registry.value_to_string(&value)),
+      (Other(x), None)           => Err(RecordDataError::UnknownType { record_type: x }),
+      _                          => self.value().map(|value| value.to_string()),
+    }
+  }
+
+  /// Display the value of the record as `value_to_string` formats it, or its raw bytes in hex if
+  /// it cannot be formatted.
+  pub fn display<'a>(&'a self, registry: Option<&'a RecordTypeRegistry>) -> RecordDisplay<'a> {
+    RecordDisplay {
+      record: self,
+      registry: registry,
+    }
+  }
+
   /// Get the type of a record.
   pub fn record_type(&self) -> RecordType {
-    RecordType::from_u32(self.data.record_type).unwrap()
+    RecordType::from_u32(self.record_type)
+  }
+
+  /// Get the flags of a record.
+  pub fn flags(&self) -> RecordFlags {
+    RecordFlags::from_bits(self.flags)
+  }
+
+  /// When the record expires.
+  pub fn expiration(&self) -> Expiration {
+    if self.flags().contains(RF_RELATIVE_EXPIRATION) {
+      Expiration::Relative(time::Relative::from_micros(self.expiration_time))
+    }
+    else {
+      Expiration::Absolute(time::Absolute::from_micros(self.expiration_time))
+    }
+  }
+
+  /// When the record expires, given that it was received at `received`.
+  pub fn expires_at(&self, received: time::Absolute) -> time::Absolute {
+    match self.expiration() {
+      Expiration::Absolute(at) => at,
+      Expiration::Relative(r)  => received.add(r),
+    }
+  }
+
+  /// Whether the record has expired at `now`. Records with a relative expiration time never
+  /// have, as they are only relative to when they are received, see `expires_at`.
+  pub fn is_expired(&self, now: time::Absolute) -> bool {
+    match self.expiration() {
+      Expiration::Absolute(at) => !at.is_forever() && at < now,
+      Expiration::Relative(_)  => false,
+    }
+  }
+
+  /// Whether the record is a shadow record, see `RF_SHADOW`.
+  pub fn is_shadow(&self) -> bool {
+    self.flags().contains(RF_SHADOW)
+  }
+
+  /// The record held by this BOX record, if this is a BOX record for `service` over `protocol`.
+  ///
+  /// The unboxed record keeps the expiration time and flags of the box.
+  pub fn unbox(&self, protocol: u16, service: u16) -> Option<Record> {
+    if self.record_type() != BOX || self.data.len() < 8 {
+      return None;
+    }
+    if BigEndian::read_u16(&self.data[0..2]) != protocol || BigEndian::read_u16(&self.data[2..4]) != service {
+      return None;
+    }
+    Some(Record {
+      expiration_time:  self.expiration_time,
+      record_type:      BigEndian::read_u32(&self.data[4..8]),
+      flags:            self.flags,
+      data:             self.data[8..].to_vec(),
+    })
   }
 }
 
-impl Debug for Record {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        // TODO properly implement ll::GNUNET_GNSRECORD_value_to_string
-        assert!(self.data.data_size == 4);
+/// The records of `records` which are to be used at `now`.
+///
+/// Expired records are dropped, and shadow records are dropped unless every other record of their
+/// type has expired, the same way the GNS service does before publishing or returning a record
+/// set.
+pub fn live_records(records: &[Record], now: time::Absolute) -> Vec<Record> {
+  records.iter().filter(|r| {
+    if r.is_expired(now) {
+      return false;
+    }
+    if !r.is_shadow() {
+      return true;
+    }
+    !records.iter().any(|o| o.record_type == r.record_type && !o.is_shadow() && !o.is_expired(now))
+  }).cloned().collect()
+}
+
+/// The minimum size of a serialized record set, smaller sets are padded with zeros.
+pub const RECORDS_MIN_SERIALIZED_SIZE: usize = 32;
+
+/// The number of bytes `records_serialize` produces for `records`.
+///
+/// To hide the exact size of the records, non-empty record sets are padded to the next power of
+/// two, and to at least `RECORDS_MIN_SERIALIZED_SIZE` bytes.
+pub fn records_serialized_size(records: &[Record]) -> usize {
+  if records.is_empty() {
+    return 0;
+  }
+  let size: usize = records.iter().map(|r| r.serialized_size()).sum();
+  let size = size.next_power_of_two();
+  if size < RECORDS_MIN_SERIALIZED_SIZE { RECORDS_MIN_SERIALIZED_SIZE } else { size }
+}
+
+/// Serialize a record set the way `GNUNET_GNSRECORD_records_serialize` does, as found in namestore
+/// files and GNS blocks.
+///
+/// The records are packed in the format of `Record::serialize` and followed by zero padding, see
+/// `records_serialized_size`.
+pub fn records_serialize(records: &[Record]) -> Vec<u8> {
+  let size = records_serialized_size(records);
+  let mut ret = Vec::with_capacity(size);
+  for record in records {
+    record.serialize(&mut ret).unwrap();
+  }
+  ret.resize(size, 0);
+  ret
+}
+
+/// Deserialize a record set written by `records_serialize`.
+///
+/// Reading stops at the padding, which is recognised by being all zeros: a record cannot start
+/// with a zero header as there is no record type 0.
+pub fn records_deserialize(data: &[u8]) -> Result<Vec<Record>, io::Error> {
+  let mut records = Vec::new();
+  let mut rest = data;
+  while rest.iter().any(|b| *b != 0) {
+    records.push(try!(Record::deserialize(&mut rest)));
+  }
+  Ok(records)
+}
 
-        let slice = unsafe {
-            ::std::slice::from_raw_parts(self.data.data as *mut u8, self.data.data_size as usize)
-        };
-        let addr = ::std::net::Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]);
-        addr.fmt(f)
+/// Split a name of the form `_port._protocol.name`, as used to look up BOX records, into the port,
+/// the IP protocol number and the rest of the name.
+///
+/// The protocol may be given as `tcp`, `udp` or `sctp`, or by its number. Returns `None` for
+/// other names.
+///
+/// # Example
+///
+/// ```rust
+/// use gnunet::gns::split_service_name;
+///
+/// assert_eq!(split_service_name("_443._tcp.www.gnu"), Some((443, 6, "www.gnu")));
+/// assert_eq!(split_service_name("www.gnu"), None);
+/// ```
+pub fn split_service_name(name: &str) -> Option<(u16, u16, &str)> {
+  let mut it = name.splitn(3, '.');
+  let (service, protocol, rest) = match (it.next(), it.next(), it.next()) {
+    (Some(s), Some(p), Some(rest)) if s.starts_with('_') && p.starts_with('_') => (&s[1..], &p[1..], rest),
+    _ => return None,
+  };
+  let protocol = match protocol {
+    "tcp"  => 6,
+    "udp"  => 17,
+    "sctp" => 132,
+    p      => match u16::from_str(p) {
+      Ok(p)  => p,
+      Err(_) => return None,
+    },
+  };
+  match u16::from_str(service) {
+    Ok(service) => Some((service, protocol, rest)),
+    Err(_)      => None,
+  }
+}
+
+/// The records boxed in the BOX records of `records` for `service` over `protocol` which have
+/// type `record_type`, see `Record::unbox`.
+pub fn unbox_records(records: &[Record], protocol: u16, service: u16, record_type: RecordType) -> Vec<Record> {
+  records.iter()
+         .filter_map(|r| r.unbox(protocol, service))
+         .filter(|r| r.record_type() == record_type)
+         .collect()
+}
+
+impl Debug for Record {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    f.debug_struct("Record")
+     .field("record_type", &self.record_type)
+     .field("expiration_time", &self.expiration_time)
+     .field("flags", &self.flags())
+     .field("value", &self.value())
+     .finish()
   }
 }
 
 impl fmt::Display for Record {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        Debug::fmt(self, f)
+  /// Print the value of the record, or its raw bytes in hex if it cannot be decoded. Use
+  /// `Record::display` to print values of registered types.
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    fmt::Display::fmt(&self.display(None), f)
+  }
+}
+
+/// Displays the value of a record with the handlers of a `RecordTypeRegistry`, see
+/// `Record::display`.
+pub struct RecordDisplay<'a> {
+  record: &'a Record,
+  registry: Option<&'a RecordTypeRegistry>,
+}
+
+impl<'a> fmt::Display for RecordDisplay<'a> {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    match self.record.value_to_string(self.registry) {
+      Ok(s)  => f.write_str(&s),
+      Err(_) => {
+        for b in self.record.data.iter() {
+          try!(write!(f, "{:02X}", b));
+        }
+        Ok(())
+      },
     }
+  }
+}
+
+#[test]
+fn test_live_records() {
+  let now = time::Absolute::from_micros(1000);
+  let a = |expiration, flags, last| Record::new(A, expiration, flags, vec![10, 0, 0, last]);
+  let current = a(2000, RecordFlags::empty(), 1);
+  let shadow = a(5000, RF_SHADOW, 2);
+  let expired = a(500, RecordFlags::empty(), 3);
+  let relative = a(10, RF_RELATIVE_EXPIRATION, 4);
+
+  assert!(expired.is_expired(now));
+  assert!(!relative.is_expired(now));
+  assert_eq!(relative.expiration(), Expiration::Relative(time::Relative::from_micros(10)));
+  assert_eq!(relative.expires_at(now), time::Absolute::from_micros(1010));
+
+  // the shadow record is only used once the record it shadows has expired
+  let live = live_records(&[current.clone(), shadow.clone(), expired.clone()], now);
+  assert_eq!(live.iter().map(|r| r.data()[3]).collect::<Vec<u8>>(), vec![1]);
+  let live = live_records(&[shadow, expired], now);
+  assert_eq!(live.iter().map(|r| r.data()[3]).collect::<Vec<u8>>(), vec![2]);
 }
 
+#[test]
+fn test_records_serialize() {
+  let records = vec![
+    Record::new(A, 1000, RecordFlags::empty(), vec![10, 0, 0, 1]),
+    Record::new(TXT, 2000, RF_PRIVATE, b"hello".to_vec()),
+  ];
+  // 24 + 25 bytes of records, padded to 64
+  assert_eq!(records_serialized_size(&records), 64);
+  assert_eq!(records_serialized_size(&records[..1]), 32);
+  assert_eq!(records_serialized_size(&[]), 0);
+
+  let data = records_serialize(&records);
+  assert_eq!(data.len(), 64);
+  assert!(data[49..].iter().all(|b| *b == 0));
+  let read = records_deserialize(&data).unwrap();
+  assert_eq!(read.len(), 2);
+  assert_eq!(read[0].record_type(), A);
+  assert_eq!(read[0].data(), &[10, 0, 0, 1]);
+  assert_eq!(read[1].record_type(), TXT);
+  assert_eq!(read[1].flags(), RF_PRIVATE);
+  assert_eq!(read[1].expires_at(time::Absolute::from_micros(0)), time::Absolute::from_micros(2000));
+
+  assert_eq!(records_deserialize(&[]).unwrap().len(), 0);
+  assert!(records_deserialize(&data[..30]).is_err());
+}
+
+#[test]
+fn test_unbox() {
+  // a TLSA record for port 443 over TCP
+  let boxed = Record::new(BOX, 1000, RF_PRIVATE, vec![0, 6, 1, 187, 0, 0, 0, 52, 3, 1, 1, 0xab]);
+  let tlsa = boxed.unbox(6, 443).unwrap();
+  assert_eq!(tlsa.record_type(), TLSA);
+  assert_eq!(tlsa.data(), &[3, 1, 1, 0xab]);
+  assert_eq!(tlsa.flags(), RF_PRIVATE);
+  assert!(boxed.unbox(17, 443).is_none());
+  assert!(boxed.unbox(6, 80).is_none());
+  assert_eq!(boxed.to_string(), "6 443 52 3 1 1 ab");
+
+  let records = [boxed, Record::new(A, 1000, RecordFlags::empty(), vec![10, 0, 0, 1])];
+  assert_eq!(unbox_records(&records, 6, 443, TLSA).len(), 1);
+  assert_eq!(unbox_records(&records, 6, 443, Other(33)).len(), 0);
+
+  assert_eq!(split_service_name("_25._udp.mail.gnu"), Some((25, 17, "mail.gnu")));
+  assert_eq!(split_service_name("_443._6.gnu"), Some((443, 6, "gnu")));
+  assert_eq!(split_service_name("_https._tcp.gnu"), None);
+  assert_eq!(split_service_name("_443.www.gnu"), None);
+}
@@ -0,0 +1,669 @@
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use EcdsaPublicKey;
+use dnsparser;
+use peerinfo::PeerIdentity;
+use super::RecordType;
+use util::io::ReadUtil;
+
+/// The longest DNS name that can be stored in a record, excluding the final dot.
+pub const MAX_NAME_LENGTH: usize = dnsparser::MAX_NAME_LENGTH;
+
+/// The longest label of a DNS name.
+pub const MAX_LABEL_LENGTH: usize = dnsparser::MAX_LABEL_LENGTH;
+
+/// The decoded value of a GNS record.
+///
+/// Names are stored in records in DNS wire format and are given here as dot-separated strings
+/// without a trailing dot.
+///
+/// Values are converted to and from strings the same way GNUnet's gnsrecord plugins do, and so
+/// the `gnunet-gns` and `gnunet-namestore` command line tools. For example an MX record is
+/// written as `10,mail.example.gnu` and a VPN record as `proto peer identifier`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordData {
+  /// An IPv4 address.
+  A(Ipv4Addr),
+  /// The name of an authoritative name server.
+  NS(String),
+  /// The canonical name this name is an alias of.
+  CNAME(String),
+  /// The start of authority of a DNS zone.
+  SOA {
+    /// The primary name server of the zone.
+    mname: String,
+    /// The mailbox of the person responsible for the zone, with the `@` replaced by a dot.
+    rname: String,
+    /// The version of the zone.
+    serial: u32,
+    /// How often secondary name servers should refresh the zone, in seconds.
+    refresh: u32,
+    /// How long secondary name servers should wait before retrying a failed refresh, in seconds.
+    retry: u32,
+    /// How long secondary name servers may keep serving the zone without refreshing it, in seconds.
+    expire: u32,
+    /// How long negative answers may be cached, in seconds.
+    minimum: u32,
+  },
+  /// A pointer to a canonical name.
+  PTR(String),
+  /// A mail exchanger for the name.
+  MX {
+    /// Exchangers with lower values are preferred.
+    preference: u16,
+    /// The name of the mail exchanger.
+    host: String,
+  },
+  /// Free-form text.
+  TXT(String),
+  /// An IPv6 address.
+  AAAA(Ipv6Addr),
+  /// A TLSA certificate association.
+  TLSA {
+    /// Which certificate in the chain must match, and how it is to be trusted.
+    usage: u8,
+    /// Whether the full certificate (0) or only its public key (1) is matched.
+    selector: u8,
+    /// How `data` is compared: exactly (0), as a SHA-256 hash (1) or as a SHA-512 hash (2).
+    matching_type: u8,
+    /// The certificate association data.
+    data: Vec<u8>,
+  },
+  /// The public key of a delegated zone.
+  PKEY(EcdsaPublicKey),
+  /// The preferred name of the zone.
+  NICK(String),
+  /// The legacy hostname to use when talking to the service, eg. for TLS and HTTP `Host`.
+  LEHO(String),
+  /// A service offered over the GNUnet VPN.
+  VPN {
+    /// The peer offering the service.
+    peer: PeerIdentity,
+    /// The IP protocol of the service, eg. 6 for TCP.
+    proto: u16,
+    /// The name identifying the service at the peer.
+    identifier: String,
+  },
+  /// A delegation to a legacy DNS zone.
+  GNS2DNS {
+    /// The name of the DNS zone.
+    name: String,
+    /// The DNS server to resolve names in the zone with.
+    server: String,
+  },
+  /// A record for one port and protocol of the name.
+  BOX {
+    /// The IP protocol of the service, eg. 6 for TCP.
+    protocol: u16,
+    /// The port of the service.
+    service: u16,
+    /// The boxed value, usually SRV or TLSA.
+    value: Box<RecordData>,
+  },
+  /// The value of a record of a type not known to this crate, see `RecordTypeRegistry`.
+  Other {
+    /// The record type number.
+    record_type: u32,
+    /// The serialized value.
+    data: Vec<u8>,
+  },
+}
+
+/// Errors generated when decoding or encoding the value of a record.
+error_def! RecordDataError {
+  Truncated { record_type: RecordType }
+    => "The record value ended early" ("The {} record value was truncated.", record_type),
+  TrailingData { record_type: RecordType, len: usize }
+    => "The record value had unexpected trailing data" ("The {} record value had {} bytes left over.", record_type, len),
+  InvalidName { name: String }
+    => "A name in the record was not a valid DNS name" ("\"{}\" is not a valid DNS name.", name),
+  InvalidString
+    => "A string in the record was not valid utf-8",
+  UnknownType { record_type: u32 }
+    => "The record type is not known" ("Values of records of type {} cannot be decoded.", record_type),
+  Parse { record_type: RecordType, value: String }
+    => "Failed to parse the record value" ("\"{}\" is not a valid {} record value.", value, record_type),
+}
+
+impl RecordData {
+  /// Decode the value `data` of a record of type `record_type`.
+  pub fn decode(record_type: RecordType, data: &[u8]) -> Result<RecordData, RecordDataError> {
+    let mut r = Cursor::new(data);
+    let value = match decode_value(record_type, &mut r) {
+      Ok(value) => value,
+      Err(DecodeError::Io(_)) => return Err(RecordDataError::Truncated { record_type: record_type }),
+      Err(DecodeError::Data(e)) => return Err(e),
+    };
+    let rem = data.len() - r.position() as usize;
+    if rem != 0 {
+      return Err(RecordDataError::TrailingData { record_type: record_type, len: rem });
+    }
+    Ok(value)
+  }
+
+  /// Encode the value in the form it is stored in a record.
+  pub fn encode(&self) -> Result<Vec<u8>, RecordDataError> {
+    let mut w = Vec::new();
+    match encode_value(self, &mut w) {
+      Ok(()) => Ok(w),
+      Err(DecodeError::Data(e)) => Err(e),
+      // writing to a vector does not fail
+      Err(DecodeError::Io(e)) => panic!("failed to write a record value to memory: {}", e),
+    }
+  }
+
+  /// Parse the value of a record of type `record_type` from the string form printed by `Display`.
+  ///
+  /// # Example
+  ///
+  /// ```rust
+  /// use gnunet::gns::{RecordData, RecordType};
+  ///
+  /// let mx = RecordData::from_str(RecordType::MX, "10,mail.example.gnu").unwrap();
+  /// assert_eq!(mx, RecordData::MX { preference: 10, host: "mail.example.gnu".to_string() });
+  /// assert_eq!(mx.to_string(), "10,mail.example.gnu");
+  /// ```
+  pub fn from_str(record_type: RecordType, s: &str) -> Result<RecordData, RecordDataError> {
+    let invalid = || RecordDataError::Parse { record_type: record_type, value: s.to_string() };
+    let value = match record_type {
+      RecordType::A     => RecordData::A(try!(Ipv4Addr::from_str(s).map_err(|_| invalid()))),
+      RecordType::AAAA  => RecordData::AAAA(try!(Ipv6Addr::from_str(s).map_err(|_| invalid()))),
+      RecordType::NS    => RecordData::NS(try!(parse_name(s))),
+      RecordType::CNAME => RecordData::CNAME(try!(parse_name(s))),
+      RecordType::PTR   => RecordData::PTR(try!(parse_name(s))),
+      RecordType::SOA => {
+        // rname=%253s mname=%253s %u,%u,%u,%u,%u
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 3 || !fields[0].starts_with("rname=") || !fields[1].starts_with("mname=") {
+          return Err(invalid());
+        }
+        let numbers: Vec<u32> = match fields[2].split(',').map(u32::from_str).collect() {
+          Ok(numbers) => numbers,
+          Err(_)      => return Err(invalid()),
+        };
+        if numbers.len() != 5 {
+          return Err(invalid());
+        }
+        RecordData::SOA {
+          rname: try!(parse_name(&fields[0]["rname=".len()..])),
+          mname: try!(parse_name(&fields[1]["mname=".len()..])),
+          serial: numbers[0],
+          refresh: numbers[1],
+          retry: numbers[2],
+          expire: numbers[3],
+          minimum: numbers[4],
+        }
+      },
+      RecordType::MX => {
+        // %u,%253s
+        let mut it = s.splitn(2, ',');
+        match (it.next().map(u16::from_str), it.next()) {
+          (Some(Ok(preference)), Some(host)) => RecordData::MX {
+            preference: preference,
+            host: try!(parse_name(host)),
+          },
+          _ => return Err(invalid()),
+        }
+      },
+      RecordType::TXT   => RecordData::TXT(s.to_string()),
+      RecordType::NICK  => RecordData::NICK(s.to_string()),
+      RecordType::LEHO  => RecordData::LEHO(s.to_string()),
+      RecordType::TLSA => {
+        // %u %u %u %s, with the association data in hex
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 4 {
+          return Err(invalid());
+        }
+        match (u8::from_str(fields[0]), u8::from_str(fields[1]), u8::from_str(fields[2]), hex_to_bin(fields[3])) {
+          (Ok(usage), Ok(selector), Ok(matching_type), Some(data)) => RecordData::TLSA {
+            usage: usage,
+            selector: selector,
+            matching_type: matching_type,
+            data: data,
+          },
+          _ => return Err(invalid()),
+        }
+      },
+      RecordType::PKEY  => RecordData::PKEY(try!(EcdsaPublicKey::from_str(s).map_err(|_| invalid()))),
+      RecordType::VPN => {
+        // %u %103s %253s
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 3 {
+          return Err(invalid());
+        }
+        match (u16::from_str(fields[0]), PeerIdentity::from_str(fields[1])) {
+          (Ok(proto), Ok(peer)) => RecordData::VPN {
+            peer: peer,
+            proto: proto,
+            identifier: fields[2].to_string(),
+          },
+          _ => return Err(invalid()),
+        }
+      },
+      RecordType::BOX => {
+        // %u %u %u %s, with the boxed value in its own string form
+        let fields: Vec<&str> = s.splitn(4, ' ').collect();
+        if fields.len() != 4 {
+          return Err(invalid());
+        }
+        match (u16::from_str(fields[0]), u16::from_str(fields[1]), u32::from_str(fields[2])) {
+          (Ok(protocol), Ok(service), Ok(record_type)) => RecordData::BOX {
+            protocol: protocol,
+            service: service,
+            value: Box::new(try!(RecordData::from_str(RecordType::from_u32(record_type), fields[3]))),
+          },
+          _ => return Err(invalid()),
+        }
+      },
+      RecordType::Other(x) => return Err(RecordDataError::UnknownType { record_type: x }),
+      RecordType::GNS2DNS => {
+        let mut it = s.splitn(2, '@');
+        match (it.next(), it.next()) {
+          (Some(name), Some(server)) => RecordData::GNS2DNS {
+            name: try!(parse_name(name)),
+            server: try!(parse_name(server)),
+          },
+          _ => return Err(invalid()),
+        }
+      },
+    };
+    Ok(value)
+  }
+
+  /// The type of the record holding this value.
+  pub fn record_type(&self) -> RecordType {
+    match *self {
+      RecordData::A(_)            => RecordType::A,
+      RecordData::NS(_)           => RecordType::NS,
+      RecordData::CNAME(_)        => RecordType::CNAME,
+      RecordData::SOA { .. }      => RecordType::SOA,
+      RecordData::PTR(_)          => RecordType::PTR,
+      RecordData::MX { .. }       => RecordType::MX,
+      RecordData::TXT(_)          => RecordType::TXT,
+      RecordData::AAAA(_)         => RecordType::AAAA,
+      RecordData::TLSA { .. }     => RecordType::TLSA,
+      RecordData::PKEY(_)         => RecordType::PKEY,
+      RecordData::NICK(_)         => RecordType::NICK,
+      RecordData::LEHO(_)         => RecordType::LEHO,
+      RecordData::VPN { .. }      => RecordType::VPN,
+      RecordData::GNS2DNS { .. }  => RecordType::GNS2DNS,
+      RecordData::BOX { .. }      => RecordType::BOX,
+      RecordData::Other { record_type, .. } => RecordType::from_u32(record_type),
+    }
+  }
+}
+
+impl fmt::Display for RecordData {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      RecordData::A(ref addr)     => write!(f, "{}", addr),
+      RecordData::AAAA(ref addr)  => write!(f, "{}", addr),
+      RecordData::NS(ref name) |
+      RecordData::CNAME(ref name) |
+      RecordData::PTR(ref name)   => write!(f, "{}", name),
+      RecordData::SOA { ref mname, ref rname, serial, refresh, retry, expire, minimum }
+        => write!(f, "rname={} mname={} {},{},{},{},{}", rname, mname, serial, refresh, retry, expire, minimum),
+      RecordData::MX { preference, ref host }
+        => write!(f, "{},{}", preference, host),
+      RecordData::TXT(ref text) |
+      RecordData::NICK(ref text) |
+      RecordData::LEHO(ref text)  => write!(f, "{}", text),
+      RecordData::TLSA { usage, selector, matching_type, ref data } => {
+        try!(write!(f, "{} {} {} ", usage, selector, matching_type));
+        for b in data.iter() {
+          try!(write!(f, "{:02x}", b));
+        }
+        Ok(())
+      },
+      RecordData::PKEY(ref key)   => write!(f, "{}", key),
+      RecordData::VPN { ref peer, proto, ref identifier }
+        => write!(f, "{} {} {}", proto, peer, identifier),
+      RecordData::GNS2DNS { ref name, ref server }
+        => write!(f, "{}@{}", name, server),
+      RecordData::BOX { protocol, service, ref value }
+        => write!(f, "{} {} {} {}", protocol, service, value.record_type().to_u32(), value),
+      RecordData::Other { ref data, .. } => {
+        for b in data.iter() {
+          try!(write!(f, "{:02X}", b));
+        }
+        Ok(())
+      },
+    }
+  }
+}
+
+// io errors while decoding mean the value was too short
+enum DecodeError {
+  Io(io::Error),
+  Data(RecordDataError),
+}
+
+impl From<io::Error> for DecodeError {
+  fn from(e: io::Error) -> DecodeError {
+    DecodeError::Io(e)
+  }
+}
+
+impl From<RecordDataError> for DecodeError {
+  fn from(e: RecordDataError) -> DecodeError {
+    DecodeError::Data(e)
+  }
+}
+
+fn decode_value(record_type: RecordType, r: &mut Cursor<&[u8]>) -> Result<RecordData, DecodeError> {
+  let len = r.get_ref().len();
+  Ok(match record_type {
+    RecordType::A => {
+      let mut b = [0u8; 4];
+      try!(r.read_exact(&mut b));
+      RecordData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+    },
+    RecordType::AAAA => {
+      let mut s = [0u16; 8];
+      for x in s.iter_mut() {
+        *x = try!(r.read_u16::<BigEndian>());
+      }
+      RecordData::AAAA(Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]))
+    },
+    RecordType::NS      => RecordData::NS(try!(read_name(r))),
+    RecordType::CNAME   => RecordData::CNAME(try!(read_name(r))),
+    RecordType::PTR     => RecordData::PTR(try!(read_name(r))),
+    RecordType::SOA => {
+      let mname = try!(read_name(r));
+      let rname = try!(read_name(r));
+      RecordData::SOA {
+        mname: mname,
+        rname: rname,
+        serial: try!(r.read_u32::<BigEndian>()),
+        refresh: try!(r.read_u32::<BigEndian>()),
+        retry: try!(r.read_u32::<BigEndian>()),
+        expire: try!(r.read_u32::<BigEndian>()),
+        minimum: try!(r.read_u32::<BigEndian>()),
+      }
+    },
+    RecordType::MX => {
+      let preference = try!(r.read_u16::<BigEndian>());
+      RecordData::MX {
+        preference: preference,
+        host: try!(read_name(r)),
+      }
+    },
+    RecordType::TXT     => RecordData::TXT(try!(read_rest_string(r))),
+    RecordType::NICK    => {
+      // the service writes the nick as a C string
+      let mut nick = try!(read_rest_string(r));
+      if nick.ends_with('\0') {
+        nick.pop();
+      }
+      RecordData::NICK(nick)
+    },
+    RecordType::LEHO    => RecordData::LEHO(try!(read_rest_string(r))),
+    RecordType::TLSA => {
+      let usage = try!(r.read_u8());
+      let selector = try!(r.read_u8());
+      let matching_type = try!(r.read_u8());
+      let rest = len - r.position() as usize;
+      RecordData::TLSA {
+        usage: usage,
+        selector: selector,
+        matching_type: matching_type,
+        data: try!(r.read_exact_alloc(rest)),
+      }
+    },
+    RecordType::PKEY    => RecordData::PKEY(try!(EcdsaPublicKey::deserialize(r))),
+    RecordType::VPN => {
+      let peer = try!(PeerIdentity::deserialize(r));
+      let proto = try!(r.read_u16::<BigEndian>());
+      // the identifier is NUL-terminated
+      let rest = try!(r.read_exact_alloc(len - r.position() as usize));
+      let identifier = match rest.split_last() {
+        Some((&0, s)) => try!(utf8(s.to_vec())),
+        _             => return Err(DecodeError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "missing NUL"))),
+      };
+      RecordData::VPN {
+        peer: peer,
+        proto: proto,
+        identifier: identifier,
+      }
+    },
+    RecordType::GNS2DNS => {
+      let name = try!(read_name(r));
+      RecordData::GNS2DNS {
+        name: name,
+        server: try!(read_name(r)),
+      }
+    },
+    RecordType::BOX => {
+      let protocol = try!(r.read_u16::<BigEndian>());
+      let service = try!(r.read_u16::<BigEndian>());
+      let record_type = RecordType::from_u32(try!(r.read_u32::<BigEndian>()));
+      let data = try!(r.read_exact_alloc(len - r.position() as usize));
+      RecordData::BOX {
+        protocol: protocol,
+        service: service,
+        value: Box::new(try!(RecordData::decode(record_type, &data[..]))),
+      }
+    },
+    RecordType::Other(x) => RecordData::Other {
+      record_type: x,
+      data: try!(r.read_exact_alloc(len)),
+    },
+  })
+}
+
+fn encode_value(value: &RecordData, w: &mut Vec<u8>) -> Result<(), DecodeError> {
+  match *value {
+    RecordData::A(ref addr)     => try!(w.write_all(&addr.octets())),
+    RecordData::AAAA(ref addr)  => {
+      for s in addr.segments().iter() {
+        try!(w.write_u16::<BigEndian>(*s));
+      }
+    },
+    RecordData::NS(ref name) |
+    RecordData::CNAME(ref name) |
+    RecordData::PTR(ref name)   => try!(write_name(w, name)),
+    RecordData::SOA { ref mname, ref rname, serial, refresh, retry, expire, minimum } => {
+      try!(write_name(w, mname));
+      try!(write_name(w, rname));
+      for x in [serial, refresh, retry, expire, minimum].iter() {
+        try!(w.write_u32::<BigEndian>(*x));
+      }
+    },
+    RecordData::MX { preference, ref host } => {
+      try!(w.write_u16::<BigEndian>(preference));
+      try!(write_name(w, host));
+    },
+    RecordData::TXT(ref text) |
+    RecordData::LEHO(ref text)  => try!(w.write_all(text.as_bytes())),
+    RecordData::NICK(ref nick)  => {
+      if nick.as_bytes().contains(&0) {
+        return Err(DecodeError::Data(RecordDataError::InvalidString));
+      }
+      try!(w.write_all(nick.as_bytes()));
+      try!(w.write_u8(0));
+    },
+    RecordData::TLSA { usage, selector, matching_type, ref data } => {
+      try!(w.write_all(&[usage, selector, matching_type]));
+      try!(w.write_all(&data[..]));
+    },
+    RecordData::PKEY(ref key)   => try!(key.serialize(w)),
+    RecordData::VPN { ref peer, proto, ref identifier } => {
+      if identifier.as_bytes().contains(&0) {
+        return Err(DecodeError::Data(RecordDataError::InvalidString));
+      }
+      try!(peer.serialize(w));
+      try!(w.write_u16::<BigEndian>(proto));
+      try!(w.write_all(identifier.as_bytes()));
+      try!(w.write_u8(0));
+    },
+    RecordData::GNS2DNS { ref name, ref server } => {
+      try!(write_name(w, name));
+      try!(write_name(w, server));
+    },
+    RecordData::BOX { protocol, service, ref value } => {
+      try!(w.write_u16::<BigEndian>(protocol));
+      try!(w.write_u16::<BigEndian>(service));
+      try!(w.write_u32::<BigEndian>(value.record_type().to_u32()));
+      try!(encode_value(value, w));
+    },
+    RecordData::Other { ref data, .. } => try!(w.write_all(&data[..])),
+  }
+  Ok(())
+}
+
+// check that `s` can be stored as a name and strip any trailing dot
+fn parse_name(s: &str) -> Result<String, RecordDataError> {
+  match write_name(&mut io::sink(), s) {
+    Ok(())                     => Ok(s.trim_right_matches('.').to_string()),
+    Err(DecodeError::Data(e))  => Err(e),
+    Err(DecodeError::Io(_))    => Err(RecordDataError::InvalidName { name: s.to_string() }),
+  }
+}
+
+// the inverse of the lowercase hex printed for TLSA records
+fn hex_to_bin(s: &str) -> Option<Vec<u8>> {
+  if s.len() % 2 != 0 {
+    return None;
+  }
+  let mut data = Vec::with_capacity(s.len() / 2);
+  for pair in s.as_bytes().chunks(2) {
+    let b = ::std::str::from_utf8(pair).ok().and_then(|b| u8::from_str_radix(b, 16).ok());
+    match b {
+      Some(b) => data.push(b),
+      None    => return None,
+    }
+  }
+  Some(data)
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String, RecordDataError> {
+  String::from_utf8(bytes).map_err(|_| RecordDataError::InvalidString)
+}
+
+fn read_rest_string(r: &mut Cursor<&[u8]>) -> Result<String, DecodeError> {
+  let rest = r.get_ref().len() - r.position() as usize;
+  let bytes = try!(r.read_exact_alloc(rest));
+  Ok(try!(utf8(bytes)))
+}
+
+// read an uncompressed name in DNS wire format
+fn read_name(r: &mut Cursor<&[u8]>) -> Result<String, DecodeError> {
+  let mut name = String::new();
+  loop {
+    let len = try!(r.read_u8()) as usize;
+    if len == 0 {
+      return Ok(name);
+    }
+    if len > MAX_LABEL_LENGTH {
+      // compression pointers cannot appear in records
+      return Err(DecodeError::Data(RecordDataError::InvalidName { name: name }));
+    }
+    let label = try!(utf8(try!(r.read_exact_alloc(len))));
+    if !name.is_empty() {
+      name.push('.');
+    }
+    name.push_str(&label);
+    if name.len() > MAX_NAME_LENGTH {
+      return Err(DecodeError::Data(RecordDataError::InvalidName { name: name }));
+    }
+  }
+}
+
+// write a name in DNS wire format, a trailing dot is optional
+fn write_name<W: Write>(w: &mut W, name: &str) -> Result<(), DecodeError> {
+  let invalid = || DecodeError::Data(RecordDataError::InvalidName { name: name.to_string() });
+  let trimmed = if name.ends_with('.') { &name[..name.len() - 1] } else { name };
+  if trimmed.len() > MAX_NAME_LENGTH {
+    return Err(invalid());
+  }
+  if !trimmed.is_empty() {
+    for label in trimmed.split('.') {
+      if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+        return Err(invalid());
+      }
+      try!(w.write_u8(label.len() as u8));
+      try!(w.write_all(label.as_bytes()));
+    }
+  }
+  try!(w.write_u8(0));
+  Ok(())
+}
+
+#[test]
+fn test_record_data_round_trip() {
+  use std::str::FromStr;
+
+  let values = vec![
+    RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+    RecordData::AAAA(Ipv6Addr::from_str("2001:db8::1").unwrap()),
+    RecordData::CNAME("www.gnu.org".to_string()),
+    RecordData::MX { preference: 10, host: "mail.example.gnu".to_string() },
+    RecordData::SOA {
+      mname: "ns.example.com".to_string(),
+      rname: "hostmaster.example.com".to_string(),
+      serial: 2016, refresh: 3600, retry: 600, expire: 86400, minimum: 300,
+    },
+    RecordData::TXT("hello world".to_string()),
+    RecordData::NICK("alice".to_string()),
+    RecordData::TLSA { usage: 3, selector: 1, matching_type: 1, data: vec![0xab; 32] },
+    RecordData::PKEY(EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap()),
+    RecordData::VPN {
+      peer: PeerIdentity::from_str("DPQIBOOJV8QBS3FGJ6B0K5NTSQ9SULV45H5KCR4HU7PQ64N8Q9F0").unwrap(),
+      proto: 6,
+      identifier: "www".to_string(),
+    },
+    RecordData::GNS2DNS { name: "example.com".to_string(), server: "8.8.8.8".to_string() },
+    RecordData::BOX {
+      protocol: 6,
+      service: 443,
+      value: Box::new(RecordData::TLSA { usage: 3, selector: 1, matching_type: 1, data: vec![0xab; 32] }),
+    },
+  ];
+  for value in values.into_iter() {
+    let data = value.encode().unwrap();
+    assert_eq!(RecordData::decode(value.record_type(), &data[..]).unwrap(), value);
+  }
+
+  let mx = RecordData::MX { preference: 10, host: "mx.gnu".to_string() }.encode().unwrap();
+  assert_eq!(mx, vec![0, 10, 2, b'm', b'x', 3, b'g', b'n', b'u', 0]);
+  match RecordData::decode(RecordType::AAAA, &[0; 4]) {
+    Err(RecordDataError::Truncated { .. }) => (),
+    _ => panic!("truncated AAAA record was accepted"),
+  }
+  assert!(RecordData::CNAME("a..b".to_string()).encode().is_err());
+  assert_eq!(RecordData::NICK("bob".to_string()).encode().unwrap(), b"bob\0".to_vec());
+  assert_eq!(RecordData::decode(RecordType::NICK, b"bob\0").unwrap(), RecordData::NICK("bob".to_string()));
+}
+
+#[test]
+fn test_record_data_strings() {
+  let strings = vec![
+    (RecordType::A, "10.0.0.1"),
+    (RecordType::AAAA, "2001:db8::1"),
+    (RecordType::NS, "ns1.example.com"),
+    (RecordType::SOA, "rname=hostmaster.example.com mname=ns.example.com 2016,3600,600,86400,300"),
+    (RecordType::MX, "10,mail.example.gnu"),
+    (RecordType::TXT, "v=spf1 -all"),
+    (RecordType::TLSA, "3 1 1 abcdef0123"),
+    (RecordType::PKEY, "JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG"),
+    (RecordType::LEHO, "www.example.com"),
+    (RecordType::VPN, "6 DPQIBOOJV8QBS3FGJ6B0K5NTSQ9SULV45H5KCR4HU7PQ64N8Q9F0 www"),
+    (RecordType::GNS2DNS, "example.com@8.8.8.8"),
+    (RecordType::BOX, "6 443 52 3 1 1 abcdef0123"),
+  ];
+  for (record_type, s) in strings.into_iter() {
+    let value = RecordData::from_str(record_type, s).unwrap();
+    assert_eq!(value.record_type(), record_type);
+    assert_eq!(value.to_string(), s);
+  }
+
+  assert_eq!(RecordData::from_str(RecordType::CNAME, "www.gnu.org.").unwrap(),
+             RecordData::CNAME("www.gnu.org".to_string()));
+  assert!(RecordData::from_str(RecordType::MX, "mail.example.gnu").is_err());
+  assert!(RecordData::from_str(RecordType::TLSA, "3 1 1 abc").is_err());
+  assert!(RecordData::from_str(RecordType::A, "10.0.0").is_err());
+}
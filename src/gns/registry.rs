@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use super::{Record, RecordData, RecordDataError, RecordType};
+
+/// Handles the values of an application specific record type registered with a
+/// `RecordTypeRegistry`, like a GNUnet gnsrecord plugin does for the types it defines.
+pub trait RecordTypeHandler {
+  /// Check that `data` is a well-formed value of this type.
+  fn decode(&self, data: &[u8]) -> Result<(), RecordDataError>;
+
+  /// Parse the string form of a value into its serialized form.
+  fn encode(&self, s: &str) -> Result<Vec<u8>, RecordDataError>;
+
+  /// Format a value for display.
+  fn to_string(&self, data: &[u8]) -> Result<String, RecordDataError>;
+}
+
+/// Errors returned by `RecordTypeRegistry::register`.
+error_def! RegisterError {
+  KnownType { record_type: u32 }
+    => "The record type is already known" ("Record type {} is already known.", record_type),
+  NameTaken { name: String }
+    => "The record type name is already in use" ("The name \"{}\" is already used by another record type.", name),
+}
+
+struct Registered {
+  name: String,
+  handler: Box<RecordTypeHandler>,
+}
+
+/// Record types known to an application in addition to the ones built into `RecordType`.
+///
+/// The registry converts between type names and numbers and between values and strings for both
+/// the built-in types and the registered ones. `Record::value_to_string` and `Record::display`
+/// use it to format the records of registered types read back from GNS.
+///
+/// # Example
+///
+/// ```rust
+/// use gnunet::gns::{Record, RecordDataError, RecordFlags, RecordType, RecordTypeHandler, RecordTypeRegistry};
+///
+/// // a record holding a UTF-8 string in upper case
+/// struct Shout;
+///
+/// impl RecordTypeHandler for Shout {
+///   fn decode(&self, data: &[u8]) -> Result<(), RecordDataError> {
+///     self.to_string(data).map(|_| ())
+///   }
+///
+///   fn encode(&self, s: &str) -> Result<Vec<u8>, RecordDataError> {
+///     Ok(s.to_uppercase().into_bytes())
+///   }
+///
+///   fn to_string(&self, data: &[u8]) -> Result<String, RecordDataError> {
+///     String::from_utf8(data.to_vec()).map_err(|_| RecordDataError::InvalidString)
+///   }
+/// }
+///
+/// let mut registry = RecordTypeRegistry::new();
+/// registry.register(4242424, "SHOUT", Box::new(Shout)).unwrap();
+/// let record_type = registry.type_from_name("SHOUT").unwrap();
+/// assert_eq!(record_type, RecordType::Other(4242424));
+/// let value = registry.value_from_string(record_type, "hello").unwrap();
+/// assert_eq!(registry.value_to_string(&value).unwrap(), "HELLO");
+///
+/// // records read back from GNS are displayed through the registry
+/// let record = Record::from_value(&value, 0, RecordFlags::empty()).unwrap();
+/// assert_eq!(record.display(Some(&registry)).to_string(), "HELLO");
+/// ```
+pub struct RecordTypeRegistry {
+  types: HashMap<u32, Registered>,
+}
+
+impl RecordTypeRegistry {
+  /// A registry knowing only the built-in types.
+  pub fn new() -> RecordTypeRegistry {
+    RecordTypeRegistry {
+      types: HashMap::new(),
+    }
+  }
+
+  /// Handle values of type `record_type`, called `name`, with `handler`.
+  ///
+  /// Types built into `RecordType` cannot be replaced. Registering a type again replaces the
+  /// previous registration.
+  pub fn register(&mut self, record_type: u32, name: &str, handler: Box<RecordTypeHandler>)
+                  -> Result<(), RegisterError>
+  {
+    if let RecordType::Other(_) = RecordType::from_u32(record_type) {
+      match self.type_from_name(name) {
+        Some(RecordType::Other(x)) if x == record_type => (),
+        Some(_) => return Err(RegisterError::NameTaken { name: name.to_string() }),
+        None    => (),
+      }
+      self.types.insert(record_type, Registered {
+        name: name.to_string(),
+        handler: handler,
+      });
+      Ok(())
+    }
+    else {
+      Err(RegisterError::KnownType { record_type: record_type })
+    }
+  }
+
+  /// The type called `name`.
+  pub fn type_from_name(&self, name: &str) -> Option<RecordType> {
+    if let Ok(record_type) = RecordType::from_str(name) {
+      return Some(record_type);
+    }
+    self.types.iter()
+              .find(|&(_, registered)| registered.name == name)
+              .map(|(&x, _)| RecordType::Other(x))
+  }
+
+  /// The name of `record_type`, if it is built in or registered.
+  pub fn type_name(&self, record_type: RecordType) -> Option<String> {
+    match record_type {
+      RecordType::Other(x) => self.types.get(&x).map(|registered| registered.name.clone()),
+      t                    => Some(t.to_string()),
+    }
+  }
+
+  /// Decode the value `data` of a record of type `record_type`. Values of registered types are
+  /// checked by their handler and returned as `RecordData::Other`.
+  pub fn decode(&self, record_type: RecordType, data: &[u8]) -> Result<RecordData, RecordDataError> {
+    if let RecordType::Other(x) = record_type {
+      if let Some(registered) = self.types.get(&x) {
+        try!(registered.handler.decode(data));
+      }
+    }
+    RecordData::decode(record_type, data)
+  }
+
+  /// Decode the value of `record`.
+  pub fn record_value(&self, record: &Record) -> Result<RecordData, RecordDataError> {
+    self.decode(record.record_type(), record.data())
+  }
+
+  /// Parse the string form of a value of type `record_type`.
+  pub fn value_from_string(&self, record_type: RecordType, s: &str) -> Result<RecordData, RecordDataError> {
+    match record_type {
+      RecordType::Other(x) => match self.types.get(&x) {
+        Some(registered) => Ok(RecordData::Other {
+          record_type: x,
+          data: try!(registered.handler.encode(s)),
+        }),
+        None => Err(RecordDataError::UnknownType { record_type: x }),
+      },
+      t => RecordData::from_str(t, s),
+    }
+  }
+
+  /// Format `value` for display.
+  pub fn value_to_string(&self, value: &RecordData) -> Result<String, RecordDataError> {
+    match *value {
+      RecordData::Other { record_type, ref data } => match self.types.get(&record_type) {
+        Some(registered) => registered.handler.to_string(&data[..]),
+        None             => Err(RecordDataError::UnknownType { record_type: record_type }),
+      },
+      ref value => Ok(value.to_string()),
+    }
+  }
+}
+
+#[test]
+fn test_registered_record_display() {
+  use super::RecordFlags;
+
+  // a record holding a UTF-8 string in upper case
+  struct Shout;
+
+  impl RecordTypeHandler for Shout {
+    fn decode(&self, data: &[u8]) -> Result<(), RecordDataError> {
+      self.to_string(data).map(|_| ())
+    }
+
+    fn encode(&self, s: &str) -> Result<Vec<u8>, RecordDataError> {
+      Ok(s.to_uppercase().into_bytes())
+    }
+
+    fn to_string(&self, data: &[u8]) -> Result<String, RecordDataError> {
+      String::from_utf8(data.to_vec()).map_err(|_| RecordDataError::InvalidString)
+    }
+  }
+
+  let mut registry = RecordTypeRegistry::new();
+  registry.register(4242424, "SHOUT", Box::new(Shout)).unwrap();
+  let record_type = registry.type_from_name("SHOUT").unwrap();
+  let value = registry.value_from_string(record_type, "hello").unwrap();
+  let record = Record::from_value(&value, 0, RecordFlags::empty()).unwrap();
+
+  // the value read back from the record is formatted by the handler and parses to the same value
+  assert_eq!(record.value_to_string(Some(&registry)).unwrap(), "HELLO");
+  let displayed = record.display(Some(&registry)).to_string();
+  assert_eq!(displayed, "HELLO");
+  assert_eq!(registry.value_from_string(record_type, &displayed).unwrap(), value);
+
+  // without the registry the value is unknown
+  assert!(record.value_to_string(None).is_err());
+  assert_eq!(record.to_string(), "48454C4C4F");
+
+  // values the handler rejects are shown in hex
+  let invalid = Record::new(record_type, 0, RecordFlags::empty(), vec![0xff]);
+  assert!(invalid.value_to_string(Some(&registry)).is_err());
+  assert_eq!(invalid.display(Some(&registry)).to_string(), "FF");
+}
@@ -0,0 +1,121 @@
+//! Choosing the zone a GNS name is looked up in.
+//!
+//! GNUnet resolves a name relative to a zone chosen from its top-level domain:
+//!
+//!  * `label.<key>.zkey` names carry the public key of their zone.
+//!  * A TLD `tld` may be mapped to a zone in the `[gns]` section of the configuration with an
+//!    option `.tld = <key>`.
+//!  * Any other name is looked up in the master zone, the default ego of `gns-master`.
+//!
+//! The TLD is removed from names resolved in an explicit zone, leaving `@` for the zone apex.
+
+use std::str::FromStr;
+
+use EcdsaPublicKey;
+use Cfg;
+use super::LocalOptions;
+
+/// Errors returned when choosing the zone of a name.
+error_def! ZoneError {
+    InvalidZkey { name: String }
+        => "The name contains a malformed zone key" ("The zone key in \"{}\" is malformed.", name),
+    InvalidTldKey { tld: String, value: String }
+        => "The zone key configured for a TLD is malformed"
+           ("The zone key \"{}\" configured for .{} is malformed.", value, tld),
+}
+
+/// The zone a name is looked up in, the name relative to that zone and the options to use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    /// The zone to look the name up in.
+    pub zone: EcdsaPublicKey,
+    /// The name relative to `zone`, `"@"` for the apex of the zone.
+    pub name: String,
+    /// Whether the DHT may be asked.
+    pub options: LocalOptions,
+}
+
+impl Resolution {
+    /// Look `name` up in the master zone `master`.
+    ///
+    /// Records of labels directly under `.gnu` are stored locally so these are looked up without
+    /// going to the DHT.
+    pub fn in_master(name: &str, master: EcdsaPublicKey) -> Resolution {
+        let name = name.trim_right_matches('.');
+        let options = {
+            let mut it = name.split('.');
+            match (it.next(), it.next(), it.next()) {
+                (Some(_), Some("gnu"), None)  => LocalOptions::NoDHT,
+                _                             => LocalOptions::LocalMaster,
+            }
+        };
+        Resolution {
+            zone: master,
+            name: name.to_string(),
+            options: options,
+        }
+    }
+
+    // names in explicitly chosen zones are not under our control, so the DHT is always used
+    fn in_zone(name: &str, zone: EcdsaPublicKey) -> Resolution {
+        Resolution {
+            zone: zone,
+            name: if name.is_empty() { "@".to_string() } else { name.to_string() },
+            options: LocalOptions::Default,
+        }
+    }
+}
+
+/// Choose the zone of `name` from its TLD and the `[gns]` section of `cfg`.
+///
+/// Returns `None` if `name` should be looked up in the master zone, see `Resolution::in_master`.
+pub fn zone_for_name(cfg: &Cfg, name: &str) -> Result<Option<Resolution>, ZoneError> {
+    let name = name.trim_right_matches('.');
+    let (rest, tld) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None    => ("", name),
+    };
+
+    if tld.to_lowercase() == "zkey" {
+        let (label, key) = match rest.rfind('.') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None    => ("", rest),
+        };
+        let zone = match EcdsaPublicKey::from_str(key) {
+            Ok(zone) => zone,
+            Err(_)   => return Err(ZoneError::InvalidZkey { name: name.to_string() }),
+        };
+        return Ok(Some(Resolution::in_zone(label, zone)));
+    }
+
+    match cfg.get_string("gns", &format!(".{}", tld)) {
+        Ok(value) => match EcdsaPublicKey::from_str(value.trim()) {
+            Ok(zone) => Ok(Some(Resolution::in_zone(rest, zone))),
+            Err(_)   => Err(ZoneError::InvalidTldKey { tld: tld.to_string(), value: value }),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+#[test]
+fn test_zone_for_name() {
+    let key = "JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG";
+    let zone = EcdsaPublicKey::from_str(key).unwrap();
+    let mut cfg = Cfg::empty();
+    cfg.set_string("gns", ".pin", key.to_string());
+    cfg.set_string("gns", ".bad", "XYZ".to_string());
+
+    let r = zone_for_name(&cfg, &format!("www.{}.zkey", key)).unwrap().unwrap();
+    assert_eq!(r, Resolution { zone: zone, name: "www".to_string(), options: LocalOptions::Default });
+    let r = zone_for_name(&cfg, &format!("{}.zkey.", key)).unwrap().unwrap();
+    assert_eq!(r.name, "@");
+    assert!(zone_for_name(&cfg, "www.XYZ.zkey").is_err());
+
+    let r = zone_for_name(&cfg, "a.b.pin").unwrap().unwrap();
+    assert_eq!(r, Resolution { zone: zone, name: "a.b".to_string(), options: LocalOptions::Default });
+    assert!(zone_for_name(&cfg, "www.bad").is_err());
+
+    assert_eq!(zone_for_name(&cfg, "www.gnu").unwrap(), None);
+    assert_eq!(Resolution::in_master("www.gnu", zone).options, LocalOptions::NoDHT);
+    assert_eq!(Resolution::in_master("www.alice.gnu", zone).options, LocalOptions::LocalMaster);
+}
@@ -0,0 +1,459 @@
+//! A SOCKS5 proxy which resolves GNS names.
+//!
+//! Applications which can use a SOCKS proxy, such as browsers or `curl --socks5-hostname`, reach
+//! services named in GNS through it without any changes to the system's DNS configuration. Only
+//! the CONNECT command without authentication is supported (RFC 1928).
+//!
+//! Names under a GNS TLD are chosen as by `dns2gns`: `.zkey` names, TLDs mapped to a zone in the
+//! `[gns]` section of the configuration and the TLDs added with `SocksProxy::add_tld` (`.gnu` by
+//! default). Their A, AAAA and LEHO records are looked up with `GNS::lookup` and the connection is
+//! made to the first address found, or, if the name has no addresses, to the legacy hostname of
+//! its LEHO record. All other names are resolved by the system resolver, on a thread of their own
+//! since it blocks. Once connected, data is relayed both ways until both sides have closed their
+//! connection; a side closing its connection shuts down the write side of the other one.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use gnunet::Cfg;
+//! use gnunet::gns::socks::SocksProxy;
+//! use gnunet::util::async;
+//!
+//! let config = Cfg::default().unwrap();
+//! let mut event_port = async::EventPort::new().unwrap();
+//! let network = event_port.get_network();
+//!
+//! async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
+//!     let proxy = SocksProxy::connect(&config, &network).wait(wait_scope, &mut event_port).unwrap();
+//!     let listener = try!(network.get_tcp_address("127.0.0.1:1080".parse().unwrap()).listen());
+//!     proxy.serve(listener).wait(wait_scope, &mut event_port)
+//! }).expect("top_level");
+//! ```
+
+use std::cell::RefCell;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use byteorder::{BigEndian, ByteOrder};
+use gj::{Promise, TaskSet, TaskReaper};
+use gjio::{AsyncRead, AsyncWrite, Network, SocketListener, SocketStream};
+
+use identity;
+use service;
+use service::dispatch::Deadline;
+use util::async;
+use Cfg;
+use EcdsaPublicKey;
+use super::{GNS, RecordData, RecordType, Resolution, ZoneError, zone_for_name};
+
+const VERSION: u8 = 5;
+
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// SOCKS reply: the connection was made.
+pub const REPLY_SUCCEEDED: u8 = 0;
+/// SOCKS reply: the lookup of a GNS name failed.
+pub const REPLY_GENERAL_FAILURE: u8 = 1;
+/// SOCKS reply: the name has no address or the destination could not be reached.
+pub const REPLY_HOST_UNREACHABLE: u8 = 4;
+/// SOCKS reply: the destination refused the connection.
+pub const REPLY_CONNECTION_REFUSED: u8 = 5;
+/// SOCKS reply: the command is not CONNECT.
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+/// SOCKS reply: the address type of the request is unknown.
+pub const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Errors returned by `SocksProxy::connect`.
+error_def! SocksProxyError {
+    IdentityGetDefaultEgo { #[from] cause: identity::ConnectGetDefaultEgoError }
+        => "Failed to retrieve the default identity for gns-master from the identity service" ("Reason: {}", cause),
+    GnsConnect { #[from] cause: service::ConnectError }
+        => "Failed to connect to the GNS service" ("Reason: {}", cause),
+}
+
+// the destination of a CONNECT request
+enum Host {
+    Ip(IpAddr),
+    Name(String),
+}
+
+/// A SOCKS5 proxy resolving GNS names.
+pub struct SocksProxy {
+    gns: RefCell<GNS>,
+    cfg: Cfg,
+    master: EcdsaPublicKey,
+    network: Network,
+    tlds: Vec<String>,
+    deadline: Option<Deadline>,
+}
+
+struct Reaper;
+
+impl TaskReaper<(), io::Error> for Reaper {
+    fn task_failed(&mut self, _: io::Error) {}
+}
+
+impl SocksProxy {
+    /// Resolve GNS names with `gns`, looking names under the TLDs added with `add_tld` up in the
+    /// zone `master`. Connections are made over `network`.
+    pub fn new(gns: GNS, cfg: &Cfg, master: EcdsaPublicKey, network: &Network) -> SocksProxy {
+        SocksProxy {
+            gns: RefCell::new(gns),
+            cfg: cfg.clone(),
+            master: master,
+            network: network.clone(),
+            tlds: vec!["gnu".to_string()],
+            deadline: None,
+        }
+    }
+
+    /// Connect to the GNS service configured in `cfg` and fetch the master zone, the default ego
+    /// of `gns-master`.
+    pub fn connect(cfg: &Cfg, network: &Network) -> Promise<SocksProxy, SocksProxyError> {
+        let cfg = cfg.clone();
+        let network = network.clone();
+        identity::get_default_ego(&cfg, Rc::new("gns-master".to_string()), &network, None)
+            .lift()
+            .then(move |ego| {
+                let connect = GNS::connect(&cfg, &network);
+                connect.lift().map(move |gns| Ok(SocksProxy::new(gns, &cfg, ego.get_public_key(), &network)))
+            })
+    }
+
+    /// Resolve the names under `tld` in the master zone.
+    pub fn add_tld(&mut self, tld: &str) {
+        let tld = tld.trim_matches('.').to_lowercase();
+        if !self.tlds.contains(&tld) {
+            self.tlds.push(tld);
+        }
+    }
+
+    /// The deadline of the GNS lookups made for each request. By default lookups wait for the
+    /// service as long as it takes.
+    pub fn set_lookup_deadline(&mut self, deadline: Option<Deadline>) {
+        self.deadline = deadline;
+    }
+
+    /// Serve the clients connecting to `listener`. The promise only resolves if accepting a
+    /// connection fails.
+    pub fn serve(self, listener: SocketListener) -> Promise<(), io::Error> {
+        accept_loop(Rc::new(self), listener, TaskSet::new(Box::new(Reaper)))
+    }
+
+    // the zone to look `name` up in, or `None` for names which are not in GNS
+    fn resolution(&self, name: &str) -> Result<Option<Resolution>, ZoneError> {
+        if let Some(resolution) = try!(zone_for_name(&self.cfg, name)) {
+            return Ok(Some(resolution));
+        }
+        let name = name.trim_right_matches('.');
+        let tld = name.rsplit('.').next().unwrap_or("").to_lowercase();
+        if self.tlds.contains(&tld) {
+            Ok(Some(Resolution::in_master(name, self.master)))
+        }
+        else {
+            Ok(None)
+        }
+    }
+
+    // the address to connect to for `host`, or the SOCKS reply code to fail the request with
+    fn resolve(&self, host: Host, port: u16) -> Promise<SocketAddr, u8> {
+        let name = match host {
+            Host::Ip(ip)     => return Promise::ok(SocketAddr::new(ip, port)),
+            Host::Name(name) => name,
+        };
+        let resolution = match self.resolution(&name) {
+            Ok(Some(resolution)) => resolution,
+            Ok(None)             => return resolve_system(&self.network, &name, port),
+            Err(_)               => return Promise::err(REPLY_HOST_UNREACHABLE),
+        };
+        let Resolution { zone, name, options } = resolution;
+        let name = Rc::new(name);
+        let mut gns = self.gns.borrow_mut();
+        let lookups: Vec<_> = [RecordType::A, RecordType::AAAA, RecordType::LEHO].iter().map(|record_type| {
+            gns.lookup(name.clone(), zone, *record_type, options, None, self.deadline.clone())
+        }).collect();
+        let network = self.network.clone();
+        Promise::all(lookups.into_iter()).then_else(move |res| {
+            let records = match res {
+                Ok(results) => results.concat(),
+                Err(_)      => return Promise::err(REPLY_GENERAL_FAILURE),
+            };
+            let values: Vec<RecordData> = records.iter().filter_map(|r| r.value().ok()).collect();
+            for value in values.iter() {
+                match *value {
+                    RecordData::A(ip)    => return Promise::ok(SocketAddr::new(IpAddr::V4(ip), port)),
+                    RecordData::AAAA(ip) => return Promise::ok(SocketAddr::new(IpAddr::V6(ip), port)),
+                    _                    => (),
+                }
+            }
+            for value in values {
+                if let RecordData::LEHO(legacy) = value {
+                    return resolve_system(&network, &legacy, port);
+                }
+            }
+            Promise::err(REPLY_HOST_UNREACHABLE)
+        })
+    }
+
+    // connect to the destination of a request
+    fn open(&self, host: Host, port: u16) -> Promise<SocketStream, u8> {
+        let network = self.network.clone();
+        self.resolve(host, port).then(move |addr| {
+            network.get_tcp_address(addr).connect().map_else(|res| match res {
+                Ok(stream) => Ok(stream),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Err(REPLY_CONNECTION_REFUSED),
+                Err(_)     => Err(REPLY_HOST_UNREACHABLE),
+            })
+        })
+    }
+}
+
+// the first address of `name` according to the system resolver, which is asked on a new thread so
+// that it does not block the event loop
+fn resolve_system(network: &Network, name: &str, port: u16) -> Promise<SocketAddr, u8> {
+    // the thread drops its end of the pair once the result has been sent, which wakes the event
+    // loop up
+    let (notify, mut wake) = match async::wake_pair(network) {
+        Ok(pair) => pair,
+        Err(_)   => return Promise::err(REPLY_GENERAL_FAILURE),
+    };
+    let (tx, rx) = mpsc::channel();
+    let name = name.to_string();
+    let spawned = thread::Builder::new().name("gnunet-resolver".to_string()).spawn(move || {
+        let _ = tx.send(resolve_blocking(&name, port));
+        drop(notify);
+    });
+    if spawned.is_err() {
+        return Promise::err(REPLY_GENERAL_FAILURE);
+    }
+    let done = wake.read(vec![0; 1], 1);
+    done.map_else(move |_| {
+        drop(wake);
+        rx.try_recv().unwrap_or(Err(REPLY_HOST_UNREACHABLE))
+    })
+}
+
+fn resolve_blocking(name: &str, port: u16) -> Result<SocketAddr, u8> {
+    match (name, port).to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or(REPLY_HOST_UNREACHABLE),
+        Err(_)        => Err(REPLY_HOST_UNREACHABLE),
+    }
+}
+
+fn accept_loop(proxy: Rc<SocksProxy>,
+               mut listener: SocketListener,
+               mut connections: TaskSet<(), io::Error>) -> Promise<(), io::Error> {
+    let accept = listener.accept();
+    accept.then(move |client| {
+        connections.add(handle(proxy.clone(), client));
+        accept_loop(proxy, listener, connections)
+    })
+}
+
+// serve one client, from the method negotiation to the end of the relayed connection
+fn handle(proxy: Rc<SocksProxy>, client: SocketStream) -> Promise<(), io::Error> {
+    let client2 = client.clone();
+    negotiate(client).then(move |accepted| {
+        if !accepted {
+            return Promise::ok(());
+        }
+        let client3 = client2.clone();
+        read_request(client2).then(move |(cmd, dest)| {
+            let (host, port) = match (cmd, dest) {
+                (CMD_CONNECT, Some(dest)) => dest,
+                (CMD_CONNECT, None)       => return reply(client3, REPLY_ADDRESS_TYPE_NOT_SUPPORTED),
+                _                         => return reply(client3, REPLY_COMMAND_NOT_SUPPORTED),
+            };
+            proxy.open(host, port).then_else(move |res| match res {
+                Ok(server) => {
+                    let client4 = client3.clone();
+                    reply(client3, REPLY_SUCCEEDED).then(move |()| relay(client4, server))
+                },
+                Err(code) => reply(client3, code),
+            })
+        })
+    })
+}
+
+// read `len` bytes, failing if the connection is closed before
+fn read_exact(stream: &mut SocketStream, len: usize) -> Promise<Vec<u8>, io::Error> {
+    stream.read(vec![0; len], len).map(move |(buf, n)| {
+        if n < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the SOCKS client disconnected"));
+        }
+        Ok(buf)
+    })
+}
+
+// the method negotiation, resolves to whether the client accepts using no authentication
+fn negotiate(mut client: SocketStream) -> Promise<bool, io::Error> {
+    let mut client2 = client.clone();
+    read_exact(&mut client, 2).then(move |header| {
+        if header[0] != VERSION {
+            return Promise::err(io::Error::new(io::ErrorKind::InvalidData, "not a SOCKS5 client"));
+        }
+        let mut client3 = client2.clone();
+        read_exact(&mut client2, header[1] as usize).then(move |methods| {
+            let accepted = methods.contains(&METHOD_NO_AUTH);
+            let method = if accepted { METHOD_NO_AUTH } else { METHOD_NONE_ACCEPTABLE };
+            client3.write(vec![VERSION, method]).map(move |_| Ok(accepted))
+        })
+    })
+}
+
+// read a request, resolves to its command and its destination, or `None` if the address type is
+// unknown in which case the rest of the request is left unread
+fn read_request(mut client: SocketStream) -> Promise<(u8, Option<(Host, u16)>), io::Error> {
+    let mut client2 = client.clone();
+    read_exact(&mut client, 4).then(move |header| {
+        if header[0] != VERSION {
+            return Promise::err(io::Error::new(io::ErrorKind::InvalidData, "not a SOCKS5 request"));
+        }
+        let cmd = header[1];
+        let mut client3 = client2.clone();
+        let host = match header[3] {
+            ATYP_IPV4 => read_exact(&mut client2, 4).map(|b| {
+                Ok(Some(Host::Ip(IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])))))
+            }),
+            ATYP_IPV6 => read_exact(&mut client2, 16).map(|b| {
+                let mut segments = [0u16; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = BigEndian::read_u16(&b[2 * i..]);
+                }
+                let s = segments;
+                Ok(Some(Host::Ip(IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7])))))
+            }),
+            ATYP_DOMAIN => {
+                let mut client4 = client2.clone();
+                read_exact(&mut client2, 1).then(move |len| {
+                    read_exact(&mut client4, len[0] as usize).map(|name| match String::from_utf8(name) {
+                        Ok(name) => Ok(Some(Host::Name(name))),
+                        Err(_)   => Err(io::Error::new(io::ErrorKind::InvalidData, "the domain name is not UTF-8")),
+                    })
+                })
+            },
+            _ => Promise::ok(None),
+        };
+        host.then(move |host| match host {
+            Some(host) => read_exact(&mut client3, 2).map(move |port| Ok((cmd, Some((host, BigEndian::read_u16(&port)))))),
+            None       => Promise::ok((cmd, None)),
+        })
+    })
+}
+
+// send a reply with code `code`, the bound address is not reported
+fn reply(mut client: SocketStream, code: u8) -> Promise<(), io::Error> {
+    client.write(vec![VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).map(|_| Ok(()))
+}
+
+// relay data both ways until both sides have closed their connection
+fn relay(a: SocketStream, b: SocketStream) -> Promise<(), io::Error> {
+    Promise::all(vec![pump(a.clone(), b.clone()), pump(b, a)].into_iter()).map(|_| Ok(()))
+}
+
+// copy data from `from` to `to`, once `from` is closed the write side of `to` is shut down
+fn pump(mut from: SocketStream, to: SocketStream) -> Promise<(), io::Error> {
+    let from2 = from.clone();
+    from.read(vec![0; BUFFER_SIZE], 1).then(move |(mut buf, n)| {
+        if n == 0 {
+            return Promise::ok(pry!(shutdown_write(&to)));
+        }
+        buf.truncate(n);
+        let mut to2 = to.clone();
+        to2.write(buf).then(move |_| pump(from2, to))
+    })
+}
+
+// shut down the write side of `stream`, its peer reads the end of the stream
+fn shutdown_write(stream: &SocketStream) -> Result<(), io::Error> {
+    // SAFETY: the descriptor is only borrowed from `stream`, it is released again without being
+    // closed
+    let borrowed = unsafe { TcpStream::from_raw_fd(stream.as_raw_fd()) };
+    let res = borrowed.shutdown(Shutdown::Write);
+    borrowed.into_raw_fd();
+    res
+}
+
+#[test]
+fn test_socks_proxy() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use testing::{MockService, TestPeer};
+    use super::{LookupMessage, LookupResultMessage, Record, RecordFlags};
+
+    // "www.gnu" has an address, "leho.gnu" only a legacy hostname
+    let mut service = MockService::new("gns");
+    service.on_message(move |msg: LookupMessage, replies| {
+        let value = match (&msg.name[..], msg.record_type) {
+            ("www.gnu", RecordType::A)     => Some(RecordData::A(Ipv4Addr::new(127, 0, 0, 1))),
+            ("leho.gnu", RecordType::LEHO) => Some(RecordData::LEHO("127.0.0.1".to_string())),
+            _                              => None,
+        };
+        let records = value.map(|v| Record::from_value(&v, u64::max_value(), RecordFlags::empty()).unwrap());
+        replies.send(&LookupResultMessage { id: msg.id, records: records.into_iter().collect() })
+    });
+
+    // an echo server which only replies once the client has shut down its side of the connection
+    let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    thread::spawn(move || for stream in echo.incoming() {
+        let mut stream = stream.unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+    });
+
+    TestPeer::with_service(service, GNS::connect, |gns, t| {
+        let network = t.network.clone();
+        let master = EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+        let proxy = Rc::new(SocksProxy::new(gns, t.peer.cfg(), master, &network));
+        let mut connections = TaskSet::new(Box::new(Reaper));
+
+        // send a CONNECT request for `name` over a connection handled by the proxy, resolves to
+        // the reply code and the connection
+        let mut request = |name: &str, port: u16| {
+            let (mut client, server) = network.new_socket_pair().unwrap();
+            connections.add(handle(proxy.clone(), server));
+            let mut msg = vec![VERSION, 1, METHOD_NO_AUTH, VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, name.len() as u8];
+            msg.extend_from_slice(name.as_bytes());
+            msg.extend_from_slice(&[(port >> 8) as u8, port as u8]);
+            let mut client2 = client.clone();
+            client.write(msg).then(move |_| {
+                let client3 = client2.clone();
+                read_exact(&mut client2, 12).map(move |replies| {
+                    assert_eq!(&replies[..2], &[VERSION, METHOD_NO_AUTH]);
+                    Ok((replies[3], client3))
+                })
+            })
+        };
+
+        // the data sent before the client shuts down its side still reaches it back
+        let (code, mut client) = t.wait(request("www.gnu", echo_port)).unwrap();
+        assert_eq!(code, REPLY_SUCCEEDED);
+        t.wait(client.write(b"ping".to_vec())).unwrap();
+        shutdown_write(&client).unwrap();
+        assert_eq!(t.wait(read_exact(&mut client, 4)).unwrap(), b"ping");
+        let (_, n) = t.wait(client.read(vec![0; 1], 1)).unwrap();
+        assert_eq!(n, 0);
+
+        // the LEHO name is resolved as a legacy hostname
+        let (code, _) = t.wait(request("leho.gnu", echo_port)).unwrap();
+        assert_eq!(code, REPLY_SUCCEEDED);
+
+        let (code, _) = t.wait(request("ftp.gnu", echo_port)).unwrap();
+        assert_eq!(code, REPLY_HOST_UNREACHABLE);
+    });
+}
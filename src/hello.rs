@@ -1,16 +1,21 @@
 use std::fmt;
-use std::io::{self, Read};
-use byteorder::{ReadBytesExt, BigEndian};
+use std::io::{self, Read, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
+use ll;
+use service::message::{Message, MessageError};
 use PeerIdentity;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Hello {
   /// Use this peer in F2F mode. Do not gossip this hello.
   pub friend_only: bool,
 
   /// The identity of the peer.
   pub id: PeerIdentity,
+
+  /// The transport addresses of the peer, still in their serialized form.
+  pub addresses: Vec<u8>,
 }
 
 error_def! HelloDeserializeError {
@@ -35,6 +40,31 @@ impl Hello {
     Ok(Hello {
       friend_only: friend_only,
       id:          id,
+      addresses:   Vec::new(),
+    })
+  }
+}
+
+impl Message for Hello {
+  fn message_type() -> u16 {
+    ll::GNUNET_MESSAGE_TYPE_HELLO
+  }
+
+  fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+    try!(w.write_u32::<BigEndian>(self.friend_only as u32));
+    try!(self.id.serialize(w));
+    Ok(try!(w.write_all(&self.addresses[..])))
+  }
+
+  fn read_body<R: Read>(r: &mut R, len: usize) -> Result<Hello, MessageError> {
+    let friend_only = try!(r.read_u32::<BigEndian>()) != 0;
+    let id = try!(PeerIdentity::deserialize(r));
+    let mut addresses = vec![0u8; len.saturating_sub(4 + 32)];
+    try!(r.read_exact(&mut addresses[..]));
+    Ok(Hello {
+      friend_only: friend_only,
+      id:          id,
+      addresses:   addresses,
     })
   }
 }
@@ -44,4 +74,3 @@ impl fmt::Display for Hello {
     write!(f, "Hello!")
   }
 }
-
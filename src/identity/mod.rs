@@ -1,20 +1,20 @@
 //! Module for connecting to and querying the GNUnet identity service.
 
-use std::string;
 use std::collections::HashMap;
-use std::io::{self, Read, Cursor};
+use std::io::{self, Read, Write, Cursor};
 use std::fmt;
 use std::rc::Rc;
-use byteorder::{BigEndian, ReadBytesExt};
-use num::ToPrimitive;
+use std::cell::RefCell;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use ll;
 use EcdsaPrivateKey;
 use EcdsaPublicKey;
 use HashCode;
-use service::{self, ServiceReader, ServiceWriter, MessageHeader, MessageTrait};
+use service::{self, ServiceReader, ProcessMessageResult};
+use service::dispatch::{self, Deadline, Dispatcher, Route, ReconnectPolicy, EventListener, ServiceEvent};
+use service::message::{self, Message, MessageError};
 use configuration::Cfg;
-use util::{ReadCString, ReadCStringError, ReadCStringWithLenError};
 
 use gj::{Promise};
 use gjio::{Network};
@@ -74,9 +74,8 @@ impl fmt::Display for Ego {
 
 /// A handle to the identity service.
 pub struct IdentityService {
-    service_reader: ServiceReader,
-    service_writer: ServiceWriter,
-    egos: Rc<HashMap<HashCode, Ego>>, // egos is only modified at connect
+    dispatcher: Dispatcher,
+    egos: Rc<RefCell<HashMap<HashCode, Ego>>>, // kept up to date with the service's updates
 }
 
 /// Errors returned by `IdentityService::connect`
@@ -89,8 +88,8 @@ error_def! ConnectError {
         => "An I/O error occured while communicating with the identity service" ("Specifically: {}", cause),
     ReadMessage { #[from] cause: service::ReadMessageError }
         => "Failed to read a message from the server" ("Specifically: {}", cause),
-    InvalidName { #[from] cause: string::FromUtf8Error }
-        => "The service responded with a name containing invalid utf-8 during initial exchange. *(It is a bug to see this error)*" ("Utf8-error: {}", cause),
+    Message { #[from] cause: MessageError }
+        => "Failed to encode or decode a message exchanged with the service during initial exchange. *(It is a bug to see this error)*" ("Reason: {}", cause),
     UnexpectedMessageType { ty: u16 }
         => "Received an unexpected message from the service during initial exchange. *(It is a bug to see this error)*" ("Message type {} was not expected.", ty)
 }
@@ -101,20 +100,32 @@ error_def! GetDefaultEgoError {
         => "The name of the service was too long" ("\"{}\" is too long to be the name of a service.", name),
     Io { #[from] cause: io::Error }
         => "An I/O error occured while communicating with the identity service" ("Specifically: {}", cause),
-    ReadMessage { #[from] cause: service::ReadMessageError }
+    ReadMessage { cause: service::ReadMessageError }
         => "Failed to read a message from the server" ("Specifically: {}", cause),
     ServiceResponse { response: String }
         => "The service responded with an error message" ("Error: \"{}\"", response),
-    MalformedErrorResponse { #[from] cause: string::FromUtf8Error }
-        => "The service responded with an error message but the message contained invalid utf-8" ("Utf8-error: {}", cause),
-    ReceiveName { #[from] cause: ReadCStringWithLenError }
-        => "Failed to receive the identity name from the service" ("Reason: {}", cause),
+    Message { #[from] cause: MessageError }
+        => "Failed to encode or decode a message exchanged with the service" ("Reason: {}", cause),
     Connect { #[from] cause: ConnectError }
         => "Failed to connect to the identity service" ("Reason: {}", cause),
     InvalidResponse
         => "The service response was incoherent. You should file a bug-report if you encounter this error.",
     Disconnected
         => "The service disconnected unexpectedly",
+    TimedOut
+        => "The service did not answer before the deadline",
+    Cancelled
+        => "The request was cancelled",
+}
+
+impl From<service::ReadMessageError> for GetDefaultEgoError {
+    fn from(e: service::ReadMessageError) -> GetDefaultEgoError {
+        match e {
+            service::ReadMessageError::TimedOut  => GetDefaultEgoError::TimedOut,
+            service::ReadMessageError::Cancelled => GetDefaultEgoError::Cancelled,
+            e                                    => GetDefaultEgoError::ReadMessage { cause: e },
+        }
+    }
 }
 
 impl IdentityService {
@@ -135,7 +146,7 @@ impl IdentityService {
         service::connect(cfg, "identity", network)
             .lift()
             .then(|(sr, mut sw)| {
-                sw.send(StartMessage::new())
+                sw.send(&StartMessage)
                     .lift()
                     .map(move |()| { Ok((sr, sw)) })
             })
@@ -143,48 +154,85 @@ impl IdentityService {
                 let egos: HashMap<HashCode, Ego> = HashMap::new();
                 IdentityService::parse_egos(sr, egos)
                     .map(|(sr, egos)| {
+                        let egos = Rc::new(RefCell::new(egos));
+                        let mut dispatcher = Dispatcher::new(sr, sw, Box::new(IdentityService::route));
+                        dispatcher.replies_in_order();
+                        // after reconnecting the service has to be asked for the egos again
+                        try!(dispatcher.add_handshake(&StartMessage));
+                        // the service sends all the egos again in answer to the handshake, egos
+                        // deleted while we were disconnected must not be kept
+                        let egos2 = egos.clone();
+                        dispatcher.on_event(Box::new(move |event| {
+                            if let ServiceEvent::Reconnected { .. } = *event {
+                                egos2.borrow_mut().clear();
+                            }
+                        }));
+                        let egos2 = egos.clone();
+                        dispatcher.subscribe(ll::GNUNET_MESSAGE_TYPE_IDENTITY_UPDATE, Box::new(move |tpe, mr| {
+                            if let Ok(msg) = message::decode::<UpdateMessage>(tpe, mr) {
+                                if !msg.end_of_list {
+                                    IdentityService::update_ego(&mut egos2.borrow_mut(), msg);
+                                }
+                            }
+                            ProcessMessageResult::Continue
+                        }));
                         Ok(IdentityService {
-                            service_reader: sr,
-                            service_writer: sw,
-                            egos: Rc::new(egos),
+                            dispatcher: dispatcher,
+                            egos: egos,
                         })
                     })
             })
     }
 
+    /// Reconnect to the service according to `policy` whenever the connection is lost. Requests
+    /// made while disconnected are sent once the connection is re-established.
+    pub fn enable_reconnect(&mut self, cfg: &Cfg, network: &Network, policy: ReconnectPolicy) {
+        self.dispatcher.enable_reconnect(dispatch::connector(cfg, "identity", network), policy);
+    }
+
+    /// Call `listener` whenever the connection to the service is lost or re-established.
+    pub fn on_service_event(&mut self, listener: EventListener) {
+        self.dispatcher.on_event(listener);
+    }
+
+    // the service answers requests in the order they were made
+    fn route(tpe: u16, _body: &[u8]) -> Route {
+        match tpe {
+            ll::GNUNET_MESSAGE_TYPE_IDENTITY_RESULT_CODE |
+            ll::GNUNET_MESSAGE_TYPE_IDENTITY_SET_DEFAULT => Route::OldestLast,
+            _ => Route::Unsolicited,
+        }
+    }
+
+    // an update without a name means the ego was deleted
+    fn update_ego(egos: &mut HashMap<HashCode, Ego>, msg: UpdateMessage) {
+        let id = msg.private_key.get_public().hash();
+        if msg.name.is_empty() {
+            egos.remove(&id);
+        }
+        else {
+            egos.insert(id.clone(), Ego {
+                sk: msg.private_key,
+                name: Some(msg.name),
+                id: id,
+            });
+        }
+    }
+
     /// This recursive function reads data from the ServiceReader `sr`
     /// and attempts to parse the result into egos.
     fn parse_egos<'a>(mut sr: ServiceReader, mut egos: HashMap<HashCode, Ego>)
                       -> Promise<(ServiceReader, HashMap<HashCode, Ego>), ConnectError> {
         sr.read_message()
             .lift()
-            .then(|(tpe, mut mr)| {
+            .then(|(tpe, mr)| {
                 match tpe {
                     ll::GNUNET_MESSAGE_TYPE_IDENTITY_UPDATE => {
-                        let name_len = pry!(mr.read_u16::<BigEndian>());
-                        let eol = pry!(mr.read_u16::<BigEndian>());
-                        if eol != 0 {
+                        let msg: UpdateMessage = pry!(message::decode(tpe, mr));
+                        if msg.end_of_list {
                             return Promise::ok((sr, egos));
                         };
-                        let sk = pry!(EcdsaPrivateKey::deserialize(&mut mr));
-                        let mut v: Vec<u8> = Vec::with_capacity(name_len as usize);
-                        for r in mr.bytes() {
-                            let b = pry!(r);
-                            if b == 0u8 {
-                                break;
-                            }
-                            v.push(b)
-                        };
-                        let name = match String::from_utf8(v) {
-                            Ok(n)   => n,
-                            Err(v)  => return Promise::err(ConnectError::InvalidName { cause: v }),
-                        };
-                        let id = sk.get_public().hash();
-                        egos.insert(id.clone(), Ego {
-                            sk: sk,
-                            name: Some(name),
-                            id: id,
-                        });
+                        IdentityService::update_ego(&mut egos, msg);
                         return IdentityService::parse_egos(sr, egos)
                     },
                     _ => return Promise::err(ConnectError::UnexpectedMessageType { ty: tpe }),
@@ -194,6 +242,9 @@ impl IdentityService {
 
     /// Returns a promise to the default identity associated with a service.
     ///
+    /// If `deadline` passes before the service answers the promise is rejected with
+    /// `GetDefaultEgoError::TimedOut`. Dropping the promise cancels the request.
+    ///
     /// # Example
     ///
     /// Get the ego for the default master zone.
@@ -208,67 +259,40 @@ impl IdentityService {
     /// let gns_master = ::std::rc::Rc::new("gns-master".to_string());
     ///
     /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-    ///     let ego_promise = IdentityService::connect(&config, &network).lift()
-    ///                         .then(|mut is| { is.get_default_ego(gns_master) });
-    ///     let ego = ego_promise.wait(wait_scope, &mut event_port);
+    ///     let mut is = IdentityService::connect(&config, &network).wait(wait_scope, &mut event_port).unwrap();
+    ///     let ego = is.get_default_ego(gns_master, None).wait(wait_scope, &mut event_port);
     ///     Ok(())
     /// }).expect("top_level");
     /// ```
-    pub fn get_default_ego(&mut self, name: Rc<String>) -> Promise<Ego, GetDefaultEgoError> {
+    pub fn get_default_ego(&mut self, name: Rc<String>, deadline: Option<Deadline>) -> Promise<Ego, GetDefaultEgoError> {
         let msg = pry!(GetDefaultMessage::new(&name));
-        let mut sr = self.service_reader.clone();
+        let id = self.dispatcher.next_id();
         let egos = self.egos.clone();
-        self.service_writer.send_with_str(msg, &name)
-            .lift()
-            .then(move |()| {
-                sr.read_message()
-                    .lift()
-                    .map(move |(tpe, mr)| {
-                        match IdentityService::parse_identity(egos, &name, tpe, mr) {
-                            Ok(ego) => Ok(ego),
-                            Err(e)  => Err(e),
-                        }
-                    })
+        self.dispatcher.request(id, &msg, deadline)
+            .map(move |(tpe, mr)| {
+                IdentityService::parse_identity(&egos.borrow(), &name, tpe, mr)
             })
-            .lift()
     }
 
     /// Returns an identity by parsing data from `mr`.
-    fn parse_identity(rc_egos: Rc<HashMap<HashCode, Ego>>, name: &str, tpe: u16, mut mr: Cursor<Vec<u8>>)
+    fn parse_identity(egos: &HashMap<HashCode, Ego>, name: &str, tpe: u16, mr: Cursor<Vec<u8>>)
                       -> Result<Ego, GetDefaultEgoError>
     {
-        let egos = rc_egos.as_ref();
         match tpe {
             ll::GNUNET_MESSAGE_TYPE_IDENTITY_RESULT_CODE => {
-                try!(mr.read_u32::<BigEndian>());
-                match mr.read_c_string() {
-                    Err(e) => match e {
-                        ReadCStringError::Io { cause }       => Err(GetDefaultEgoError::Io { cause: cause }),
-                        ReadCStringError::FromUtf8 { cause } => Err(GetDefaultEgoError::MalformedErrorResponse { cause: cause }),
-                        ReadCStringError::Disconnected       => Err(GetDefaultEgoError::Disconnected),
-                    },
-                    Ok(s)  => Err(GetDefaultEgoError::ServiceResponse { response: s }),
-                }
+                let msg: ResultCodeMessage = try!(message::decode(tpe, mr));
+                Err(GetDefaultEgoError::ServiceResponse { response: msg.message })
             },
-            ll::GNUNET_MESSAGE_TYPE_IDENTITY_SET_DEFAULT => match try!(mr.read_u16::<BigEndian>()) {
-                0 => Err(GetDefaultEgoError::InvalidResponse),
-                reply_name_len => {
-                    let zero = try!(mr.read_u16::<BigEndian>());
-                    match zero {
-                        0 => {
-                            let sk = try!(EcdsaPrivateKey::deserialize(&mut mr));
-                            let s: String = try!(mr.read_c_string_with_len((reply_name_len - 1) as usize));
-                            match &s[..] == name {
-                                true  => {
-                                    let id = sk.get_public().hash();
-                                    Ok(egos[&id].clone())
-                                },
-                                false => Err(GetDefaultEgoError::InvalidResponse),
-                            }
-                        },
-                        _ => Err(GetDefaultEgoError::InvalidResponse),
-                    }
-                },
+            ll::GNUNET_MESSAGE_TYPE_IDENTITY_SET_DEFAULT => {
+                let msg: SetDefaultMessage = try!(message::decode(tpe, mr));
+                if &msg.name[..] != name {
+                    return Err(GetDefaultEgoError::InvalidResponse);
+                }
+                let id = msg.private_key.get_public().hash();
+                match egos.get(&id) {
+                    Some(ego) => Ok(ego.clone()),
+                    None      => Err(GetDefaultEgoError::InvalidResponse),
+                }
             },
             _ => Err(GetDefaultEgoError::InvalidResponse),
         }
@@ -285,7 +309,8 @@ error_def! ConnectGetDefaultEgoError {
         => "An I/O error occured while communicating with the identity service" ("Specifically: {}", cause),
 }
 
-/// Get the default identity associated with a service.
+/// Get the default identity associated with a service. `deadline` applies to the request once
+/// connected.
 ///
 /// # Example
 ///
@@ -299,7 +324,7 @@ error_def! ConnectGetDefaultEgoError {
 /// let gns_master = ::std::rc::Rc::new("gns-master".to_string());
 ///
 /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-///     let ego_promise = gnunet::get_default_ego(&config, gns_master, &network);
+///     let ego_promise = gnunet::get_default_ego(&config, gns_master, &network, None);
 ///     let ego = ego_promise.wait(wait_scope, &mut event_port);
 ///     Ok(())
 /// }).expect("top_level");
@@ -313,72 +338,187 @@ error_def! ConnectGetDefaultEgoError {
 pub fn get_default_ego(
     cfg: &Cfg,
     name: Rc<String>,
-    network: &Network) -> Promise<Ego, ConnectGetDefaultEgoError> {
+    network: &Network,
+    deadline: Option<Deadline>) -> Promise<Ego, ConnectGetDefaultEgoError> {
     IdentityService::connect(cfg, network)
         .lift()
         .then(move |mut is| {
-            is.get_default_ego(name)
+            let ego_promise = is.get_default_ego(name, deadline);
+            ego_promise.map(move |ego| {
+                // keep the connection open until the reply has arrived
+                drop(is);
+                Ok(ego)
+            })
         })
         .lift()
 }
 
-/// Packed struct representing the initial message sent to the identity service.
-#[repr(C, packed)]
-struct StartMessage {
-    header: MessageHeader,
+/// The initial message sent to the identity service, asking it to send us all the egos.
+pub struct StartMessage;
+
+impl Message for StartMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_START
+    }
+
+    fn write_body<W: Write>(&self, _w: &mut W) -> Result<(), MessageError> {
+        Ok(())
+    }
+
+    fn read_body<R: Read>(_r: &mut R, _len: usize) -> Result<StartMessage, MessageError> {
+        Ok(StartMessage)
+    }
 }
 
-impl StartMessage {
-    fn new () -> StartMessage {
-        StartMessage {
-            header: MessageHeader {
-                len: 4u16.to_be(),
-                tpe: ll::GNUNET_MESSAGE_TYPE_IDENTITY_START.to_be(),
-            }
-        }
+/// GNUNET_IDENTITY_ResultCodeMessage, sent by the service in response to a failed request.
+pub struct ResultCodeMessage {
+    /// Zero on success, non-zero on failure.
+    pub result_code: u32,
+    /// A description of the error, possibly empty.
+    pub message: String,
+}
+
+impl Message for ResultCodeMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_RESULT_CODE
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(self.result_code));
+        message::write_c_string(w, &self.message)
+    }
+
+    fn read_body<R: Read>(r: &mut R, len: usize) -> Result<ResultCodeMessage, MessageError> {
+        let result_code = try!(r.read_u32::<BigEndian>());
+        // the error message is optional
+        let message = match len > 4 {
+            true  => try!(message::read_c_string(r)),
+            false => String::new(),
+        };
+        Ok(ResultCodeMessage {
+            result_code: result_code,
+            message: message,
+        })
     }
 }
 
-impl MessageTrait for StartMessage {
-    fn into_slice(&self) -> &[u8] {
-        message_to_slice!(StartMessage, self)
+/// GNUNET_IDENTITY_UpdateMessage, sent by the service to describe one of its egos.
+pub struct UpdateMessage {
+    /// Set on the message marking the end of the initial list of egos, which carries no ego.
+    pub end_of_list: bool,
+    /// The private key of the ego.
+    pub private_key: EcdsaPrivateKey,
+    /// The name of the ego, empty if the ego was deleted.
+    pub name: String,
+}
+
+impl Message for UpdateMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_UPDATE
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        let name_len = match self.name.is_empty() {
+            true  => 0,
+            false => self.name.len() + 1,
+        };
+        try!(w.write_u16::<BigEndian>(name_len as u16));
+        try!(w.write_u16::<BigEndian>(self.end_of_list as u16));
+        try!(self.private_key.serialize(w));
+        if name_len > 0 {
+            try!(message::write_c_string(w, &self.name));
+        }
+        Ok(())
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<UpdateMessage, MessageError> {
+        let name_len = try!(r.read_u16::<BigEndian>());
+        let end_of_list = try!(r.read_u16::<BigEndian>()) != 0;
+        let private_key = try!(EcdsaPrivateKey::deserialize(r));
+        let name = match name_len {
+            0 => String::new(),
+            n => try!(message::read_c_string_with_len(r, (n - 1) as usize)),
+        };
+        Ok(UpdateMessage {
+            end_of_list: end_of_list,
+            private_key: private_key,
+            name: name,
+        })
     }
 }
 
-/// Packed struct representing GNUNET_IDENTITY_GetDefaultMessage,
-/// note that it must be followed by a 0-terminated string.
-#[repr(C, packed)]
-struct GetDefaultMessage {
-    header: MessageHeader,
-    name_len: u16,
-    reserved: u16, // always zero
-    // followed by 0-terminated string
+/// GNUNET_IDENTITY_GetDefaultMessage, asks the service for the default ego of a subsystem.
+pub struct GetDefaultMessage {
+    /// The name of the subsystem, eg. `"gns-master"`.
+    pub name: String,
 }
 
 impl GetDefaultMessage {
     fn new(name: &str) -> Result<GetDefaultMessage, GetDefaultEgoError> {
-        use std::mem;
-        let name_len = name.len();
-        let msg_len = match (mem::size_of::<GetDefaultMessage>() + name_len + 1).to_u16() {
-            Some(l) => l,
-            None    => return Err(GetDefaultEgoError::NameTooLong { name: name.to_string() }),
-        };
-
+        if name.len() + 1 > ::std::u16::MAX as usize {
+            return Err(GetDefaultEgoError::NameTooLong { name: name.to_string() });
+        }
         Ok(GetDefaultMessage {
-            header: MessageHeader {
-                len: (msg_len as u16).to_be(),
-                tpe: ll::GNUNET_MESSAGE_TYPE_IDENTITY_GET_DEFAULT.to_be(),
-            },
-            name_len: ((name_len + 1) as u16).to_be(),
-            reserved: 0u16.to_be(),
+            name: name.to_string(),
         })
     }
 }
 
-impl MessageTrait for GetDefaultMessage {
-    // Note that this does not include the 0-terminated string.
-    fn into_slice(&self) -> &[u8] {
-        message_to_slice!(GetDefaultMessage, self)
+impl Message for GetDefaultMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_GET_DEFAULT
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u16::<BigEndian>((self.name.len() + 1) as u16));
+        try!(w.write_u16::<BigEndian>(0)); // reserved
+        message::write_c_string(w, &self.name)
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<GetDefaultMessage, MessageError> {
+        let name_len = try!(r.read_u16::<BigEndian>());
+        try!(r.read_u16::<BigEndian>());
+        if name_len == 0 {
+            return Err(MessageError::InvalidField { tpe: GetDefaultMessage::message_type(), field: "name_len" });
+        }
+        let name = try!(message::read_c_string_with_len(r, (name_len - 1) as usize));
+        Ok(GetDefaultMessage {
+            name: name,
+        })
     }
 }
 
+/// GNUNET_IDENTITY_SetDefaultMessage, sent by the service in reply to a `GetDefaultMessage`.
+pub struct SetDefaultMessage {
+    /// The private key of the default ego.
+    pub private_key: EcdsaPrivateKey,
+    /// The name of the subsystem the ego is the default of.
+    pub name: String,
+}
+
+impl Message for SetDefaultMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_SET_DEFAULT
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u16::<BigEndian>((self.name.len() + 1) as u16));
+        try!(w.write_u16::<BigEndian>(0)); // reserved
+        try!(self.private_key.serialize(w));
+        message::write_c_string(w, &self.name)
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<SetDefaultMessage, MessageError> {
+        let name_len = try!(r.read_u16::<BigEndian>());
+        try!(r.read_u16::<BigEndian>());
+        if name_len == 0 {
+            return Err(MessageError::InvalidField { tpe: SetDefaultMessage::message_type(), field: "name_len" });
+        }
+        let private_key = try!(EcdsaPrivateKey::deserialize(r));
+        let name = try!(message::read_c_string_with_len(r, (name_len - 1) as usize));
+        Ok(SetDefaultMessage {
+            private_key: private_key,
+            name: name,
+        })
+    }
+}
@@ -23,12 +23,12 @@ extern crate regex;
 extern crate gjio;
 
 pub use configuration::Cfg;
-pub use crypto::{EcdsaPublicKey, EcdsaPrivateKey, HashCode};
+pub use crypto::{EcdsaPublicKey, EcdsaPrivateKey, EcdsaSignature, HashCode};
 
 pub use gns::{lookup_in_master, GNS, LocalOptions};
 pub use identity::{get_default_ego, Ego, IdentityService};
 pub use hello::Hello;
-pub use peerinfo::{get_peers, get_peers_vec, get_peer, get_self_id, PeerIdentity};
+pub use peerinfo::{get_peers, get_peers_vec, get_peer, get_self_id, PeerIdentity, PeerInfoService};
 //pub use dht::DHT;
 
 /*
@@ -84,12 +84,13 @@ fn print_error<E: ::std::error::Error>(error: &E, file: &str, line: u32) {
 #[allow(dead_code, non_camel_case_types, non_snake_case, non_upper_case_globals)]
 mod ll;
 
-#[macro_use]
 pub mod service;
 pub mod configuration;
 pub mod time;
 pub mod paths;
 pub mod gns;
+pub mod dns2gns;
+pub mod dnsparser;
 //pub mod dht;
 mod crypto;
 pub mod identity;
@@ -99,4 +100,7 @@ pub mod hello;
 //pub mod cadet;
 pub mod data;
 pub mod transport;
+pub mod testing;
+pub mod blocking;
+pub mod sync;
 
@@ -1,20 +1,24 @@
-use std::mem::{size_of, uninitialized, size_of_val};
+use std::mem::size_of_val;
 use std::fmt;
 use std::str::{FromStr};
 use std::io::{self, Read, Write, Cursor};
-use byteorder::{BigEndian, ReadBytesExt};
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use gj::Promise;
-use gjio::Network;
+use gj::{Promise, FulfillerDropped};
+use gjio::{Network, Timer};
 
 use ll;
 use Cfg;
-use service::{self, connect, ServiceReader, ReadMessageError, MessageTrait, MessageHeader};
+use service::{self, connect, ServiceReader, ReadMessageError, ProcessMessageResult};
+use service::dispatch::{self, Deadline, Dispatcher, Route, ReplyHandler, ReconnectPolicy, EventListener};
+use service::message::{self, Message, MessageError, HEADER_SIZE};
 use Hello;
 use transport::{self, TransportServiceInitError};
 use util::strings::{data_to_string, string_to_data};
 
 /// The identity of a GNUnet peer.
+#[derive(Copy, Clone)]
 pub struct PeerIdentity {
     data: ll::Struct_GNUNET_PeerIdentity,
 }
@@ -22,7 +26,7 @@ pub struct PeerIdentity {
 impl PeerIdentity {
     /// Deserializes into PeerIdentity from a reader, the reader should have 32 bytes available.
     pub fn deserialize<R>(r: &mut R) -> Result<PeerIdentity, io::Error> where R: Read {
-        let mut ret: PeerIdentity = unsafe { uninitialized() };
+        let mut ret = PeerIdentity { data: Default::default() };
         try!(r.read_exact(&mut ret.data.public_key.q_y[..]));
         Ok(ret)
     }
@@ -33,6 +37,14 @@ impl PeerIdentity {
     }
 }
 
+impl PartialEq for PeerIdentity {
+    fn eq(&self, other: &PeerIdentity) -> bool {
+        self.data.public_key.q_y == other.data.public_key.q_y
+    }
+}
+
+impl Eq for PeerIdentity {}
+
 /// Error generated when attempting to parse a PeerIdentity
 error_def! PeerIdentityFromStrError {
     ParsingFailed => "Failed to parse the string as a PeerIdentity"
@@ -57,7 +69,7 @@ impl FromStr for PeerIdentity {
     }
 }
 
-/// Get a promise of a peer by its key.
+/// Get a promise of a peer by its key. `deadline` applies to the request once connected.
 ///
 /// # Example
 ///
@@ -71,43 +83,42 @@ impl FromStr for PeerIdentity {
 /// let pk_string = "DPQIBOOJV8QBS3FGJ6B0K5NTSQ9SULV45H5KCR4HU7PQ64N8Q9F0";
 ///
 /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-///     let peer_promise = gnunet::get_peer(&config, &network, pk_string).map(|(peer, _)| { Ok(peer) });
+///     let peer_promise = gnunet::get_peer(&config, &network, pk_string, None).map(|(peer, _)| { Ok(peer) });
 ///     let peer = peer_promise.wait(wait_scope, &mut event_port);
 ///     // do something with `peer`
 ///     Ok(())
 /// }).expect("top_level");
 /// ```
 ///
-pub fn get_peer(cfg: &Cfg, network: &Network, pk_string: &str) -> Promise<(Option<PeerIdentity>, Option<Hello>), PeerInfoError> {
+pub fn get_peer(cfg: &Cfg, network: &Network, pk_string: &str, deadline: Option<Deadline>)
+                -> Promise<(Option<PeerIdentity>, Option<Hello>), PeerInfoError> {
     // prepare peer identity
     let pk = &mut [0; 32];
     string_to_data(pk_string, pk);
-    let id =
-        ll::Struct_GNUNET_PeerIdentity {
+    let id = PeerIdentity {
+        data: ll::Struct_GNUNET_PeerIdentity {
             public_key : ll::Struct_GNUNET_CRYPTO_EddsaPublicKey {
                 q_y: *pk,
             }
-        };
+        }
+    };
 
-    connect(cfg, "peerinfo", network).lift().then(move |(sr, mut sw)| {
-            sw.send(ListPeerMessage::new(0, id)).lift().then(move |()| {
-                let mut peers = Peers { service: sr };
-                peers.get_vec().map(|mut vec| {
-                    match vec.len() {
-                        0 | 1 => {
-                            match vec.pop() {
-                                Some((id, hello)) => Ok((Some(id), hello)),
-                                None => Ok((None, None)),
-                            }
-                        },
-                        _ => Err(PeerInfoError::InvalidResponse),
-                    }
-                })
-            })
+    PeerInfoService::connect(cfg, network).then(move |mut pis| {
+        let peer_promise = pis.get_peer(&id, deadline);
+        peer_promise.map(move |peer| {
+            // keep the connection open until the reply has arrived
+            drop(pis);
+            match peer {
+                Some((id, hello)) => Ok((Some(id), hello)),
+                None              => Ok((None, None)),
+            }
         })
+    })
 }
 
-/// Get a proimise to all the currently connected peers.
+/// Get a proimise to all the currently connected peers. If `deadline` passes before the whole
+/// list has been received, counting from when the request is sent, `Peers` fails with
+/// `PeerInfoError::TimedOut`.
 ///
 /// # Example
 ///
@@ -120,27 +131,29 @@ pub fn get_peer(cfg: &Cfg, network: &Network, pk_string: &str) -> Promise<(Optio
 /// let network = event_port.get_network();
 ///
 /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-///     let peers_promise = gnunet::get_peers(&config, &network);
+///     let peers_promise = gnunet::get_peers(&config, &network, None);
 ///     let peers = peers_promise.wait(wait_scope, &mut event_port);
 ///     // do things with `peers`, i.e. use its methods such as `iterate` or `get_vec`
 ///     Ok(())
 /// }).expect("top_level");
 /// ```
 ///
-pub fn get_peers(cfg: &Cfg, network: &Network)
+pub fn get_peers(cfg: &Cfg, network: &Network, deadline: Option<Deadline>)
                      -> Promise<Peers, PeerInfoError> {
     connect(cfg, "peerinfo", network)
         .lift()
         .then(move |(sr, mut sw)| {
-            sw.send(ListAllPeersMessage::new(0))
+            let expire = deadline.map(|d| (d.timer().clone(), Instant::now() + d.timeout()));
+            sw.send(&ListAllPeersMessage { include_friend_only: false })
                 .lift()
                 .map(move |()| {
-                    Ok(Peers { service: sr })
+                    Ok(Peers { service: sr, expire: expire })
                 })
         })
 }
 
-/// Get a promise to a vector of all the currently connected peers.
+/// Get a promise to a vector of all the currently connected peers. `deadline` applies to the
+/// request once connected.
 ///
 /// # Example
 ///
@@ -153,7 +166,7 @@ pub fn get_peers(cfg: &Cfg, network: &Network)
 /// let network = event_port.get_network();
 ///
 /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-///     let peers_vec = gnunet::get_peers_vec(&config, &network).wait(wait_scope, &mut event_port).unwrap();
+///     let peers_vec = gnunet::get_peers_vec(&config, &network, None).wait(wait_scope, &mut event_port).unwrap();
 ///     for (peerinfo, _) in peers_vec {
 ///         // do something with `peerinfo`
 ///     }
@@ -161,13 +174,18 @@ pub fn get_peers(cfg: &Cfg, network: &Network)
 /// }).expect("top_level");
 /// ```
 ///
-pub fn get_peers_vec(cfg: &Cfg, network: &Network) -> Promise<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
-    get_peers(cfg, network).then(|mut peers| {
-        peers.get_vec()
+pub fn get_peers_vec(cfg: &Cfg, network: &Network, deadline: Option<Deadline>)
+                     -> Promise<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+    PeerInfoService::connect(cfg, network).then(move |mut pis| {
+        pis.get_peers_vec(deadline).map(move |peers| {
+            // keep the connection open until the reply has arrived
+            drop(pis);
+            Ok(peers)
+        })
     })
 }
 
-/// Get our own identity.
+/// Get our own identity. `deadline` is handled as by `transport::self_hello`.
 ///
 /// # Example
 ///
@@ -180,15 +198,16 @@ pub fn get_peers_vec(cfg: &Cfg, network: &Network) -> Promise<Vec<(PeerIdentity,
 /// let network = event_port.get_network();
 ///
 /// async::EventLoop::top_level(|wait_scope| -> Result<(), ::std::io::Error> {
-///     let get_self_id_promise = gnunet::get_self_id(&config, &network);
+///     let get_self_id_promise = gnunet::get_self_id(&config, &network, None);
 ///     let get_self_id = get_self_id_promise.wait(wait_scope, &mut event_port);
 ///     // do something with `get_self_id`
 ///     Ok(())
 /// }).expect("top_level");
 /// ```
 ///
-pub fn get_self_id(cfg: &Cfg, network: &Network) -> Promise<PeerIdentity, TransportServiceInitError> {
-    transport::self_hello(cfg, network)
+pub fn get_self_id(cfg: &Cfg, network: &Network, deadline: Option<Deadline>)
+                   -> Promise<PeerIdentity, TransportServiceInitError> {
+    transport::self_hello(cfg, network, deadline)
         .map(|hello| {
             Ok(hello.id)
         })
@@ -197,6 +216,8 @@ pub fn get_self_id(cfg: &Cfg, network: &Network) -> Promise<PeerIdentity, Transp
 /// Struct representing all the currently connected peers.
 pub struct Peers {
     service: ServiceReader,
+    // the timer and the time at which the deadline given to `get_peers` passes
+    expire: Option<(Timer, Instant)>,
 }
 
 /// Errors returned by `Peers::next`.
@@ -207,81 +228,185 @@ error_def! PeerInfoError {
         => "The peerinfo service sent an unexpected response message type" ("Message type {} was not expected", ty),
     Io { #[from] cause: io::Error }
         => "There was an I/O error communicating with the peerinfo service" ("Specifically: {}", cause),
-    ReadMessage { #[from] cause: ReadMessageError }
+    ReadMessage { cause: ReadMessageError }
         => "Failed to receive the response from the peerinfo service" ("Reason: {}", cause),
     Disconnected
         => "The service disconnected unexpectedly",
     Connect { #[from] cause: service::ConnectError }
-        => "Failed to connect to the peerinfo service" ("Reason: {}", cause)
+        => "Failed to connect to the peerinfo service" ("Reason: {}", cause),
+    Message { #[from] cause: MessageError }
+        => "Failed to encode or decode a message exchanged with the peerinfo service" ("Reason: {}", cause),
+    FulfillerDropped
+        => "Promise fulfiller was dropped",
+    TimedOut
+        => "The peerinfo service did not answer before the deadline",
+    Cancelled
+        => "The request was cancelled",
+}
+
+impl From<ReadMessageError> for PeerInfoError {
+    fn from(e: ReadMessageError) -> PeerInfoError {
+        match e {
+            ReadMessageError::TimedOut  => PeerInfoError::TimedOut,
+            ReadMessageError::Cancelled => PeerInfoError::Cancelled,
+            e                           => PeerInfoError::ReadMessage { cause: e },
+        }
+    }
+}
+
+impl FulfillerDropped for PeerInfoError {
+    fn fulfiller_dropped() -> PeerInfoError {
+        PeerInfoError::FulfillerDropped
+    }
 }
 
 impl Peers {
     /// Returns a promise to the next iteration.
     pub fn iterate(&mut self) -> Promise<Option<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
-        self.service.read_message()
+        let read = self.service.read_message()
             .map_else(move |x| {
                 match x {
                     Err(e)  => return Err(PeerInfoError::ReadMessage { cause: e }),
                     Ok((tpe, mr))   => parse_peer(tpe, mr),
                 }
-            })
+            });
+        match self.expire {
+            Some((ref timer, at)) => {
+                let now = Instant::now();
+                let left = if at > now { at.duration_since(now) } else { Duration::new(0, 0) };
+                let expired = timer.after_delay(left).then_else(|_| Promise::err(PeerInfoError::TimedOut));
+                read.exclusive_join(expired)
+            },
+            None => read,
+        }
     }
 
     /// Returns a promise to a vector of all connected peers.
     pub fn get_vec(&mut self) -> Promise<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
-        let v = Vec::new();
-        let mut sr = self.service.clone();
-        Peers::peers_loop(&mut sr, v)
+        let peers = Peers {
+            service: self.service.clone(),
+            expire: self.expire.clone(),
+        };
+        Peers::peers_loop(peers, Vec::new())
     }
 
-    fn peers_loop(sr: &mut ServiceReader, mut v: Vec<(PeerIdentity, Option<Hello>)>)
+    fn peers_loop(mut peers: Peers, mut v: Vec<(PeerIdentity, Option<Hello>)>)
                        -> Promise<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError>
     {
-        let mut sr2 = sr.clone();
-        sr.read_message().then_else(move |x| {
+        peers.iterate().then(move |x| {
             match x {
-                Err(e)  => return Promise::err(PeerInfoError::ReadMessage { cause: e }),
-                Ok((tpe, mr))   => {
-                    match parse_peer(tpe, mr) {
-                        Ok(Some(x)) => {
-                            v.push(x);
-                            Peers::peers_loop(&mut sr2, v)
-                        },
-                        Ok(None) => return Promise::ok(v),
-                        Err(e) => return Promise::err(e),
-                    }
-                }
+                Some(x) => {
+                    v.push(x);
+                    Peers::peers_loop(peers, v)
+                },
+                None => Promise::ok(v),
             }
         })
     }
 }
 
+/// A handle to the peerinfo service. Any number of requests can be made concurrently.
+pub struct PeerInfoService {
+    dispatcher: Dispatcher,
+}
+
+impl PeerInfoService {
+    /// Connect to the peerinfo service.
+    pub fn connect(cfg: &Cfg, network: &Network) -> Promise<PeerInfoService, PeerInfoError> {
+        connect(cfg, "peerinfo", network)
+            .lift()
+            .map(|(sr, sw)| {
+                let mut dispatcher = Dispatcher::new(sr, sw, Box::new(PeerInfoService::route));
+                dispatcher.replies_in_order();
+                Ok(PeerInfoService {
+                    dispatcher: dispatcher,
+                })
+            })
+    }
+
+    /// Reconnect to the service according to `policy` whenever the connection is lost. Requests
+    /// made while disconnected are sent once the connection is re-established.
+    pub fn enable_reconnect(&mut self, cfg: &Cfg, network: &Network, policy: ReconnectPolicy) {
+        self.dispatcher.enable_reconnect(dispatch::connector(cfg, "peerinfo", network), policy);
+    }
+
+    /// Call `listener` whenever the connection to the service is lost or re-established.
+    pub fn on_service_event(&mut self, listener: EventListener) {
+        self.dispatcher.on_event(listener);
+    }
+
+    // the service answers requests in the order they were made
+    fn route(tpe: u16, _body: &[u8]) -> Route {
+        match tpe {
+            ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO     => Route::Oldest,
+            ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO_END => Route::OldestLast,
+            _ => Route::Unsolicited,
+        }
+    }
+
+    /// Returns a promise to a vector of all the peers known to the service.
+    ///
+    /// If `deadline` passes before the whole list has been received the promise is rejected with
+    /// `PeerInfoError::TimedOut`. Dropping the promise cancels the request.
+    pub fn get_peers_vec(&mut self, deadline: Option<Deadline>)
+                         -> Promise<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+        let id = self.dispatcher.next_id();
+        self.list(id, &ListAllPeersMessage { include_friend_only: false }, deadline)
+    }
+
+    /// Returns a promise to the peer with the identity `peer`, or `None` if the service does not
+    /// know about it. `deadline` is handled as by `get_peers_vec`.
+    pub fn get_peer(&mut self, peer: &PeerIdentity, deadline: Option<Deadline>)
+                    -> Promise<Option<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+        let id = self.dispatcher.next_id();
+        self.list(id, &ListPeerMessage { include_friend_only: false, peer: *peer }, deadline)
+            .map(|mut vec| {
+                match vec.len() {
+                    0 | 1 => Ok(vec.pop()),
+                    _     => Err(PeerInfoError::InvalidResponse),
+                }
+            })
+    }
+
+    // collects the peers sent in reply to `msg` until the end-of-list message
+    fn list<M: Message>(&mut self, id: u32, msg: &M, deadline: Option<Deadline>)
+                        -> Promise<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+        let (promise, fulfiller) = Promise::<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError>::and_fulfiller();
+        let mut state = Some((Vec::new(), fulfiller));
+        let handler: ReplyHandler = Box::new(move |res: Result<(u16, Cursor<Vec<u8>>), ReadMessageError>| {
+            let (mut peers, fulfiller) = match state.take() {
+                Some(state) => state,
+                None        => return ProcessMessageResult::Shutdown,
+            };
+            match res.map_err(PeerInfoError::from).and_then(|(tpe, mr)| parse_peer(tpe, mr)) {
+                Ok(Some(peer)) => {
+                    peers.push(peer);
+                    state = Some((peers, fulfiller));
+                    ProcessMessageResult::Continue
+                },
+                Ok(None) => {
+                    fulfiller.fulfill(peers);
+                    ProcessMessageResult::Shutdown
+                },
+                Err(e) => {
+                    fulfiller.reject(e);
+                    ProcessMessageResult::Shutdown
+                },
+            }
+        });
+        let sent = self.dispatcher.request_with(id, msg, deadline, handler)
+            .lift()
+            .then(move |()| promise);
+        self.dispatcher.cancel_on_drop(id, sent)
+    }
+}
+
 /// Parse some data in `mr` into a tuple of `PeerIdentity` and optionally a `Hello`.
-fn parse_peer(tpe: u16, mut mr: Cursor<Vec<u8>>) -> Result<Option<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+fn parse_peer(tpe: u16, mr: Cursor<Vec<u8>>) -> Result<Option<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
     match tpe {
-        ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO => match mr.read_u32::<BigEndian>() {
-            Err(e)  => match e.kind() {
-                io::ErrorKind::UnexpectedEof => Err(PeerInfoError::Disconnected),
-                _                            => Err(PeerInfoError::Io { cause: e }),
-            },
-            Ok(x)   => match x == 0 {
-                false => Err(PeerInfoError::InvalidResponse),
-                true  => match PeerIdentity::deserialize(&mut mr) {
-                    Err(e)  => Err(PeerInfoError::Io { cause: e }),
-                    Ok(pi)  => {
-                        Ok(Some((pi, None)))
-                        /*
-                            * when we have hello parsing
-                            match mr.eof() {
-                            true  => Some(Ok(pi, None)),
-                            false => {
-
-                    },
-                    }
-                            */
-                    },
-                },
-            },
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO => {
+            let msg: InfoMessage = try!(message::decode(tpe, mr));
+            Ok(Some((msg.peer, msg.hello)))
         },
         ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO_END => Ok(None),
         x => Err(PeerInfoError::UnexpectedMessageType { ty: x }),
@@ -302,60 +427,183 @@ impl fmt::Display for PeerIdentity {
     }
 }
 
-/// Packed struct representing GNUNET_PEERINFO_ListAllPeersMessage.
-#[repr(C, packed)]
-struct ListAllPeersMessage {
-    header: MessageHeader,
-    include_friend_only: u32,
+/// GNUNET_PEERINFO_ListAllPeersMessage, asks the service for all the peers it knows about.
+pub struct ListAllPeersMessage {
+    /// Also list the HELLOs of peers which are only to be shared with friends.
+    pub include_friend_only: bool,
 }
 
-impl ListAllPeersMessage {
-    fn new(include_friend_only: u32) -> ListAllPeersMessage {
-        let len = size_of::<ListAllPeersMessage>();
-        use std::u16::MAX;
-        assert!(len >= 4 && len <= (MAX as usize));
+impl Message for ListAllPeersMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_GET_ALL
+    }
 
-        ListAllPeersMessage {
-            header: MessageHeader {
-                len: (len as u16).to_be(),
-                tpe: ll::GNUNET_MESSAGE_TYPE_PEERINFO_GET_ALL.to_be(),
-            },
-            include_friend_only: include_friend_only.to_be(),
-        }
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        Ok(try!(w.write_u32::<BigEndian>(self.include_friend_only as u32)))
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<ListAllPeersMessage, MessageError> {
+        Ok(ListAllPeersMessage {
+            include_friend_only: try!(r.read_u32::<BigEndian>()) != 0,
+        })
     }
 }
 
+/// GNUNET_PEERINFO_ListPeerMessage, asks the service for a single peer.
+pub struct ListPeerMessage {
+    /// Also send the HELLO if it is only to be shared with friends.
+    pub include_friend_only: bool,
+    /// The peer wanted.
+    pub peer: PeerIdentity,
+}
+
+impl Message for ListPeerMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_GET
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(self.include_friend_only as u32));
+        Ok(try!(self.peer.serialize(w)))
+    }
 
-impl MessageTrait for ListAllPeersMessage {
-    fn into_slice(&self) -> &[u8] {
-        message_to_slice!(ListAllPeersMessage, self)
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<ListPeerMessage, MessageError> {
+        let include_friend_only = try!(r.read_u32::<BigEndian>()) != 0;
+        let peer = try!(PeerIdentity::deserialize(r));
+        Ok(ListPeerMessage {
+            include_friend_only: include_friend_only,
+            peer: peer,
+        })
     }
 }
 
-/// Packed struct representing GNUNET_PEERINFO_ListPeerMessage.
-#[repr(C, packed)]
-struct ListPeerMessage {
-    header: MessageHeader,
-    include_friend_only: u32,
-    peer: ll::Struct_GNUNET_PeerIdentity,
+/// GNUNET_PEERINFO_InfoMessage, sent by the service for every peer matching a request. It may
+/// be followed by the peer's HELLO message.
+pub struct InfoMessage {
+    /// The identity of the peer.
+    pub peer: PeerIdentity,
+    /// The HELLO of the peer, if the service has one.
+    pub hello: Option<Hello>,
 }
 
-impl ListPeerMessage {
-    fn new(include_friend_only: u32, peer: ll::Struct_GNUNET_PeerIdentity) -> ListPeerMessage {
-        let len = size_of::<ListPeerMessage>();
-        ListPeerMessage {
-            header: MessageHeader {
-                len: (len as u16).to_be(),
-                tpe: ll::GNUNET_MESSAGE_TYPE_PEERINFO_GET.to_be(),
+impl Message for InfoMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(0)); // reserved
+        try!(self.peer.serialize(w));
+        if let Some(ref hello) = self.hello {
+            try!(w.write_all(&try!(message::encode(hello))[..]));
+        }
+        Ok(())
+    }
+
+    fn read_body<R: Read>(r: &mut R, len: usize) -> Result<InfoMessage, MessageError> {
+        let tpe = InfoMessage::message_type();
+        if try!(r.read_u32::<BigEndian>()) != 0 {
+            return Err(MessageError::InvalidField { tpe: tpe, field: "reserved" });
+        }
+        let peer = try!(PeerIdentity::deserialize(r));
+        let hello = match len - 4 - 32 {
+            0 => None,
+            rem => {
+                let hello_len = try!(r.read_u16::<BigEndian>()) as usize;
+                let hello_tpe = try!(r.read_u16::<BigEndian>());
+                if hello_len != rem || hello_len < HEADER_SIZE {
+                    return Err(MessageError::InvalidField { tpe: tpe, field: "hello" });
+                }
+                if hello_tpe != Hello::message_type() {
+                    return Err(MessageError::UnexpectedType { expected: Hello::message_type(), received: hello_tpe });
+                }
+                Some(try!(Hello::read_body(r, hello_len - HEADER_SIZE)))
             },
-            include_friend_only: include_friend_only.to_be(),
+        };
+        Ok(InfoMessage {
             peer: peer,
-        }
+            hello: hello,
+        })
+    }
+}
+
+/// Sent by the service after the last `InfoMessage` answering a request.
+pub struct InfoEndMessage;
+
+impl Message for InfoEndMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO_END
+    }
+
+    fn write_body<W: Write>(&self, _w: &mut W) -> Result<(), MessageError> {
+        Ok(())
+    }
+
+    fn read_body<R: Read>(_r: &mut R, _len: usize) -> Result<InfoEndMessage, MessageError> {
+        Ok(InfoEndMessage)
     }
 }
 
-impl MessageTrait for ListPeerMessage {
-    fn into_slice(&self) -> &[u8] {
-        message_to_slice!(ListPeerMessage, self)
+#[test]
+fn test_info_message_hello() {
+    let peer = PeerIdentity::deserialize(&mut &[1u8; 32][..]).unwrap();
+    let hello = Hello { friend_only: true, id: peer, addresses: vec![1, 2, 3] };
+    for hello in vec![None, Some(hello)] {
+        let buf = message::encode(&InfoMessage { peer: peer, hello: hello }).unwrap();
+        let mut mr = Cursor::new(buf);
+        mr.set_position(HEADER_SIZE as u64);
+        let msg: InfoMessage = message::decode(InfoMessage::message_type(), mr).unwrap();
+        assert_eq!(msg.peer, peer);
+        if let Some(hello) = msg.hello {
+            assert!(hello.friend_only);
+            assert_eq!(hello.id, peer);
+            assert_eq!(hello.addresses, vec![1, 2, 3]);
+        }
+    }
+
+    // a HELLO whose length disagrees with the rest of the message is rejected
+    let mut buf = message::encode(&InfoMessage {
+        peer: peer,
+        hello: Some(Hello { friend_only: false, id: peer, addresses: Vec::new() }),
+    }).unwrap();
+    buf.push(0);
+    let len = buf.len() as u16;
+    buf[0] = (len >> 8) as u8;
+    buf[1] = len as u8;
+    let mut mr = Cursor::new(buf);
+    mr.set_position(HEADER_SIZE as u64);
+    match message::decode::<InfoMessage>(InfoMessage::message_type(), mr) {
+        Err(MessageError::InvalidField { field: "hello", .. }) => (),
+        _ => panic!("expected the HELLO to be rejected"),
     }
 }
+
+#[test]
+fn test_peerinfo_service() {
+    use testing::{self, TestPeer};
+
+    let alice = PeerIdentity::deserialize(&mut &[1u8; 32][..]).unwrap();
+    let bob = PeerIdentity::deserialize(&mut &[2u8; 32][..]).unwrap();
+    let carol = PeerIdentity::deserialize(&mut &[3u8; 32][..]).unwrap();
+    let hello = Hello { friend_only: false, id: alice, addresses: vec![0xaa; 8] };
+    let service = testing::peerinfo(vec![(alice, Some(hello)), (bob, None)]);
+
+    TestPeer::with_service(service, PeerInfoService::connect, |mut pis, t| {
+        let peers = t.wait(pis.get_peers_vec(None)).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].0, alice);
+        let hello = peers[0].1.as_ref().expect("alice has a HELLO");
+        assert_eq!(hello.id, alice);
+        assert_eq!(hello.addresses, vec![0xaa; 8]);
+        assert_eq!(peers[1].0, bob);
+        assert!(peers[1].1.is_none());
+
+        // both requests are answered in order on the same connection
+        let known = pis.get_peer(&bob, None);
+        let unknown = pis.get_peer(&carol, None);
+        let (id, hello) = t.wait(known).unwrap().expect("bob is known");
+        assert_eq!(id, bob);
+        assert!(hello.is_none());
+        assert!(t.wait(unknown).unwrap().is_none());
+    });
+}
@@ -17,6 +17,8 @@ pub const GNUNET_MESSAGE_TYPE_PEERINFO_INFO: u16 = 332;
 pub const GNUNET_MESSAGE_TYPE_PEERINFO_INFO_END: u16 = 333;
 pub const GNUNET_MESSAGE_TYPE_GNS_LOOKUP: u16 = 500;
 pub const GNUNET_MESSAGE_TYPE_GNS_LOOKUP_RESULT: u16 = 501;
+pub const GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP: u16 = 503;
+pub const GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP_RESULT: u16 = 504;
 pub const GNUNET_MESSAGE_TYPE_IDENTITY_START: u16 = 624;
 pub const GNUNET_MESSAGE_TYPE_IDENTITY_RESULT_CODE: u16 = 625;
 pub const GNUNET_MESSAGE_TYPE_IDENTITY_UPDATE: u16 = 626;
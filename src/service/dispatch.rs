@@ -0,0 +1,979 @@
+//! Multiplexing of requests over a single service connection.
+//!
+//! A `Dispatcher` owns the only read loop of a connection. Every message it reads is routed either
+//! to the request it answers or, if it is not an answer to anything, to the subscribers for its
+//! message type. This lets a client have any number of requests in flight on one socket.
+//!
+//! A dispatcher can also be told how to reconnect to its service. When the connection breaks it
+//! then retries with exponential backoff and, once connected again, re-sends the requests that
+//! were still waiting for replies.
+//!
+//! Requests can be given a `Deadline` and can be cancelled. Either way the request is removed
+//! from the dispatcher, its handler is called with `ReadMessageError::TimedOut` or
+//! `ReadMessageError::Cancelled` and, if one was registered with `stop_with`, a stop message is
+//! sent to tell the service to stop working on it. None of the GNS, identity and peerinfo
+//! protocols have such a message, for those cancelling is purely local.
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Cursor};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use gj::{Promise, PromiseFulfiller, TaskSet, TaskReaper};
+use gjio::{Network, Timer};
+
+use configuration::Cfg;
+use time;
+use service::{self, ServiceReader, ServiceWriter, ReadMessageError, ProcessMessageResult, ConnectError};
+use service::message::{self, CopyError, Message, MessageError};
+
+/// Where an incoming message should be delivered. Returned by a connection's `Router`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// The message is a reply to the pending request with the given id.
+    Reply(u32),
+    /// The message is a reply to the oldest pending request. Used for services which answer
+    /// requests in order without echoing back a request id.
+    Oldest,
+    /// Like `Oldest`, but the message is known to be the last reply to the request.
+    OldestLast,
+    /// The message is not a reply to any request and is passed to the subscribers for its type.
+    Unsolicited,
+}
+
+/// Decides the `Route` of an incoming message given its type and body.
+pub type Router = Box<Fn(u16, &[u8]) -> Route>;
+
+/// Receives the replies to a request. It is called for each reply until it returns
+/// `ProcessMessageResult::Shutdown`, or once with an error if the request fails. Returning
+/// `ProcessMessageResult::Reconnect` ends the request and drops the connection.
+pub type ReplyHandler = Box<FnMut(Result<(u16, Cursor<Vec<u8>>), ReadMessageError>) -> ProcessMessageResult>;
+
+/// Receives the unsolicited messages of one type until it returns
+/// `ProcessMessageResult::Shutdown`.
+pub type Subscriber = Box<FnMut(u16, Cursor<Vec<u8>>) -> ProcessMessageResult>;
+
+/// Opens a new connection to a service. Used by a `Dispatcher` to reconnect.
+pub type Connector = Box<Fn() -> Promise<(ServiceReader, ServiceWriter), ConnectError>>;
+
+/// Receives the `ServiceEvent`s of a dispatcher.
+pub type EventListener = Box<FnMut(&ServiceEvent)>;
+
+/// Returns a `Connector` which connects to the service `name` as configured in `cfg`.
+pub fn connector(cfg: &Cfg, name: &str, network: &Network) -> Connector {
+    let cfg = cfg.clone();
+    let name = name.to_string();
+    let network = network.clone();
+    Box::new(move || service::connect(&cfg, &name, &network))
+}
+
+/// Describes how a `Dispatcher` reconnects after losing its connection.
+pub struct ReconnectPolicy {
+    /// The timer used to wait between attempts.
+    pub timer: Timer,
+    /// How long to wait before the first attempt. The delay doubles after every failed attempt.
+    pub initial_delay: Duration,
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+    /// Give up after this many failed attempts. `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// Whether requests which were sent but not answered before the disconnect are sent again
+    /// after reconnecting. If `false` they fail with `ReadMessageError::Disconnected`.
+    pub reissue: bool,
+}
+
+impl ReconnectPolicy {
+    /// The default policy: start at 100ms, back off up to one minute, never give up and re-issue
+    /// requests which were in flight.
+    pub fn new(timer: Timer) -> ReconnectPolicy {
+        ReconnectPolicy {
+            timer: timer,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+            reissue: true,
+        }
+    }
+}
+
+/// How long a request may wait for its replies. Once the deadline passes the request fails with
+/// `ReadMessageError::TimedOut`.
+#[derive(Clone)]
+pub struct Deadline {
+    timer: Timer,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// A deadline `timeout` from the time the request is made, measured with `timer`. Returns
+    /// `None`, ie. no deadline, if `timeout` is `Relative::forever()`.
+    pub fn new(timer: &Timer, timeout: time::Relative) -> Option<Deadline> {
+        if timeout.is_forever() {
+            return None;
+        }
+        Some(Deadline {
+            timer: timer.clone(),
+            timeout: Duration::from(timeout),
+        })
+    }
+
+    /// The timer the deadline is measured with.
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// How long after the request is made the deadline passes.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Changes in the state of a dispatcher's connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServiceEvent {
+    /// The connection to the service was lost, eg. because it was restarted.
+    Disconnected,
+    /// Attempt number `attempt` to reconnect will be made after `delay`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The connection was re-established after `attempts` attempts.
+    Reconnected { attempts: u32 },
+    /// Reconnecting failed too many times. All pending and future requests fail.
+    GaveUp,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Connected,
+    Reconnecting,
+    Failed,
+}
+
+struct Pending {
+    handler: ReplyHandler,
+    // the encoded request, kept so it can be sent again after reconnecting
+    request: Vec<u8>,
+    sent: bool,
+    // sent to the service if the request is cancelled after being sent
+    stop: Option<Vec<u8>>,
+    // the request was cancelled but the service is still going to answer it, see `Inner::cancel`
+    cancelled: bool,
+    // dropping it stops the deadline timer of the request
+    expire: Option<PromiseFulfiller<(), ReadMessageError>>,
+}
+
+struct Inner {
+    router: Router,
+    writer: ServiceWriter,
+    state: State,
+    next_id: u32,
+    pending: HashMap<u32, Pending>,
+    // ids of the pending requests, oldest first
+    order: VecDeque<u32>,
+    subscribers: HashMap<u16, Vec<Subscriber>>,
+    listeners: Vec<EventListener>,
+    reconnect: Option<(Connector, ReconnectPolicy)>,
+    // messages which have to be sent first on every new connection
+    handshake: Vec<Vec<u8>>,
+    // set by handlers which return `ProcessMessageResult::Reconnect`
+    force_reconnect: bool,
+    // the service answers requests in order, see `Dispatcher::replies_in_order`
+    in_order: bool,
+    // deadline timers and stop messages being written
+    tasks: TaskSet<(), ()>,
+}
+
+struct Reaper;
+
+impl TaskReaper<(), ()> for Reaper {
+    fn task_failed(&mut self, _: ()) {}
+}
+
+// cancels a request when the promise of its reply is dropped
+struct CancelOnDrop {
+    inner: Weak<RefCell<Inner>>,
+    id: u32,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            Inner::cancel(&inner, self.id, ReadMessageError::Cancelled);
+        }
+    }
+}
+
+/// Routes the messages received on a connection to the requests and subscribers waiting for them.
+///
+/// Dropping the dispatcher stops the read loop. Requests still pending at that time fail with
+/// `ReadMessageError::FulfillerDropped`.
+pub struct Dispatcher {
+    inner: Rc<RefCell<Inner>>,
+    _read_loop: Promise<(), ()>,
+}
+
+impl Dispatcher {
+    /// Start dispatching the messages read from `reader`. `router` decides where every message
+    /// goes and `writer` is used to send requests.
+    pub fn new(reader: ServiceReader, writer: ServiceWriter, router: Router) -> Dispatcher {
+        let inner = Rc::new(RefCell::new(Inner {
+            router: router,
+            writer: writer,
+            state: State::Connected,
+            next_id: 0,
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+            subscribers: HashMap::new(),
+            listeners: Vec::new(),
+            reconnect: None,
+            handshake: Vec::new(),
+            force_reconnect: false,
+            in_order: false,
+            tasks: TaskSet::new(Box::new(Reaper)),
+        }));
+        let read_loop = Dispatcher::read_loop(reader, inner.clone()).eagerly_evaluate();
+        Dispatcher {
+            inner: inner,
+            _read_loop: read_loop,
+        }
+    }
+
+    /// Reconnect using `connector` according to `policy` whenever the connection is lost.
+    pub fn enable_reconnect(&mut self, connector: Connector, policy: ReconnectPolicy) {
+        self.inner.borrow_mut().reconnect = Some((connector, policy));
+    }
+
+    /// Send `msg` first on every new connection, before re-issuing any requests. Used for the
+    /// start messages some services expect. It is not sent on the current connection.
+    pub fn add_handshake<M: Message>(&mut self, msg: &M) -> Result<(), MessageError> {
+        let buf = try!(message::encode(msg));
+        self.inner.borrow_mut().handshake.push(buf);
+        Ok(())
+    }
+
+    /// Declare that the service answers requests in the order they were made, as is the case for
+    /// services whose `Router` returns `Route::Oldest`.
+    ///
+    /// A request cancelled after being sent to such a service keeps its place in the queue until
+    /// its last reply (`Route::OldestLast`) arrives, so that replies are not delivered to the
+    /// wrong request. Its handler is not called again.
+    pub fn replies_in_order(&mut self) {
+        self.inner.borrow_mut().in_order = true;
+    }
+
+    /// Pass every `ServiceEvent` to `listener`.
+    pub fn on_event(&mut self, listener: EventListener) {
+        self.inner.borrow_mut().listeners.push(listener);
+    }
+
+    /// Allocate an unused request id.
+    pub fn next_id(&mut self) -> u32 {
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.next_id;
+        inner.next_id = inner.next_id.wrapping_add(1);
+        id
+    }
+
+    /// Send a message that does not expect a reply.
+    pub fn send<M: Message>(&mut self, msg: &M) -> Promise<(), MessageError> {
+        let mut inner = self.inner.borrow_mut();
+        let sent = match inner.state {
+            State::Connected => inner.writer.send(msg),
+            _ => Promise::err(MessageError::Io {
+                cause: io::Error::new(io::ErrorKind::NotConnected, "not connected to the service"),
+            }),
+        };
+        sent
+    }
+
+    /// Send the request `msg` with id `id` and pass every reply routed to it to `handler`.
+    ///
+    /// The returned promise resolves once the request has been sent, or queued to be sent once
+    /// the dispatcher has reconnected. If `deadline` passes before the handler has returned
+    /// `ProcessMessageResult::Shutdown` the request is cancelled with `ReadMessageError::TimedOut`.
+    pub fn request_with<M: Message>(&mut self,
+                                    id: u32,
+                                    msg: &M,
+                                    deadline: Option<Deadline>,
+                                    mut handler: ReplyHandler)
+                                    -> Promise<(), MessageError> {
+        let buf = pry!(message::encode(msg));
+        let write = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.state == State::Failed {
+                drop(inner);
+                handler(Err(ReadMessageError::Disconnected));
+                return Promise::ok(());
+            }
+            let connected = inner.state == State::Connected;
+            let expire = deadline.map(|deadline| {
+                let (stopped, stop) = Promise::<(), ReadMessageError>::and_fulfiller();
+                let weak = Rc::downgrade(&self.inner);
+                let expire = deadline.timer.after_delay(deadline.timeout)
+                    .map_else(|res| Ok(res.is_ok()))
+                    .exclusive_join(stopped.map_else(|_| Ok(false)))
+                    .map(move |expired| {
+                        if let (true, Some(inner)) = (expired, weak.upgrade()) {
+                            Inner::cancel(&inner, id, ReadMessageError::TimedOut);
+                        }
+                        Ok(())
+                    });
+                inner.tasks.add(expire);
+                stop
+            });
+            inner.pending.insert(id, Pending {
+                handler: handler,
+                request: buf.clone(),
+                sent: connected,
+                stop: None,
+                cancelled: false,
+                expire: expire,
+            });
+            inner.order.push_back(id);
+            if connected { Some(inner.writer.write(buf)) } else { None }
+        };
+        match write {
+            Some(write) => {
+                let inner = self.inner.clone();
+                write.map_else(move |res| {
+                    match res {
+                        Ok(_)  => Ok(()),
+                        Err(e) => {
+                            Inner::remove(&inner, id);
+                            Err(MessageError::Io { cause: e })
+                        },
+                    }
+                })
+            },
+            None => Promise::ok(()),
+        }
+    }
+
+    /// Send the request `msg` with id `id` and return a promise of the single reply routed to it.
+    ///
+    /// Dropping the returned promise cancels the request.
+    pub fn request<M, E>(&mut self, id: u32, msg: &M, deadline: Option<Deadline>)
+                         -> Promise<(u16, Cursor<Vec<u8>>), E>
+        where M: Message,
+              E: From<MessageError> + From<ReadMessageError> + 'static
+    {
+        let (reply, fulfiller) = Promise::and_fulfiller();
+        let mut fulfiller = Some(fulfiller);
+        let handler: ReplyHandler = Box::new(move |res: Result<(u16, Cursor<Vec<u8>>), ReadMessageError>| {
+            if let Some(f) = fulfiller.take() {
+                f.resolve(res);
+            }
+            ProcessMessageResult::Shutdown
+        });
+        let sent = self.request_with(id, msg, deadline, handler)
+            .lift()
+            .then(move |()| reply.lift());
+        self.cancel_on_drop(id, sent)
+    }
+
+    /// Cancel the request `id` if `promise` is dropped before it resolves. Used to tie a request
+    /// made with `request_with` to the promise of its result.
+    pub fn cancel_on_drop<T, E>(&self, id: u32, promise: Promise<T, E>) -> Promise<T, E>
+        where T: 'static,
+              E: 'static
+    {
+        let guard = CancelOnDrop {
+            inner: Rc::downgrade(&self.inner),
+            id: id,
+        };
+        promise.map(move |value| {
+            drop(guard);
+            Ok(value)
+        })
+    }
+
+    /// Send `msg` to the service if the pending request `id` is cancelled or times out after it
+    /// has been sent.
+    pub fn stop_with<M: Message>(&mut self, id: u32, msg: &M) -> Result<(), MessageError> {
+        let buf = try!(message::encode(msg));
+        if let Some(pending) = self.inner.borrow_mut().pending.get_mut(&id) {
+            pending.stop = Some(buf);
+        }
+        Ok(())
+    }
+
+    /// Cancel the pending request `id`. Its handler is called with `ReadMessageError::Cancelled`.
+    /// Returns `false` if there is no such request.
+    pub fn cancel(&mut self, id: u32) -> bool {
+        Inner::cancel(&self.inner, id, ReadMessageError::Cancelled)
+    }
+
+    /// Cancel all pending requests.
+    pub fn cancel_all(&mut self) {
+        let ids: Vec<u32> = self.inner.borrow().order.iter().cloned().collect();
+        for id in ids {
+            Inner::cancel(&self.inner, id, ReadMessageError::Cancelled);
+        }
+    }
+
+    /// Pass every unsolicited message of type `tpe` to `subscriber`.
+    pub fn subscribe(&mut self, tpe: u16, subscriber: Subscriber) {
+        let mut inner = self.inner.borrow_mut();
+        inner.subscribers.entry(tpe).or_insert_with(Vec::new).push(subscriber);
+    }
+
+    /// The number of requests still waiting for replies.
+    pub fn pending(&self) -> usize {
+        self.inner.borrow().pending.values().filter(|p| !p.cancelled).count()
+    }
+
+    fn read_loop(mut reader: ServiceReader, inner: Rc<RefCell<Inner>>) -> Promise<(), ()> {
+        reader.read_message().then_else(move |res| {
+            let e = match res {
+                Ok((tpe, mr)) => {
+                    Inner::dispatch(&inner, tpe, mr);
+                    if !inner.borrow().force_reconnect {
+                        return Dispatcher::read_loop(reader, inner);
+                    }
+                    inner.borrow_mut().force_reconnect = false;
+                    ReadMessageError::Disconnected
+                },
+                Err(e) => e,
+            };
+            let delay = inner.borrow().reconnect.as_ref().map(|&(_, ref policy)| policy.initial_delay);
+            let delay = match delay {
+                Some(delay) => delay,
+                None        => {
+                    Inner::fail_all(&inner, e);
+                    return Promise::ok(());
+                },
+            };
+            Inner::disconnected(&inner, e);
+            let inner2 = inner.clone();
+            Dispatcher::reconnect(inner, 1, delay)
+                .then(move |reader| Dispatcher::read_loop(reader, inner2))
+        })
+    }
+
+    fn reconnect(inner: Rc<RefCell<Inner>>, attempt: u32, delay: Duration) -> Promise<ServiceReader, ()> {
+        Inner::emit(&inner, ServiceEvent::Reconnecting { attempt: attempt, delay: delay });
+        let wait = match inner.borrow().reconnect {
+            Some((_, ref policy)) => policy.timer.after_delay(delay),
+            None                  => return Promise::err(()),
+        };
+        let inner2 = inner.clone();
+        wait.lift()
+            .then(move |()| {
+                let inner = inner2.borrow();
+                let connect = match inner.reconnect {
+                    Some((ref connector, _)) => connector(),
+                    None                     => Promise::err(ConnectError::Io {
+                        cause: io::Error::new(io::ErrorKind::NotConnected, "reconnecting was disabled"),
+                    }),
+                };
+                connect
+            })
+            .then_else(move |res| {
+                match res {
+                    Ok((sr, sw)) => Inner::reconnected(&inner, sr, sw, attempt),
+                    Err(_) => {
+                        let next = match inner.borrow().reconnect {
+                            Some((_, ref policy)) => match policy.max_attempts {
+                                Some(max) if attempt >= max => None,
+                                _ => Some(min(delay * 2, policy.max_delay)),
+                            },
+                            None => None,
+                        };
+                        match next {
+                            Some(delay) => Dispatcher::reconnect(inner, attempt + 1, delay),
+                            None        => {
+                                Inner::fail_all(&inner, ReadMessageError::Disconnected);
+                                Inner::emit(&inner, ServiceEvent::GaveUp);
+                                Promise::err(())
+                            },
+                        }
+                    },
+                }
+            })
+    }
+}
+
+impl Inner {
+    fn dispatch(inner: &Rc<RefCell<Inner>>, tpe: u16, mr: Cursor<Vec<u8>>) {
+        let route = {
+            let inner = inner.borrow();
+            let body = &mr.get_ref()[mr.position() as usize..];
+            (inner.router)(tpe, body)
+        };
+        let (id, last) = match route {
+            Route::Reply(id)   => (Some(id), true),
+            Route::Oldest      => (inner.borrow().order.front().cloned(), false),
+            Route::OldestLast  => (inner.borrow().order.front().cloned(), true),
+            Route::Unsolicited => (None, false),
+        };
+        match id {
+            Some(id) => Inner::reply(inner, id, last, tpe, mr),
+            None     => Inner::notify(inner, tpe, mr),
+        }
+    }
+
+    fn reply(inner: &Rc<RefCell<Inner>>, id: u32, last: bool, tpe: u16, mr: Cursor<Vec<u8>>) {
+        // the handler is called without holding the borrow so that it can use the dispatcher
+        let pending = inner.borrow_mut().pending.remove(&id);
+        if let Some(mut pending) = pending {
+            if pending.cancelled {
+                if last {
+                    Inner::remove(inner, id);
+                }
+                else {
+                    inner.borrow_mut().pending.insert(id, pending);
+                }
+                return;
+            }
+            match (pending.handler)(Ok((tpe, mr))) {
+                ProcessMessageResult::Continue => {
+                    inner.borrow_mut().pending.insert(id, pending);
+                },
+                ProcessMessageResult::Reconnect => {
+                    Inner::remove(inner, id);
+                    inner.borrow_mut().force_reconnect = true;
+                },
+                ProcessMessageResult::Shutdown => Inner::remove(inner, id),
+            }
+        }
+    }
+
+    fn notify(inner: &Rc<RefCell<Inner>>, tpe: u16, mr: Cursor<Vec<u8>>) {
+        let subscribers = inner.borrow_mut().subscribers.remove(&tpe);
+        if let Some(subscribers) = subscribers {
+            let mut keep = Vec::with_capacity(subscribers.len());
+            for mut subscriber in subscribers.into_iter() {
+                match subscriber(tpe, mr.clone()) {
+                    ProcessMessageResult::Continue  => keep.push(subscriber),
+                    ProcessMessageResult::Reconnect => {
+                        keep.push(subscriber);
+                        inner.borrow_mut().force_reconnect = true;
+                    },
+                    ProcessMessageResult::Shutdown  => (),
+                }
+            }
+            let mut inner = inner.borrow_mut();
+            let entry = inner.subscribers.entry(tpe).or_insert_with(Vec::new);
+            // subscribers added while we were notifying go after the existing ones
+            keep.extend(entry.drain(..));
+            *entry = keep;
+        }
+    }
+
+    fn emit(inner: &Rc<RefCell<Inner>>, event: ServiceEvent) {
+        let mut listeners = ::std::mem::replace(&mut inner.borrow_mut().listeners, Vec::new());
+        for listener in listeners.iter_mut() {
+            listener(&event);
+        }
+        let mut inner = inner.borrow_mut();
+        listeners.extend(inner.listeners.drain(..));
+        inner.listeners = listeners;
+    }
+
+    // fail the pending request `id` with `e`, returns `false` if there is no such request
+    fn cancel(inner: &Rc<RefCell<Inner>>, id: u32, e: ReadMessageError) -> bool {
+        let handler = {
+            let mut inner = inner.borrow_mut();
+            match inner.pending.get(&id) {
+                Some(pending) if !pending.cancelled => (),
+                _ => return false,
+            }
+            let mut pending = inner.pending.remove(&id).unwrap();
+            let sent = pending.sent && inner.state == State::Connected;
+            if sent && inner.in_order {
+                // the service is still going to answer, keep the request's place in the queue
+                inner.pending.insert(id, Pending {
+                    handler: Box::new(|_| ProcessMessageResult::Continue),
+                    request: Vec::new(),
+                    sent: true,
+                    stop: None,
+                    cancelled: true,
+                    expire: None,
+                });
+            }
+            else {
+                inner.order.retain(|&x| x != id);
+            }
+            if let (true, Some(stop)) = (sent, pending.stop.take()) {
+                // if the write fails so will the next read, which is handled by the read loop
+                let write = inner.writer.write(stop).then_else(|_| Promise::ok(()));
+                inner.tasks.add(write);
+            }
+            pending.handler
+        };
+        let mut handler = handler;
+        handler(Err(e));
+        true
+    }
+
+    fn remove(inner: &Rc<RefCell<Inner>>, id: u32) {
+        let mut inner = inner.borrow_mut();
+        inner.pending.remove(&id);
+        inner.order.retain(|&x| x != id);
+    }
+
+    // the connection broke and we are about to reconnect
+    fn disconnected(inner: &Rc<RefCell<Inner>>, e: ReadMessageError) {
+        let failed: Vec<ReplyHandler> = {
+            let mut inner = inner.borrow_mut();
+            inner.state = State::Reconnecting;
+            let reissue = match inner.reconnect {
+                Some((_, ref policy)) => policy.reissue,
+                None                  => false,
+            };
+            let ids: Vec<u32> = inner.order.iter().cloned().collect();
+            let mut failed = Vec::new();
+            for id in ids {
+                let in_flight = inner.pending[&id].sent;
+                if inner.pending[&id].cancelled {
+                    // the answers to cancelled requests will never come now
+                    inner.pending.remove(&id);
+                    inner.order.retain(|&x| x != id);
+                }
+                else if in_flight && !reissue {
+                    failed.push(inner.pending.remove(&id).unwrap().handler);
+                    inner.order.retain(|&x| x != id);
+                }
+                else {
+                    inner.pending.get_mut(&id).unwrap().sent = false;
+                }
+            }
+            failed
+        };
+        for mut handler in failed.into_iter() {
+            handler(Err(e.copy_error()));
+        }
+        Inner::emit(inner, ServiceEvent::Disconnected);
+    }
+
+    // install a new connection, then send the handshake followed by all the pending requests
+    fn reconnected(inner: &Rc<RefCell<Inner>>, reader: ServiceReader, writer: ServiceWriter, attempts: u32)
+                   -> Promise<ServiceReader, ()> {
+        let write = {
+            let mut inner = inner.borrow_mut();
+            let mut buf: Vec<u8> = Vec::new();
+            for msg in inner.handshake.iter() {
+                buf.extend_from_slice(&msg[..]);
+            }
+            let ids: Vec<u32> = inner.order.iter().cloned().collect();
+            for id in ids {
+                let pending = inner.pending.get_mut(&id).unwrap();
+                buf.extend_from_slice(&pending.request[..]);
+                pending.sent = true;
+            }
+            inner.writer = writer;
+            inner.state = State::Connected;
+            inner.writer.write(buf)
+        };
+        Inner::emit(inner, ServiceEvent::Reconnected { attempts: attempts });
+        // if the write fails, so will the next read, which starts another reconnect
+        write.then_else(move |_| Promise::ok(reader))
+    }
+
+    fn fail_all(inner: &Rc<RefCell<Inner>>, e: ReadMessageError) {
+        let pending: Vec<ReplyHandler> = {
+            let mut inner = inner.borrow_mut();
+            inner.state = State::Failed;
+            inner.order.clear();
+            inner.pending.drain().map(|(_, p)| p.handler).collect()
+        };
+        for mut handler in pending.into_iter() {
+            handler(Err(e.copy_error()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Read, Write};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+    use gj::{EventLoop, Promise};
+    use gjio::{EventPort, SocketStream};
+
+    use time;
+    use service::{ServiceReader, ServiceWriter, ReadMessageError, ProcessMessageResult, ConnectError};
+    use service::message::{self, Message, MessageError};
+    use super::*;
+
+    const ECHO_TYPE: u16 = 24;
+    const NOTICE_TYPE: u16 = 25;
+    const ECHO_END_TYPE: u16 = 26;
+
+    // a request and its reply, carrying the request id
+    struct Echo {
+        id: u32,
+    }
+
+    impl Message for Echo {
+        fn message_type() -> u16 {
+            ECHO_TYPE
+        }
+
+        fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+            Ok(try!(w.write_u32::<BigEndian>(self.id)))
+        }
+
+        fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<Echo, MessageError> {
+            Ok(Echo { id: try!(r.read_u32::<BigEndian>()) })
+        }
+    }
+
+    // an unsolicited message
+    struct Notice;
+
+    impl Message for Notice {
+        fn message_type() -> u16 {
+            NOTICE_TYPE
+        }
+
+        fn write_body<W: Write>(&self, _w: &mut W) -> Result<(), MessageError> {
+            Ok(())
+        }
+
+        fn read_body<R: Read>(_r: &mut R, _len: usize) -> Result<Notice, MessageError> {
+            Ok(Notice)
+        }
+    }
+
+    // the last reply to a request of a service answering in order
+    struct EchoEnd;
+
+    impl Message for EchoEnd {
+        fn message_type() -> u16 {
+            ECHO_END_TYPE
+        }
+
+        fn write_body<W: Write>(&self, _w: &mut W) -> Result<(), MessageError> {
+            Ok(())
+        }
+
+        fn read_body<R: Read>(_r: &mut R, _len: usize) -> Result<EchoEnd, MessageError> {
+            Ok(EchoEnd)
+        }
+    }
+
+    #[derive(Debug)]
+    enum TestError {
+        Message,
+        Read(ReadMessageError),
+    }
+
+    impl From<MessageError> for TestError {
+        fn from(_: MessageError) -> TestError {
+            TestError::Message
+        }
+    }
+
+    impl From<ReadMessageError> for TestError {
+        fn from(e: ReadMessageError) -> TestError {
+            TestError::Read(e)
+        }
+    }
+
+    // a dispatcher on `client` routing echoes by the id they carry
+    fn by_id(client: SocketStream) -> Dispatcher {
+        let router: Router = Box::new(|tpe, body| match tpe {
+            ECHO_TYPE => Route::Reply(BigEndian::read_u32(body)),
+            _         => Route::Unsolicited,
+        });
+        Dispatcher::new(ServiceReader::new(client.clone()), ServiceWriter::new(client), router)
+    }
+
+    // a dispatcher on `client` for a service answering in order
+    fn in_order(client: SocketStream) -> Dispatcher {
+        let router: Router = Box::new(|tpe, _| match tpe {
+            ECHO_TYPE     => Route::Oldest,
+            ECHO_END_TYPE => Route::OldestLast,
+            _             => Route::Unsolicited,
+        });
+        let mut dispatcher = Dispatcher::new(ServiceReader::new(client.clone()), ServiceWriter::new(client), router);
+        dispatcher.replies_in_order();
+        dispatcher
+    }
+
+    #[test]
+    fn test_dispatch_out_of_order() {
+        EventLoop::top_level(move |wait_scope| -> Result<(), io::Error> {
+            let mut event_port = EventPort::new().unwrap();
+            let network = event_port.get_network();
+            let (client, service) = network.new_socket_pair().unwrap();
+
+            let mut dispatcher = by_id(client);
+            let notices = Rc::new(RefCell::new(0));
+            let notices2 = notices.clone();
+            dispatcher.subscribe(NOTICE_TYPE, Box::new(move |_, _| {
+                *notices2.borrow_mut() += 1;
+                ProcessMessageResult::Continue
+            }));
+
+            let id0 = dispatcher.next_id();
+            let id1 = dispatcher.next_id();
+            let first = dispatcher.request::<_, TestError>(id0, &Echo { id: id0 }, None);
+            let second = dispatcher.request::<_, TestError>(id1, &Echo { id: id1 }, None);
+
+            // answer the requests in reverse order with an unsolicited message in between
+            let mut sw = ServiceWriter::new(service);
+            sw.send(&Echo { id: id1 }).wait(wait_scope, &mut event_port).unwrap();
+            sw.send(&Notice).wait(wait_scope, &mut event_port).unwrap();
+            sw.send(&Echo { id: id0 }).wait(wait_scope, &mut event_port).unwrap();
+
+            let (tpe, mr) = first.wait(wait_scope, &mut event_port).unwrap();
+            assert_eq!(message::decode::<Echo>(tpe, mr).unwrap().id, id0);
+            let (tpe, mr) = second.wait(wait_scope, &mut event_port).unwrap();
+            assert_eq!(message::decode::<Echo>(tpe, mr).unwrap().id, id1);
+            assert_eq!(*notices.borrow(), 1);
+            assert_eq!(dispatcher.pending(), 0);
+            Ok(())
+        }).expect("top level");
+    }
+
+    #[test]
+    fn test_dispatch_in_order() {
+        EventLoop::top_level(move |wait_scope| -> Result<(), io::Error> {
+            let mut event_port = EventPort::new().unwrap();
+            let network = event_port.get_network();
+            let (client, service) = network.new_socket_pair().unwrap();
+            let mut dispatcher = in_order(client);
+
+            // each request collects the ids of its replies until the end marker
+            let mut done = Vec::new();
+            for _ in 0..2 {
+                let id = dispatcher.next_id();
+                let (promise, fulfiller) = Promise::<Vec<u32>, ReadMessageError>::and_fulfiller();
+                let mut fulfiller = Some(fulfiller);
+                let mut ids = Vec::new();
+                let handler: ReplyHandler = Box::new(move |res: Result<(u16, Cursor<Vec<u8>>), ReadMessageError>| {
+                    match res {
+                        Ok((ECHO_TYPE, mr)) => {
+                            ids.push(message::decode::<Echo>(ECHO_TYPE, mr).unwrap().id);
+                            ProcessMessageResult::Continue
+                        },
+                        Ok(_) => {
+                            fulfiller.take().unwrap().fulfill(::std::mem::replace(&mut ids, Vec::new()));
+                            ProcessMessageResult::Shutdown
+                        },
+                        Err(e) => {
+                            fulfiller.take().unwrap().reject(e);
+                            ProcessMessageResult::Shutdown
+                        },
+                    }
+                });
+                dispatcher.request_with(id, &Echo { id: id }, None, handler).wait(wait_scope, &mut event_port).unwrap();
+                done.push(promise);
+            }
+
+            // the replies carry no request id, only their order tells them apart
+            let mut sw = ServiceWriter::new(service);
+            for &(id, tpe) in [(7, ECHO_TYPE), (8, ECHO_TYPE), (0, ECHO_END_TYPE), (9, ECHO_TYPE), (0, ECHO_END_TYPE)].iter() {
+                let send = match tpe {
+                    ECHO_TYPE => sw.send(&Echo { id: id }),
+                    _         => sw.send(&EchoEnd),
+                };
+                send.wait(wait_scope, &mut event_port).unwrap();
+            }
+
+            let second = done.pop().unwrap();
+            let first = done.pop().unwrap();
+            assert_eq!(first.wait(wait_scope, &mut event_port).unwrap(), vec![7, 8]);
+            assert_eq!(second.wait(wait_scope, &mut event_port).unwrap(), vec![9]);
+            assert_eq!(dispatcher.pending(), 0);
+            Ok(())
+        }).expect("top level");
+    }
+
+    #[test]
+    fn test_dispatch_reconnect() {
+        EventLoop::top_level(move |wait_scope| -> Result<(), io::Error> {
+            let mut event_port = EventPort::new().unwrap();
+            let network = event_port.get_network();
+            let (client, service) = network.new_socket_pair().unwrap();
+            let (client2, service2) = network.new_socket_pair().unwrap();
+
+            let mut dispatcher = by_id(client);
+            let next = RefCell::new(Some(client2));
+            dispatcher.enable_reconnect(Box::new(move || {
+                match next.borrow_mut().take() {
+                    Some(c) => Promise::ok((ServiceReader::new(c.clone()),
+                                            ServiceWriter::new(c))),
+                    None    => Promise::err(ConnectError::Io {
+                        cause: io::Error::new(io::ErrorKind::ConnectionRefused, "no more connections"),
+                    }),
+                }
+            }), ReconnectPolicy::new(event_port.get_timer()));
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let events2 = events.clone();
+            dispatcher.on_event(Box::new(move |e| events2.borrow_mut().push(e.clone())));
+
+            // the service goes away before answering
+            let id = dispatcher.next_id();
+            let reply = dispatcher.request::<_, TestError>(id, &Echo { id: id }, None);
+            let mut sr = ServiceReader::new(service);
+            sr.read_message().wait(wait_scope, &mut event_port).unwrap();
+            drop(sr);
+
+            // the request is sent again on the new connection
+            let mut sr = ServiceReader::new(service2.clone());
+            let (tpe, mr) = sr.read_message().wait(wait_scope, &mut event_port).unwrap();
+            assert_eq!(message::decode::<Echo>(tpe, mr).unwrap().id, id);
+            let mut sw = ServiceWriter::new(service2);
+            sw.send(&Echo { id: id }).wait(wait_scope, &mut event_port).unwrap();
+
+            let (tpe, mr) = reply.wait(wait_scope, &mut event_port).unwrap();
+            assert_eq!(message::decode::<Echo>(tpe, mr).unwrap().id, id);
+            assert_eq!(*events.borrow(), vec![
+                ServiceEvent::Disconnected,
+                ServiceEvent::Reconnecting { attempt: 1, delay: Duration::from_millis(100) },
+                ServiceEvent::Reconnected { attempts: 1 },
+            ]);
+            Ok(())
+        }).expect("top level");
+    }
+
+    #[test]
+    fn test_dispatch_timeout_and_cancel() {
+        EventLoop::top_level(move |wait_scope| -> Result<(), io::Error> {
+            let mut event_port = EventPort::new().unwrap();
+            let network = event_port.get_network();
+            let timer = event_port.get_timer();
+            let (client, service) = network.new_socket_pair().unwrap();
+
+            // a service answering in order, so cancelled requests must still swallow their replies
+            let mut dispatcher = in_order(client);
+
+            let ids: Vec<u32> = (0..4).map(|_| dispatcher.next_id()).collect();
+            assert!(Deadline::new(&timer, time::Relative::forever()).is_none());
+            let deadline = Deadline::new(&timer, time::Relative::from(Duration::from_millis(10)));
+            let timed_out = dispatcher.request::<_, TestError>(ids[0], &Echo { id: ids[0] }, deadline);
+            let cancelled = dispatcher.request::<_, TestError>(ids[1], &Echo { id: ids[1] }, None);
+            let dropped = dispatcher.request::<_, TestError>(ids[2], &Echo { id: ids[2] }, None);
+            let deadline = Deadline::new(&timer, time::Relative::from(Duration::from_secs(60)));
+            let answered = dispatcher.request::<_, TestError>(ids[3], &Echo { id: ids[3] }, deadline);
+
+            match timed_out.wait(wait_scope, &mut event_port) {
+                Err(TestError::Read(ReadMessageError::TimedOut)) => (),
+                r => panic!("request did not time out: {:?}", r.map(|_| ())),
+            }
+            assert!(dispatcher.cancel(ids[1]));
+            assert!(!dispatcher.cancel(ids[1]));
+            match cancelled.wait(wait_scope, &mut event_port) {
+                Err(TestError::Read(ReadMessageError::Cancelled)) => (),
+                r => panic!("request was not cancelled: {:?}", r.map(|_| ())),
+            }
+            drop(dropped);
+            assert_eq!(dispatcher.pending(), 1);
+
+            let mut sw = ServiceWriter::new(service);
+            for _ in ids.iter() {
+                sw.send(&EchoEnd).wait(wait_scope, &mut event_port).unwrap();
+            }
+            let (tpe, _) = answered.wait(wait_scope, &mut event_port).unwrap();
+            assert_eq!(tpe, ECHO_END_TYPE);
+            assert_eq!(dispatcher.pending(), 0);
+            Ok(())
+        }).expect("top level");
+    }
+}
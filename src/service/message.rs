@@ -0,0 +1,241 @@
+//! Typed encoding and decoding of the messages exchanged with GNUnet services.
+//!
+//! Every message starts with a header consisting of a big-endian `u16` length (which includes the
+//! header itself) followed by a big-endian `u16` message type. Types implementing `Message`
+//! describe how the rest of the message, the body, is laid out.
+
+use std::io::{self, Read, Write, Cursor};
+use std::u16;
+use byteorder::{BigEndian, WriteBytesExt};
+
+use util::{ReadCString, ReadCStringError, ReadCStringWithLenError};
+
+/// The size in bytes of the header which precedes every message.
+pub const HEADER_SIZE: usize = 4;
+
+/// The largest message (including the header) that can be sent to or received from a service.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Errors that can occur when encoding or decoding a message.
+error_def! MessageError {
+    Io { #[from] cause: io::Error }
+        => "There was an I/O error encoding or decoding the message" ("Specifically: {}", cause),
+    TooLong { tpe: u16, len: usize }
+        => "The encoded message is too long" ("Message of type {} would be {} bytes long.", tpe, len),
+    Truncated { tpe: u16 }
+        => "The message ended before all of its fields could be read" ("Message of type {} was truncated.", tpe),
+    TrailingData { tpe: u16, len: usize }
+        => "The message contained unexpected trailing data" ("Message of type {} had {} bytes left over.", tpe, len),
+    UnexpectedType { expected: u16, received: u16 }
+        => "Received a message of an unexpected type" ("Expected message type {} but received {}.", expected, received),
+    InvalidString
+        => "A string in the message was not valid NUL-terminated utf-8",
+    InvalidField { tpe: u16, field: &'static str }
+        => "A field in the message had an invalid value" ("Message of type {} has an invalid `{}` field.", tpe, field),
+}
+
+impl From<ReadCStringError> for MessageError {
+    fn from(e: ReadCStringError) -> MessageError {
+        match e {
+            ReadCStringError::Io { cause } => MessageError::Io { cause: cause },
+            _                              => MessageError::InvalidString,
+        }
+    }
+}
+
+impl From<ReadCStringWithLenError> for MessageError {
+    fn from(e: ReadCStringWithLenError) -> MessageError {
+        match e {
+            ReadCStringWithLenError::Io { cause } => MessageError::Io { cause: cause },
+            _                                     => MessageError::InvalidString,
+        }
+    }
+}
+
+/// Copy an error which is not `Clone` because it holds an `io::Error`, so that one failure can be
+/// reported to every request it affects. Only meant for use within this crate.
+#[doc(hidden)]
+pub trait CopyError {
+    fn copy_error(&self) -> Self;
+}
+
+impl CopyError for io::Error {
+    fn copy_error(&self) -> io::Error {
+        use std::error::Error;
+
+        io::Error::new(self.kind(), self.description())
+    }
+}
+
+impl CopyError for MessageError {
+    fn copy_error(&self) -> MessageError {
+        match *self {
+            MessageError::Io { ref cause }              => MessageError::Io { cause: cause.copy_error() },
+            MessageError::TooLong { tpe, len }          => MessageError::TooLong { tpe: tpe, len: len },
+            MessageError::Truncated { tpe }             => MessageError::Truncated { tpe: tpe },
+            MessageError::TrailingData { tpe, len }     => MessageError::TrailingData { tpe: tpe, len: len },
+            MessageError::UnexpectedType { expected, received }
+                => MessageError::UnexpectedType { expected: expected, received: received },
+            MessageError::InvalidString                 => MessageError::InvalidString,
+            MessageError::InvalidField { tpe, field }   => MessageError::InvalidField { tpe: tpe, field: field },
+        }
+    }
+}
+
+/// A message that can be sent to or received from a GNUnet service.
+///
+/// Implementors only describe the body of the message; `encode` and `decode` take care of the
+/// header and of checking lengths.
+pub trait Message: Sized {
+    /// The type of the message, one of the `GNUNET_MESSAGE_TYPE_*` constants.
+    fn message_type() -> u16;
+
+    /// Write the body of the message, everything after the header.
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError>;
+
+    /// Read the body of the message. `len` is the number of bytes available in the body, reading
+    /// past it results in `MessageError::Truncated`.
+    fn read_body<R: Read>(r: &mut R, len: usize) -> Result<Self, MessageError>;
+}
+
+/// Encode a message, including its header, into a buffer ready to be sent to a service.
+pub fn encode<M: Message>(msg: &M) -> Result<Vec<u8>, MessageError> {
+    let tpe = M::message_type();
+    let mut buf = vec![0u8; HEADER_SIZE];
+    try!(msg.write_body(&mut buf));
+    let len = buf.len();
+    if len > MAX_MESSAGE_SIZE {
+        return Err(MessageError::TooLong { tpe: tpe, len: len });
+    }
+    {
+        let mut header = &mut buf[..HEADER_SIZE];
+        try!(header.write_u16::<BigEndian>(len as u16));
+        try!(header.write_u16::<BigEndian>(tpe));
+    }
+    Ok(buf)
+}
+
+/// Decode a message received with `ServiceReader::read_message`.
+///
+/// `tpe` is the type read from the header and `body` is positioned at the start of the body. The
+/// whole body must be consumed by the message, otherwise `MessageError::TrailingData` is returned.
+pub fn decode<M: Message>(tpe: u16, mut body: Cursor<Vec<u8>>) -> Result<M, MessageError> {
+    if tpe != M::message_type() {
+        return Err(MessageError::UnexpectedType { expected: M::message_type(), received: tpe });
+    }
+    let len = body.get_ref().len() - body.position() as usize;
+    let msg = match M::read_body(&mut Read::take(&mut body, len as u64), len) {
+        Ok(msg) => msg,
+        Err(MessageError::Io { ref cause }) if cause.kind() == io::ErrorKind::UnexpectedEof
+            => return Err(MessageError::Truncated { tpe: tpe }),
+        Err(e) => return Err(e),
+    };
+    let rem = body.get_ref().len() - body.position() as usize;
+    if rem != 0 {
+        return Err(MessageError::TrailingData { tpe: tpe, len: rem });
+    }
+    Ok(msg)
+}
+
+/// Write a NUL-terminated string, as used for the variable-length trailers of many messages.
+pub fn write_c_string<W: Write>(w: &mut W, s: &str) -> Result<(), MessageError> {
+    if s.as_bytes().contains(&0u8) {
+        return Err(MessageError::InvalidString);
+    }
+    try!(w.write_all(s.as_bytes()));
+    try!(w.write_u8(0));
+    Ok(())
+}
+
+/// Read a NUL-terminated string that takes up the rest of a message body.
+pub fn read_c_string<R: Read>(r: &mut R) -> Result<String, MessageError> {
+    Ok(try!(r.read_c_string()))
+}
+
+/// Read a NUL-terminated string whose length (excluding the terminator) is known in advance.
+pub fn read_c_string_with_len<R: Read>(r: &mut R, len: usize) -> Result<String, MessageError> {
+    Ok(try!(r.read_c_string_with_len(len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write, Cursor};
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use super::*;
+
+    const DUMMY_TYPE: u16 = 24;
+
+    #[derive(Debug, PartialEq)]
+    struct Dummy {
+        body: u32,
+        name: String,
+    }
+
+    impl Message for Dummy {
+        fn message_type() -> u16 {
+            DUMMY_TYPE
+        }
+
+        fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+            try!(w.write_u32::<BigEndian>(self.body));
+            write_c_string(w, &self.name)
+        }
+
+        fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<Dummy, MessageError> {
+            let body = try!(r.read_u32::<BigEndian>());
+            let name = try!(read_c_string(r));
+            Ok(Dummy { body: body, name: name })
+        }
+    }
+
+    fn body_of(buf: Vec<u8>) -> (u16, Cursor<Vec<u8>>) {
+        let mut mr = Cursor::new(buf);
+        mr.read_u16::<BigEndian>().unwrap();
+        let tpe = mr.read_u16::<BigEndian>().unwrap();
+        (tpe, mr)
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let msg = Dummy { body: 42, name: "gnu".to_string() };
+        let buf = encode(&msg).unwrap();
+        assert_eq!(buf, vec![0, 12, 0, 24, 0, 0, 0, 42, b'g', b'n', b'u', 0]);
+
+        let (tpe, mr) = body_of(buf);
+        assert_eq!(decode::<Dummy>(tpe, mr).unwrap(), msg);
+    }
+
+    #[test]
+    fn decode_rejects_bad_messages() {
+        let buf = encode(&Dummy { body: 42, name: "gnu".to_string() }).unwrap();
+
+        let (_, mr) = body_of(buf.clone());
+        match decode::<Dummy>(DUMMY_TYPE + 1, mr) {
+            Err(MessageError::UnexpectedType { expected: DUMMY_TYPE, .. }) => (),
+            _ => panic!("wrong message type was accepted"),
+        }
+
+        let (tpe, mr) = body_of(buf[..6].to_vec());
+        match decode::<Dummy>(tpe, mr) {
+            Err(MessageError::Truncated { .. }) => (),
+            _ => panic!("truncated message was accepted"),
+        }
+
+        let mut long = buf.clone();
+        long.push(7);
+        let (tpe, mr) = body_of(long);
+        match decode::<Dummy>(tpe, mr) {
+            Err(MessageError::TrailingData { len: 1, .. }) => (),
+            _ => panic!("trailing data was accepted"),
+        }
+    }
+
+    #[test]
+    fn encode_rejects_oversized_messages() {
+        let msg = Dummy { body: 0, name: ::std::iter::repeat('x').take(MAX_MESSAGE_SIZE).collect() };
+        match encode(&msg) {
+            Err(MessageError::TooLong { tpe: DUMMY_TYPE, .. }) => (),
+            _ => panic!("oversized message was encoded"),
+        }
+    }
+}
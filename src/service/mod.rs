@@ -7,13 +7,23 @@ use byteorder::{BigEndian, ReadBytesExt};
 use gj::{Promise, FulfillerDropped};
 use gjio::{AsyncWrite, AsyncRead, SocketStream, Network};
 
-use configuration::{self, Cfg};
+use configuration::Cfg;
+use self::message::CopyError;
+use self::trace::Direction;
+pub use self::message::{Message, MessageError};
+pub use self::dispatch::{Dispatcher, Deadline, Route, ReconnectPolicy, ServiceEvent};
+pub use self::trace::Tap;
+
+pub mod message;
+pub mod dispatch;
+pub mod trace;
 
 /// Created by `service::connect`. Used to read messages from a GNUnet service.
 #[derive(Clone)]
 pub struct ServiceReader {
     /// The underlying socket wrapped by `ServiceReader`. This is a read-only socket.
     pub connection: SocketStream,
+    tap: Option<Tap>,
 }
 
 /// Created by `service::connect`. Used to send messages to a GNUnet service.
@@ -21,10 +31,11 @@ pub struct ServiceReader {
 pub struct ServiceWriter {
     /// The underlying socket wrapped by `ServiceWriter`. This is a write-only socket.
     pub connection: SocketStream,
+    tap: Option<Tap>,
 }
 
-/// Callbacks passed to `ServiceReader::spawn_callback_loop` return a `ProcessMessageResult` to
-/// tell the callback loop what action to take next.
+/// Reply handlers and subscribers registered with a `Dispatcher` return a `ProcessMessageResult` to
+/// tell it what action to take next.
 #[derive(Copy, Clone)]
 pub enum ProcessMessageResult {
   /// Continue talking to the service and passing received messages to the callback.
@@ -37,9 +48,11 @@ pub enum ProcessMessageResult {
 
 /// Error that can be generated when attempting to connect to a service.
 error_def! ConnectError {
-    NotConfigured { #[from] cause: configuration::CfgGetFilenameError }
+    NotConfigured { service: String, tried: String }
         => "The configuration does not describe how to connect to the service"
-            ("Config does not contain an entry for UNIXPATH in the service's section: {}", cause),
+            ("No usable address for \"{}\" in its config section ({}).", service, tried),
+    NoAddress { hostname: String }
+        => "The service's HOSTNAME did not resolve to any address" ("Hostname was \"{}\".", hostname),
     Io { #[from] cause: io::Error }
         => "There was an I/O error communicating with the service" ("Specifically {}", cause),
 }
@@ -48,17 +61,127 @@ error_def! ConnectError {
 ///
 /// eg. `connect(cfg, "arm")` will attempt to connect to the locally-running `gnunet-arm` service
 /// using the congfiguration details (eg. socket address, port etc.) in `cfg`.
+///
+/// Like GNUnet's own clients, the UNIX domain socket at `UNIXPATH` is tried first. If it is not
+/// set or the connection fails, the service is reached over TCP at `HOSTNAME` and `PORT`, which
+/// must both be set, with `PORT` between 1 and 65535. `ACCEPT_FROM` is enforced by the service
+/// itself and is not consulted here. When both transports fail, `ConnectError::NotConfigured`
+/// lists the error of each attempt in `tried`.
+///
+/// If the section also has a `TRACE_FILE` option, every message exchanged on the connection is
+/// appended to that file by a `trace::Tap`.
 pub fn connect(cfg: &Cfg, name: &str, network: &Network)
                      -> Promise<(ServiceReader, ServiceWriter), ConnectError> {
-    let unixpath = pry!(cfg.get_filename(name, "UNIXPATH"));
-    let addr = pry!(network.get_unix_address(unixpath.as_path()));
-    addr.connect()
-        .lift()
-        .then(move |in_stream| {
-            let out_stream = in_stream.clone();
-            Promise::ok((ServiceReader{ connection: in_stream },
-                         ServiceWriter{ connection: out_stream }))
-        })
+    let mut tried = Vec::new();
+    let unixpath = match cfg.get_filename(name, "UNIXPATH") {
+        Ok(ref path) if path.as_os_str().is_empty() => {
+            tried.push("UNIXPATH is empty".to_string());
+            None
+        },
+        Ok(path) => Some(path),
+        Err(e) => {
+            tried.push(format!("UNIXPATH: {}", e));
+            None
+        },
+    };
+    let tcp = tcp_address(cfg, name, &mut tried);
+    let tap = match cfg.get_filename(name, "TRACE_FILE") {
+        Ok(path) => Some(pry!(Tap::append_to(name, path))),
+        Err(_)   => None,
+    };
+
+    let stream = match (unixpath, tcp) {
+        (Some(path), tcp) => {
+            let network = network.clone();
+            let service = name.to_string();
+            let unix = match network.get_unix_address(path.as_path()) {
+                Ok(addr) => addr.connect(),
+                Err(e)   => Promise::err(e),
+            };
+            unix.then_else(move |res| {
+                let e = match res {
+                    Ok(stream) => return Promise::ok(stream),
+                    Err(e)     => e,
+                };
+                tried.insert(0, format!("UNIXPATH {}: {}", path.display(), e));
+                match tcp {
+                    Some((host, port)) => {
+                        // if TCP fails too, report both attempts rather than just the last one
+                        let addr = format!("TCP {}:{}", host, port);
+                        connect_tcp(&network, host, port).map_else(move |res| match res {
+                            Ok(stream) => Ok(stream),
+                            Err(e)     => {
+                                tried.insert(1, format!("{}: {}", addr, e));
+                                Err(ConnectError::NotConfigured {
+                                    service: service,
+                                    tried: tried.join("; "),
+                                })
+                            },
+                        })
+                    },
+                    None => Promise::err(ConnectError::NotConfigured {
+                        service: service,
+                        tried: tried.join("; "),
+                    }),
+                }
+            })
+        },
+        (None, Some((host, port))) => connect_tcp(network, host, port),
+        (None, None) => return Promise::err(ConnectError::NotConfigured {
+            service: name.to_string(),
+            tried: tried.join("; "),
+        }),
+    };
+    stream.map(move |stream| {
+        let mut sr = ServiceReader::new(stream.clone());
+        let mut sw = ServiceWriter::new(stream);
+        if let Some(tap) = tap {
+            sr.set_tap(tap.clone());
+            sw.set_tap(tap);
+        }
+        Ok((sr, sw))
+    })
+}
+
+// the TCP host and port of a service, if they are usable
+fn tcp_address(cfg: &Cfg, name: &str, tried: &mut Vec<String>) -> Option<(String, u16)> {
+    let port = match cfg.get_int(name, "PORT") {
+        Ok(port) if port == 0 || port > 65535 => {
+            tried.push(format!("PORT {} is out of range", port));
+            None
+        },
+        Ok(port) => Some(port as u16),
+        Err(e) => {
+            tried.push(format!("PORT: {}", e));
+            None
+        },
+    };
+    let hostname = match cfg.get_string(name, "HOSTNAME") {
+        Ok(ref hostname) if hostname.is_empty() => {
+            tried.push("HOSTNAME is empty".to_string());
+            None
+        },
+        Ok(hostname) => Some(hostname),
+        Err(e) => {
+            tried.push(format!("HOSTNAME: {}", e));
+            None
+        },
+    };
+    match (hostname, port) {
+        (Some(hostname), Some(port)) => Some((hostname, port)),
+        _                            => None,
+    }
+}
+
+fn connect_tcp(network: &Network, hostname: String, port: u16) -> Promise<SocketStream, ConnectError> {
+    use std::net::ToSocketAddrs;
+
+    // resolving blocks, but service hostnames are almost always loopback addresses or literals
+    let addr = match pry!((&hostname[..], port).to_socket_addrs()).next() {
+        Some(addr) => addr,
+        None       => return Promise::err(ConnectError::NoAddress { hostname: hostname }),
+    };
+    network.get_tcp_address(addr).connect().lift()
 }
 
 /// Error that can be generated when attempting to receive data from a service.
@@ -67,6 +190,8 @@ error_def! ReadMessageError {
     ShortMessage { len: u16 }       => "The message received from the service was too short" ("Length was {} bytes.", len),
     Disconnected                    => "The service disconnected unexpectedly",
     FulfillerDropped                => "Promise fulfiller was dropped",
+    TimedOut                        => "The service did not answer the request in time",
+    Cancelled                       => "The request was cancelled",
 }
 
 impl FulfillerDropped for ReadMessageError {
@@ -75,14 +200,42 @@ impl FulfillerDropped for ReadMessageError {
     }
 }
 
+impl CopyError for ReadMessageError {
+    fn copy_error(&self) -> ReadMessageError {
+        match *self {
+            ReadMessageError::Io { ref cause }       => ReadMessageError::Io { cause: cause.copy_error() },
+            ReadMessageError::ShortMessage { len }   => ReadMessageError::ShortMessage { len: len },
+            ReadMessageError::Disconnected           => ReadMessageError::Disconnected,
+            ReadMessageError::FulfillerDropped       => ReadMessageError::FulfillerDropped,
+            ReadMessageError::TimedOut               => ReadMessageError::TimedOut,
+            ReadMessageError::Cancelled              => ReadMessageError::Cancelled,
+        }
+    }
+}
+
 impl ServiceReader {
+    /// Read messages from `connection`.
+    pub fn new(connection: SocketStream) -> ServiceReader {
+        ServiceReader {
+            connection: connection,
+            tap: None,
+        }
+    }
+
+    /// Log every message read to `tap`.
+    pub fn set_tap(&mut self, tap: Tap) {
+        self.tap = Some(tap);
+    }
+
     /// Reads a message from the connected socket.
     ///
     /// When using this function multiple times on the same socket the caller needs to make sure the reads are chained together,
-    /// otherwise it may return bogus results.
+    /// otherwise it may return bogus results. Clients with several requests in flight should
+    /// hand the reader to a `Dispatcher` instead.
     pub fn read_message(&mut self) -> Promise<(u16, Cursor<Vec<u8>>), ReadMessageError> {
         use util::async::PromiseReader;
         let mut connection2 =  self.connection.clone(); // this is ok we're just bumping Rc count
+        let tap = self.tap.clone();
         self.connection.read_u16()
             .lift()
             .then(move |len| {
@@ -94,6 +247,9 @@ impl ServiceReader {
                     .map(move |(buf, _)| {
                         let mut mr = Cursor::new(buf);
                         let tpe = try!(mr.read_u16::<BigEndian>());
+                        if let Some(tap) = tap {
+                            tap.record(Direction::Received, tpe, &mr.get_ref()[2..]);
+                        }
                         Ok((tpe, mr))
                     })
             })
@@ -101,102 +257,62 @@ impl ServiceReader {
 }
 
 impl ServiceWriter {
-    /// Sends a message to the connected socket.
-    ///
-    /// The message should not have a null-terminated string, otherwise use `send_with_str`.
-    pub fn send<T: MessageTrait>(&mut self, message: T) -> Promise<(), io::Error> {
-        let x = message.into_slice().to_vec();
-        self.connection.write(x)
-            .map(|_| {
-                Ok(())
-            })
+    /// Send messages to `connection`.
+    pub fn new(connection: SocketStream) -> ServiceWriter {
+        ServiceWriter {
+            connection: connection,
+            tap: None,
+        }
     }
 
-    /// Sends a message with a null-terminated string to the connected socket.
-    ///
-    /// The caller needs to ensure that the message corresponds to the string, i.e. the message length should add up.
-    pub fn send_with_str<T: MessageTrait>(&mut self, message: T, string: &str) -> Promise<(), io::Error> {
-        let mut x = message.into_slice().to_vec();
-        x.extend_from_slice(string.as_bytes());
-        x.push(0u8.to_be()); // for null-termination of the string
-        self.connection.write(x)
-        .map(|_| {
-            Ok(())
-        })
+    /// Log every message sent to `tap`.
+    pub fn set_tap(&mut self, tap: Tap) {
+        self.tap = Some(tap);
     }
-}
-
-#[repr(C, packed)]
-pub struct MessageHeader {
-    pub len: u16,
-    pub tpe: u16,
-}
-
-pub trait MessageTrait {
-    fn into_slice(&self) -> &[u8];
-}
-
-#[macro_export]
-macro_rules! message_to_slice {
-    ($t:ty, $i:ident) => {{
-        use std::slice;
-        use std::mem;
-        let p: *const $t = $i;
-        let p: *const u8 = p as *const u8;
-        let res : &[u8] = unsafe {
-            slice::from_raw_parts(p, mem::size_of::<$t>())
-        };
-        res
-    }}
-}
 
-#[test]
-fn test_message_to_slice() {
-    #[repr(C, packed)]
-    struct S {
-        a: u16,
-        b: u32,
+    /// Encodes a message and sends it to the connected socket.
+    pub fn send<M: Message>(&mut self, msg: &M) -> Promise<(), MessageError> {
+        let buf = pry!(message::encode(msg));
+        self.write(buf)
+            .lift()
+            .map(|_| {
+                Ok(())
+            })
     }
 
-    let s = &S { a: 0, b: 0 };
-    let slice = message_to_slice!(S, s);
-
-    assert!(slice.iter().all(|&x| x == 0));
-    assert_eq!(slice.len(), 6);
+    /// Send one or more already encoded messages.
+    pub fn write(&mut self, buf: Vec<u8>) -> Promise<Vec<u8>, io::Error> {
+        if let Some(ref tap) = self.tap {
+            tap.record_all(Direction::Sent, &buf[..]);
+        }
+        self.connection.write(buf)
+    }
 }
 
 #[test]
 fn test_service() {
     use gj::EventLoop;
     use gjio::EventPort;
-    use std::io::Read;
-    use std::mem::size_of;
-    use byteorder::ByteOrder;
+    use std::io::{Read, Write};
+    use byteorder::{ReadBytesExt, WriteBytesExt};
 
     const DUMMY_TYPE: u16 = 24;
 
-    #[repr(C, packed)]
     struct DummyMsg {
-        header: MessageHeader,
         body: u32,
     }
 
-    impl MessageTrait for DummyMsg {
-        fn into_slice(&self) -> &[u8] {
-            message_to_slice!(DummyMsg, self)
+    impl Message for DummyMsg {
+        fn message_type() -> u16 {
+            DUMMY_TYPE
         }
-    }
 
-    impl DummyMsg {
-        fn new(body: u32) -> DummyMsg {
-            let len = size_of::<DummyMsg>() as u16;
-            DummyMsg {
-                header: MessageHeader {
-                    len: len.to_be(),
-                    tpe: DUMMY_TYPE.to_be(),
-                },
-                body: body,
-            }
+        fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+            Ok(try!(w.write_u32::<BigEndian>(self.body)))
+        }
+
+        fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<DummyMsg, MessageError> {
+            Ok(DummyMsg { body: try!(r.read_u32::<BigEndian>()) })
         }
     }
 
@@ -205,22 +321,62 @@ fn test_service() {
         let network = event_port.get_network();
         let (reader, writer) = network.new_socket_pair().unwrap();
 
-        let mut sr = ServiceReader { connection: reader };
-        let mut sw = ServiceWriter { connection: writer };
+        let mut sr = ServiceReader::new(reader);
+        let mut sw = ServiceWriter::new(writer);
         let msg_body: u32 = 42;
 
-        let msg = DummyMsg::new(msg_body);
+        sw.send(&DummyMsg { body: msg_body }).wait(wait_scope, &mut event_port).unwrap();
+        let (tpe, mr) = sr.read_message().wait(wait_scope, &mut event_port).unwrap();
+        let msg: DummyMsg = message::decode(tpe, mr).unwrap();
+        assert_eq!(msg_body, msg.body);
+        assert_eq!(DUMMY_TYPE, tpe);
 
-        sw.send(msg).lift().then(move |()| {
-            sr.read_message().map(move |(tpe, mut mr)| {
-                let mut buf = vec![0u8; 4];
-                try!(mr.read_exact(&mut buf));
-                assert_eq!(msg_body.to_be(), BigEndian::read_u32(&buf));
-                assert_eq!(DUMMY_TYPE, tpe);
-                Ok(())
-            })
-        }).wait(wait_scope, &mut event_port).unwrap();
+        Ok(())
+    }).expect("top level");
+}
+
+#[test]
+fn test_connect_tcp() {
+    use std::net::TcpListener;
+    use gj::EventLoop;
+    use gjio::EventPort;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut cfg = Cfg::empty();
+    cfg.set_string("dummy", "HOSTNAME", "127.0.0.1".to_string());
+    cfg.set_string("dummy", "PORT", port.to_string());
+    cfg.set_string("unconfigured", "PORT", "0".to_string());
+    cfg.set_string("unix-only", "UNIXPATH", "/nonexistent/gnunet-service-unix-only.sock".to_string());
+    // a port nothing listens on any more
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let tcp_tried = format!("TCP 127.0.0.1:{}: ", closed);
+    cfg.set_string("unreachable", "UNIXPATH", "/nonexistent/gnunet-service-unreachable.sock".to_string());
+    cfg.set_string("unreachable", "HOSTNAME", "127.0.0.1".to_string());
+    cfg.set_string("unreachable", "PORT", closed.to_string());
 
+    EventLoop::top_level(move |wait_scope| -> Result<(), ::std::io::Error> {
+        let mut event_port = EventPort::new().unwrap();
+        let network = event_port.get_network();
+
+        connect(&cfg, "dummy", &network).wait(wait_scope, &mut event_port).unwrap();
+        listener.accept().unwrap();
+
+        match connect(&cfg, "unconfigured", &network).wait(wait_scope, &mut event_port) {
+            Err(ConnectError::NotConfigured { ref service, .. }) if service == "unconfigured" => (),
+            _ => panic!("connected to a service without an address"),
+        }
+        // the reason the UNIX socket could not be used is kept
+        match connect(&cfg, "unix-only", &network).wait(wait_scope, &mut event_port) {
+            Err(ConnectError::NotConfigured { ref tried, .. }) if tried.starts_with("UNIXPATH /nonexistent") => (),
+            _ => panic!("the failed UNIX socket was not reported"),
+        }
+        // when the TCP fallback fails as well, both errors are kept
+        match connect(&cfg, "unreachable", &network).wait(wait_scope, &mut event_port) {
+            Err(ConnectError::NotConfigured { ref tried, .. })
+                if tried.starts_with("UNIXPATH /nonexistent") && tried.contains(&tcp_tried[..]) => (),
+            _ => panic!("the failed UNIX socket and TCP connection were not both reported"),
+        }
         Ok(())
     }).expect("top level");
 }
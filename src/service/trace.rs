@@ -0,0 +1,266 @@
+//! Recording of the messages exchanged with a service, for debugging and as test fixtures.
+//!
+//! A `Tap` attached to a `ServiceReader` and `ServiceWriter` writes one line per message:
+//!
+//! ```text
+//! send gns 500 GNS_LOOKUP 100 0000000052...
+//! recv gns 501 GNS_LOOKUP_RESULT 12 0000000000000000
+//! ```
+//!
+//! giving the direction, the service, the message type as a number and a name, the length of the
+//! message including its header and the body in hex. `service::connect` attaches a tap to every
+//! connection to a service whose config section has a `TRACE_FILE` option naming the file to
+//! append to. Recorded sessions can be parsed with `Recording` and played back to a client with
+//! `testing::replay`.
+
+use std::cell::RefCell;
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use byteorder::{BigEndian, ByteOrder};
+
+use ll;
+use service::message::HEADER_SIZE;
+
+/// Which way a recorded message went.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client to the service.
+    Sent,
+    /// Received by the client from the service.
+    Received,
+}
+
+/// Writes every message passing through a connection to a log. Clones write to the same log.
+#[derive(Clone)]
+pub struct Tap {
+    service: Rc<String>,
+    out: Rc<RefCell<Box<Write>>>,
+}
+
+impl Tap {
+    /// Log the messages exchanged with `service` to `out`.
+    pub fn new(service: &str, out: Box<Write>) -> Tap {
+        Tap {
+            service: Rc::new(service.to_string()),
+            out: Rc::new(RefCell::new(out)),
+        }
+    }
+
+    /// Log the messages exchanged with `service` to the end of the file at `path`.
+    pub fn append_to<P: AsRef<Path>>(service: &str, path: P) -> Result<Tap, io::Error> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        Ok(Tap::new(service, Box::new(file)))
+    }
+
+    /// Log a single message. `body` excludes the header.
+    pub fn record(&self, direction: Direction, tpe: u16, body: &[u8]) {
+        let entry = Entry {
+            direction: direction,
+            service: (*self.service).clone(),
+            tpe: tpe,
+            body: body.to_vec(),
+        };
+        // tracing is a debugging aid, failing to write the log must not break the connection
+        let _ = writeln!(self.out.borrow_mut(), "{}", entry);
+    }
+
+    /// Log every message in `buf`, which holds one or more complete messages.
+    pub fn record_all(&self, direction: Direction, buf: &[u8]) {
+        let mut rest = buf;
+        while rest.len() >= HEADER_SIZE {
+            let len = BigEndian::read_u16(&rest[..2]) as usize;
+            if len < HEADER_SIZE || len > rest.len() {
+                break;
+            }
+            let tpe = BigEndian::read_u16(&rest[2..4]);
+            self.record(direction, tpe, &rest[HEADER_SIZE..len]);
+            rest = &rest[len..];
+        }
+    }
+}
+
+/// One recorded message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Whether the client sent or received the message.
+    pub direction: Direction,
+    /// The name of the service the client was talking to.
+    pub service: String,
+    /// The type of the message.
+    pub tpe: u16,
+    /// The body of the message, without the header.
+    pub body: Vec<u8>,
+}
+
+impl ::std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let direction = match self.direction {
+            Direction::Sent     => "send",
+            Direction::Received => "recv",
+        };
+        let mut hex = String::with_capacity(self.body.len() * 2);
+        for b in self.body.iter() {
+            try!(write!(hex, "{:02x}", b));
+        }
+        write!(f, "{} {} {} {} {} {}",
+               direction,
+               self.service,
+               self.tpe,
+               message_type_name(self.tpe).unwrap_or("UNKNOWN"),
+               self.body.len() + HEADER_SIZE,
+               hex)
+    }
+}
+
+/// Error generated when parsing a recorded session.
+error_def! RecordingError {
+    Io { #[from] cause: io::Error }
+        => "There was an I/O error reading the recording" ("Specifically: {}", cause),
+    Syntax { line_number: usize, line: String }
+        => "Syntax error in the recording" ("line {}: Failed to parse \"{}\"", line_number, line),
+}
+
+/// A session recorded by a `Tap`.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    /// The recorded messages, in the order they were exchanged.
+    pub entries: Vec<Entry>,
+}
+
+impl Recording {
+    /// Load a recording from a file written by a `Tap`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Recording, RecordingError> {
+        let file = try!(OpenOptions::new().read(true).open(path));
+        Recording::deserialize(file)
+    }
+
+    /// Parse a recording. Empty lines and lines starting with `#` are ignored.
+    pub fn deserialize<R: Read>(read: R) -> Result<Recording, RecordingError> {
+        let mut entries = Vec::new();
+        for (i, res_line) in BufReader::new(read).lines().enumerate() {
+            let line = try!(res_line);
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_entry(&line) {
+                Some(entry) => entries.push(entry),
+                None        => return Err(RecordingError::Syntax { line_number: i + 1, line: line }),
+            }
+        }
+        Ok(Recording {
+            entries: entries,
+        })
+    }
+
+    /// The messages exchanged with the service `service` only.
+    pub fn service(&self, service: &str) -> Recording {
+        Recording {
+            entries: self.entries.iter().filter(|e| e.service == service).cloned().collect(),
+        }
+    }
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.len() != 6 {
+        return None;
+    }
+    let direction = match fields[0] {
+        "send" => Direction::Sent,
+        "recv" => Direction::Received,
+        _      => return None,
+    };
+    let tpe = match u16::from_str(fields[2]) {
+        Ok(tpe) => tpe,
+        Err(_)  => return None,
+    };
+    let hex = fields[5].as_bytes();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let mut body = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.chunks(2) {
+        let s = match ::std::str::from_utf8(pair) {
+            Ok(s)  => s,
+            Err(_) => return None,
+        };
+        match u8::from_str_radix(s, 16) {
+            Ok(b)  => body.push(b),
+            Err(_) => return None,
+        }
+    }
+    match usize::from_str(fields[4]) {
+        Ok(len) if len == body.len() + HEADER_SIZE => (),
+        _ => return None,
+    }
+    Some(Entry {
+        direction: direction,
+        service: fields[1].to_string(),
+        tpe: tpe,
+        body: body,
+    })
+}
+
+/// The name of a message type, without the `GNUNET_MESSAGE_TYPE_` prefix, if it is known to this
+/// crate.
+pub fn message_type_name(tpe: u16) -> Option<&'static str> {
+    Some(match tpe {
+        ll::GNUNET_MESSAGE_TYPE_HELLO                    => "HELLO",
+        ll::GNUNET_MESSAGE_TYPE_CADET_LOCAL_CONNECT      => "CADET_LOCAL_CONNECT",
+        ll::GNUNET_MESSAGE_TYPE_CADET_LOCAL_CHANNEL_CREATE => "CADET_LOCAL_CHANNEL_CREATE",
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_GET             => "PEERINFO_GET",
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_GET_ALL         => "PEERINFO_GET_ALL",
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO            => "PEERINFO_INFO",
+        ll::GNUNET_MESSAGE_TYPE_PEERINFO_INFO_END        => "PEERINFO_INFO_END",
+        ll::GNUNET_MESSAGE_TYPE_TRANSPORT_START          => "TRANSPORT_START",
+        ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP               => "GNS_LOOKUP",
+        ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP_RESULT        => "GNS_LOOKUP_RESULT",
+        ll::GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP       => "GNS_REVERSE_LOOKUP",
+        ll::GNUNET_MESSAGE_TYPE_GNS_REVERSE_LOOKUP_RESULT => "GNS_REVERSE_LOOKUP_RESULT",
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_START           => "IDENTITY_START",
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_RESULT_CODE     => "IDENTITY_RESULT_CODE",
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_UPDATE          => "IDENTITY_UPDATE",
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_GET_DEFAULT     => "IDENTITY_GET_DEFAULT",
+        ll::GNUNET_MESSAGE_TYPE_IDENTITY_SET_DEFAULT     => "IDENTITY_SET_DEFAULT",
+        _ => return None,
+    })
+}
+
+#[test]
+fn test_recording_round_trip() {
+    use std::io::Cursor;
+
+    let buf: Rc<RefCell<Vec<u8>>> = Rc::new(RefCell::new(Vec::new()));
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let tap = Tap::new("gns", Box::new(Shared(buf.clone())));
+    // two messages written at once, as the dispatcher does after reconnecting
+    tap.record_all(Direction::Sent, &[0, 6, 1, 244, 0xab, 0xcd, 0, 4, 2, 112]);
+    tap.record(Direction::Received, ll::GNUNET_MESSAGE_TYPE_GNS_LOOKUP_RESULT, &[1, 2, 3]);
+
+    let text = String::from_utf8(buf.borrow().clone()).unwrap();
+    assert_eq!(text, "send gns 500 GNS_LOOKUP 6 abcd\n\
+                      send gns 624 IDENTITY_START 4 \n\
+                      recv gns 501 GNS_LOOKUP_RESULT 7 010203\n");
+
+    let recording = Recording::deserialize(Cursor::new(text.into_bytes())).unwrap();
+    assert_eq!(recording.entries.len(), 3);
+    assert_eq!(recording.entries[0].body, vec![0xab, 0xcd]);
+    assert_eq!(recording.entries[2].direction, Direction::Received);
+    assert!(Recording::deserialize(Cursor::new(b"send gns 500 GNS_LOOKUP 9 abcd".to_vec())).is_err());
+}
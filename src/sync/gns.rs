@@ -0,0 +1,41 @@
+use std::rc::Rc;
+use std::time::Duration;
+use gjio::Timer;
+
+use Cfg;
+use EcdsaPublicKey;
+use gns::{GNS, Record, RecordType, LocalOptions, LookupError};
+use service::{ConnectError, Deadline};
+use super::Worker;
+
+/// A handle to the GNS service which can be cloned and shared between threads.
+#[derive(Clone)]
+pub struct Gns {
+    worker: Worker<GNS>,
+}
+
+impl Gns {
+    /// Connect to the GNS service configured in `cfg`.
+    pub fn connect(cfg: &Cfg) -> Result<Gns, ConnectError> {
+        let cfg = cfg.clone();
+        let worker = try!(Worker::spawn(move |network| GNS::connect(&cfg, network)));
+        Ok(Gns {
+            worker: worker,
+        })
+    }
+
+    /// Lookup the records of type `record_type` for `name` in the zone `zone`. See `GNS::lookup`.
+    /// Fails with `LookupError::TimedOut` if the service has not answered within `timeout`.
+    pub fn lookup(&self,
+                  name: &str,
+                  zone: EcdsaPublicKey,
+                  record_type: RecordType,
+                  options: LocalOptions,
+                  timeout: Duration) -> Result<Vec<Record>, LookupError> {
+        let name = name.to_string();
+        self.worker.call(move |gns: &mut GNS, timer: &Timer| {
+            let deadline = Deadline::new(timer, timeout.into());
+            gns.lookup(Rc::new(name), zone, record_type, options, None, deadline)
+        })
+    }
+}
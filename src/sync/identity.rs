@@ -0,0 +1,34 @@
+use std::rc::Rc;
+use std::time::Duration;
+use gjio::Timer;
+
+use Cfg;
+use identity::{IdentityService, Ego, ConnectError, GetDefaultEgoError};
+use service::Deadline;
+use super::Worker;
+
+/// A handle to the identity service which can be cloned and shared between threads.
+#[derive(Clone)]
+pub struct Identity {
+    worker: Worker<IdentityService>,
+}
+
+impl Identity {
+    /// Connect to the identity service configured in `cfg`.
+    pub fn connect(cfg: &Cfg) -> Result<Identity, ConnectError> {
+        let cfg = cfg.clone();
+        let worker = try!(Worker::spawn(move |network| IdentityService::connect(&cfg, network)));
+        Ok(Identity {
+            worker: worker,
+        })
+    }
+
+    /// Get the default ego of the service `name`, eg. `"gns-master"`. Fails with
+    /// `GetDefaultEgoError::TimedOut` if the service has not answered within `timeout`.
+    pub fn get_default_ego(&self, name: &str, timeout: Duration) -> Result<Ego, GetDefaultEgoError> {
+        let name = name.to_string();
+        self.worker.call(move |is: &mut IdentityService, timer: &Timer| {
+            is.get_default_ego(Rc::new(name), Deadline::new(timer, timeout.into()))
+        })
+    }
+}
@@ -0,0 +1,212 @@
+//! Service handles which can be shared between threads.
+//!
+//! The promise-based handles are tied to the thread running their event loop. The handles in this
+//! module are `Send + Sync` and cheap to clone: they forward every request to a worker thread which
+//! owns the event loop and a single connection to the service, then block until the reply arrives.
+//! Any number of threads can make requests concurrently over the same connection.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::thread;
+//! use std::time::Duration;
+//! use gnunet::Cfg;
+//! use gnunet::sync;
+//! use gnunet::gns::{RecordType, LocalOptions};
+//!
+//! let config = Cfg::default().unwrap();
+//! let timeout = Duration::from_secs(5);
+//! let identity = sync::Identity::connect(&config).unwrap();
+//! let zone = identity.get_default_ego("gns-master", timeout).unwrap().get_public_key();
+//! let gns = sync::Gns::connect(&config).unwrap();
+//! let threads: Vec<_> = vec!["gnu.org", "gnunet.org"].into_iter().map(|name| {
+//!     let gns = gns.clone();
+//!     thread::spawn(move || gns.lookup(name, zone, RecordType::A, LocalOptions::LocalMaster, timeout))
+//! }).collect();
+//! for t in threads {
+//!     println!("{:?}", t.join().unwrap().unwrap());
+//! }
+//! ```
+
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use gj::{EventLoop, Promise, TaskSet, TaskReaper};
+use gjio::{AsyncRead, EventPort, Network, SocketStream, Timer};
+use util::async;
+
+pub use self::gns::Gns;
+pub use self::identity::Identity;
+pub use self::peerinfo::PeerInfo;
+
+mod gns;
+mod identity;
+mod peerinfo;
+
+// a request for the worker thread, called once with the state it owns and the timer of its
+// event loop
+type Job<S> = Box<FnMut(&mut S, &Timer) -> Promise<(), ()> + Send>;
+
+struct Shared<S> {
+    jobs: Mutex<mpsc::Sender<Job<S>>>,
+    // a byte is written for every job so that the worker's event loop wakes up
+    wake: Mutex<UnixStream>,
+}
+
+/// A thread running an event loop and owning a value of type `S`, usually a service handle, on
+/// behalf of any number of other threads.
+///
+/// The thread exits once every clone of the `Worker` has been dropped.
+pub struct Worker<S> {
+    shared: Arc<Shared<S>>,
+}
+
+impl<S> Clone for Worker<S> {
+    fn clone(&self) -> Worker<S> {
+        Worker {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S: 'static> Worker<S> {
+    /// Start a worker thread whose state is the value of the promise returned by `init`, eg. a
+    /// connection to a service. Returns once that promise has resolved.
+    pub fn spawn<F, E>(init: F) -> Result<Worker<S>, E>
+        where F: FnOnce(&Network) -> Promise<S, E> + Send + 'static,
+              E: From<io::Error> + Send + 'static
+    {
+        let (jobs_tx, jobs_rx) = mpsc::channel::<Job<S>>();
+        // the worker sends its end of the wake up socket once its state is ready
+        let (ready_tx, ready_rx) = mpsc::channel::<Result<UnixStream, E>>();
+        let thread = try!(thread::Builder::new().name("gnunet-worker".to_string()).spawn(move || {
+            EventLoop::top_level(move |wait_scope| {
+                let res = EventPort::new().and_then(|event_port| {
+                    let network = event_port.get_network();
+                    let (notify, wake) = try!(async::wake_pair(&network));
+                    Ok((event_port, network, notify, wake))
+                });
+                let (mut event_port, network, notify, wake) = match res {
+                    Ok(x)  => x,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(E::from(e)));
+                        return;
+                    },
+                };
+                let state = match init(&network).wait(wait_scope, &mut event_port) {
+                    Ok(state) => state,
+                    Err(e)    => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    },
+                };
+                let _ = ready_tx.send(Ok(notify));
+                let tasks = TaskSet::new(Box::new(Reaper));
+                let timer = event_port.get_timer();
+                let _ = serve(state, timer, jobs_rx, wake, tasks).wait(wait_scope, &mut event_port);
+            })
+        }));
+
+        let failed = || E::from(io::Error::new(io::ErrorKind::Other, "the worker thread failed to start"));
+        match ready_rx.recv().unwrap_or_else(|_| Err(failed())) {
+            Ok(wake) => Ok(Worker {
+                shared: Arc::new(Shared {
+                    jobs: Mutex::new(jobs_tx),
+                    wake: Mutex::new(wake),
+                }),
+            }),
+            Err(e) => {
+                let _ = thread.join();
+                Err(e)
+            },
+        }
+    }
+
+    /// Run the promise returned by `f` on the worker thread and block until it resolves. `f` is
+    /// also given the timer of the worker's event loop, eg. for making `Deadline`s.
+    pub fn call<F, T, E>(&self, f: F) -> Result<T, E>
+        where F: FnOnce(&mut S, &Timer) -> Promise<T, E> + Send + 'static,
+              T: Send + 'static,
+              E: From<io::Error> + Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        let mut f = Some(f);
+        let job: Job<S> = Box::new(move |state: &mut S, timer: &Timer| {
+            let tx = tx.clone();
+            match f.take() {
+                Some(f) => f(state, timer).then_else(move |res| {
+                    let _ = tx.send(res);
+                    Promise::ok(())
+                }),
+                None    => Promise::ok(()),
+            }
+        });
+        try!(self.submit(job));
+        // the sender is dropped without sending if the worker thread stops first
+        rx.recv().unwrap_or_else(|_| Err(E::from(stopped())))
+    }
+
+    fn submit(&self, job: Job<S>) -> Result<(), io::Error> {
+        try!(self.shared.jobs.lock().unwrap().send(job).map_err(|_| stopped()));
+        self.shared.wake.lock().unwrap().write_all(&[0])
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the worker thread has stopped")
+}
+
+struct Reaper;
+
+impl TaskReaper<(), ()> for Reaper {
+    fn task_failed(&mut self, _: ()) {}
+}
+
+// start the jobs submitted since the last wake up, until the last handle is dropped
+fn serve<S: 'static>(mut state: S,
+                     timer: Timer,
+                     jobs: mpsc::Receiver<Job<S>>,
+                     mut wake: SocketStream,
+                     mut tasks: TaskSet<(), ()>)
+                     -> Promise<(), io::Error> {
+    wake.read(vec![0u8; 64], 1).then(move |(_, n)| {
+        if n == 0 {
+            return Promise::ok(());
+        }
+        while let Ok(mut job) = jobs.try_recv() {
+            tasks.add(job(&mut state, &timer));
+        }
+        serve(state, timer, jobs, wake, tasks)
+    })
+}
+
+#[test]
+fn test_worker_shared_between_threads() {
+    let worker: Worker<u32> = Worker::spawn(|_| Promise::<u32, io::Error>::ok(0)).unwrap();
+    let threads: Vec<_> = (0..4).map(|_| {
+        let worker = worker.clone();
+        thread::spawn(move || {
+            for _ in 0..10 {
+                worker.call(|count: &mut u32, _: &Timer| {
+                    *count += 1;
+                    Promise::<u32, io::Error>::ok(*count)
+                }).unwrap();
+            }
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let count = worker.call(|count: &mut u32, _: &Timer| Promise::<u32, io::Error>::ok(*count)).unwrap();
+    assert_eq!(count, 40);
+}
+
+#[test]
+fn test_worker_init_fails() {
+    let res = Worker::<u32>::spawn(|_| Promise::<u32, io::Error>::err(io::Error::new(io::ErrorKind::Other, "no service")));
+    match res {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::Other),
+        Ok(_)  => panic!("the worker started without its state"),
+    }
+}
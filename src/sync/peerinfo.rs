@@ -0,0 +1,44 @@
+use std::time::Duration;
+use gjio::Timer;
+
+use Cfg;
+use Hello;
+use peerinfo::{PeerIdentity, PeerInfoService};
+use peerinfo::peerinfo::PeerInfoError;
+use service::Deadline;
+use super::Worker;
+
+/// A handle to the peerinfo service which can be cloned and shared between threads.
+#[derive(Clone)]
+pub struct PeerInfo {
+    worker: Worker<PeerInfoService>,
+}
+
+impl PeerInfo {
+    /// Connect to the peerinfo service configured in `cfg`.
+    pub fn connect(cfg: &Cfg) -> Result<PeerInfo, PeerInfoError> {
+        let cfg = cfg.clone();
+        let worker = try!(Worker::spawn(move |network| PeerInfoService::connect(&cfg, network)));
+        Ok(PeerInfo {
+            worker: worker,
+        })
+    }
+
+    /// Get all the peers known to the service. Fails with `PeerInfoError::TimedOut` if the
+    /// whole list has not been received within `timeout`.
+    pub fn get_peers_vec(&self, timeout: Duration) -> Result<Vec<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+        self.worker.call(move |pis: &mut PeerInfoService, timer: &Timer| {
+            pis.get_peers_vec(Deadline::new(timer, timeout.into()))
+        })
+    }
+
+    /// Get the peer with the identity `peer`, or `None` if the service does not know it.
+    /// `timeout` is handled as by `get_peers_vec`.
+    pub fn get_peer(&self, peer: &PeerIdentity, timeout: Duration)
+                    -> Result<Option<(PeerIdentity, Option<Hello>)>, PeerInfoError> {
+        let peer = *peer;
+        self.worker.call(move |pis: &mut PeerInfoService, timer: &Timer| {
+            pis.get_peer(&peer, Deadline::new(timer, timeout.into()))
+        })
+    }
+}
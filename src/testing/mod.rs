@@ -0,0 +1,326 @@
+//! In-process fake GNUnet services for testing client code without a running peer.
+//!
+//! A `MockService` answers the messages it receives with canned replies. `TestPeer` runs any
+//! number of them on UNIX sockets in a temporary directory and builds a `Cfg` pointing at them,
+//! so the normal `connect` functions of this crate can be used against them unchanged.
+//! `TestPeer::with_service` does all of the setup for the common case of a test talking to a
+//! single service.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::collections::HashMap;
+//! use gnunet::{GNS, EcdsaPrivateKey};
+//! use gnunet::gns::{self, Record, RecordFlags, RecordType};
+//! use gnunet::testing::{self, TestPeer};
+//!
+//! let mut records = HashMap::new();
+//! records.insert("www.gnu".to_string(), vec![Record::new(RecordType::A, 0, RecordFlags::empty(), vec![10, 0, 0, 1])]);
+//!
+//! TestPeer::with_service(testing::gns(records), GNS::connect, |mut gns, t| {
+//!     let zone = EcdsaPrivateKey::zeros().get_public();
+//!     let lookup = gns.lookup(::std::rc::Rc::new("www.gnu".to_string()),
+//!                             zone,
+//!                             RecordType::A,
+//!                             gns::LocalOptions::Default,
+//!                             None,
+//!                             None);
+//!     assert_eq!(t.wait(lookup).unwrap().len(), 1);
+//! });
+//! ```
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use byteorder::{BigEndian, WriteBytesExt};
+use gj::{EventLoop, Promise, WaitScope};
+use gjio::{AsyncWrite, EventPort, Network, SocketStream, Timer};
+use rand;
+
+use configuration::Cfg;
+use service::{ServiceReader, ReadMessageError};
+use service::message::{self, Message, MessageError};
+
+pub use self::services::{gns, identity, peerinfo, transport, replay};
+
+mod services;
+
+/// The replies a `MockService` sends back for one received message.
+pub struct Replies {
+    buf: Vec<u8>,
+    disconnect: bool,
+}
+
+impl Replies {
+    fn new() -> Replies {
+        Replies {
+            buf: Vec::new(),
+            disconnect: false,
+        }
+    }
+
+    /// Queue `msg` to be sent to the client.
+    pub fn send<M: Message>(&mut self, msg: &M) -> Result<(), MessageError> {
+        let buf = try!(message::encode(msg));
+        self.buf.extend_from_slice(&buf[..]);
+        Ok(())
+    }
+
+    /// Queue a message of type `tpe` with the already encoded body `body`.
+    pub fn send_raw(&mut self, tpe: u16, body: &[u8]) -> Result<(), MessageError> {
+        let len = body.len() + message::HEADER_SIZE;
+        if len > message::MAX_MESSAGE_SIZE {
+            return Err(MessageError::TooLong { tpe: tpe, len: len });
+        }
+        try!(self.buf.write_u16::<BigEndian>(len as u16));
+        try!(self.buf.write_u16::<BigEndian>(tpe));
+        self.buf.extend_from_slice(body);
+        Ok(())
+    }
+
+    /// Close the connection after sending the queued replies, like a crashing service would.
+    pub fn disconnect(&mut self) {
+        self.disconnect = true;
+    }
+}
+
+/// Handles one type of message received by a `MockService`. Any error returned closes the
+/// connection.
+pub type Handler = Box<FnMut(u16, Cursor<Vec<u8>>, &mut Replies) -> Result<(), MessageError>>;
+
+/// A scriptable fake of a GNUnet service.
+///
+/// Messages for which no handler has been registered close the connection, as a real service
+/// would do on receiving a malformed request.
+pub struct MockService {
+    name: String,
+    handlers: HashMap<u16, Handler>,
+}
+
+impl MockService {
+    /// Create a fake of the service configured under the section `name`, eg. `"gns"`.
+    pub fn new(name: &str) -> MockService {
+        MockService {
+            name: name.to_string(),
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// The name of the service.
+    pub fn name(&self) -> &str {
+        &self.name[..]
+    }
+
+    /// Handle the messages of type `tpe` with `handler`, replacing any previous handler.
+    pub fn on(&mut self, tpe: u16, handler: Handler) {
+        self.handlers.insert(tpe, handler);
+    }
+
+    /// Decode the messages of type `M` and pass them to `handler`.
+    pub fn on_message<M, F>(&mut self, mut handler: F)
+        where M: Message,
+              F: FnMut(M, &mut Replies) -> Result<(), MessageError> + 'static
+    {
+        self.on(M::message_type(), Box::new(move |tpe, mr, replies| {
+            let msg = try!(message::decode(tpe, mr));
+            handler(msg, replies)
+        }));
+    }
+}
+
+/// A set of `MockService`s listening on UNIX sockets in a temporary directory.
+///
+/// The services run on the event loop of the `Network` they are started with and stop when the
+/// `TestPeer` is dropped, which also removes the directory.
+pub struct TestPeer {
+    dir: PathBuf,
+    cfg: Cfg,
+    services: Vec<Promise<(), io::Error>>,
+}
+
+impl TestPeer {
+    /// Create a peer with no services running.
+    pub fn new() -> Result<TestPeer, io::Error> {
+        let dir = ::std::env::temp_dir().join(format!("gnunet-rs-{:016x}", rand::random::<u64>()));
+        try!(fs::create_dir(&dir));
+        Ok(TestPeer {
+            dir: dir,
+            cfg: Cfg::empty(),
+            services: Vec::new(),
+        })
+    }
+
+    /// A configuration describing how to connect to the running services.
+    pub fn cfg(&self) -> &Cfg {
+        &self.cfg
+    }
+
+    /// Set an option in the configuration returned by `cfg`.
+    pub fn set_option(&mut self, section: &str, key: &str, value: &str) {
+        self.cfg.set_string(section, key, value.to_string());
+    }
+
+    /// The directory holding the services' sockets.
+    pub fn dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Start listening for connections to `service`.
+    pub fn start(&mut self, service: MockService, network: &Network) -> Result<(), io::Error> {
+        let path = self.dir.join(format!("{}.sock", service.name));
+        let mut listener = try!(try!(network.get_unix_address(path.as_path())).listen());
+        self.cfg.set_string(&service.name, "UNIXPATH", path.to_string_lossy().into_owned());
+
+        let handlers = Rc::new(RefCell::new(service.handlers));
+        let connections = Rc::new(RefCell::new(Vec::new()));
+        let accept = accept_loop(listener.accept(), listener, handlers, connections);
+        self.services.push(accept.eagerly_evaluate());
+        Ok(())
+    }
+}
+
+/// The event loop a test run by `TestPeer::with_service` runs on.
+pub struct TestLoop<'a> {
+    /// The peer running the service under test. More services can be started on it.
+    pub peer: TestPeer,
+    /// The network of the event loop.
+    pub network: Network,
+    /// The timer of the event loop, eg. for making `Deadline`s.
+    pub timer: Timer,
+    wait_scope: &'a WaitScope,
+    event_port: EventPort,
+}
+
+impl<'a> TestLoop<'a> {
+    /// Run the event loop until `promise` resolves.
+    pub fn wait<T, E>(&mut self, promise: Promise<T, E>) -> Result<T, E> {
+        promise.wait(self.wait_scope, &mut self.event_port)
+    }
+}
+
+impl TestPeer {
+    /// Run `test` on a new event loop, with `service` running on a new `TestPeer` and a handle
+    /// to it made with `connect`, eg. `GNS::connect`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the service cannot be started or connected to.
+    pub fn with_service<H, E, C, F>(service: MockService, connect: C, test: F)
+        where C: FnOnce(&Cfg, &Network) -> Promise<H, E>,
+              E: fmt::Debug,
+              F: FnOnce(H, &mut TestLoop)
+    {
+        EventLoop::top_level(move |wait_scope| -> Result<(), io::Error> {
+            let event_port = try!(EventPort::new());
+            let network = event_port.get_network();
+            let timer = event_port.get_timer();
+            let mut peer = try!(TestPeer::new());
+            try!(peer.start(service, &network));
+            let connected = connect(peer.cfg(), &network);
+            let mut t = TestLoop {
+                peer: peer,
+                network: network,
+                timer: timer,
+                wait_scope: wait_scope,
+                event_port: event_port,
+            };
+            let handle = t.wait(connected).expect("failed to connect to the mock service");
+            test(handle, &mut t);
+            Ok(())
+        }).expect("top level");
+    }
+}
+
+impl Drop for TestPeer {
+    fn drop(&mut self) {
+        self.services.clear();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+type Handlers = Rc<RefCell<HashMap<u16, Handler>>>;
+
+fn accept_loop(accept: Promise<SocketStream, io::Error>,
+               mut listener: ::gjio::SocketListener,
+               handlers: Handlers,
+               connections: Rc<RefCell<Vec<Promise<(), ()>>>>) -> Promise<(), io::Error> {
+    accept.then(move |stream| {
+        let serve = serve(ServiceReader::new(stream.clone()), stream, handlers.clone());
+        connections.borrow_mut().push(serve.eagerly_evaluate());
+        let accept = listener.accept();
+        accept_loop(accept, listener, handlers, connections)
+    })
+}
+
+// answer the messages of one client until it disconnects or sends something unexpected
+fn serve(mut reader: ServiceReader, mut writer: SocketStream, handlers: Handlers) -> Promise<(), ()> {
+    reader.read_message().then_else(move |res| {
+        let (tpe, mr) = match res {
+            Ok(msg) => msg,
+            Err(ReadMessageError::Disconnected) => return Promise::ok(()),
+            Err(_) => return Promise::err(()),
+        };
+        let mut replies = Replies::new();
+        let handled = match handlers.borrow_mut().get_mut(&tpe) {
+            Some(handler) => handler(tpe, mr, &mut replies).is_ok(),
+            None          => false,
+        };
+        if !handled {
+            return Promise::err(());
+        }
+        let disconnect = replies.disconnect;
+        writer.write(replies.buf).then_else(move |res| {
+            match (res, disconnect) {
+                (Ok(_), false) => serve(reader, writer, handlers),
+                (Ok(_), true)  => Promise::ok(()),
+                (Err(_), _)    => Promise::err(()),
+            }
+        })
+    })
+}
+
+#[test]
+fn test_mock_identity() {
+    use std::rc::Rc;
+    use EcdsaPrivateKey;
+    use identity::{GetDefaultEgoError, IdentityService};
+
+    let sk = EcdsaPrivateKey::zeros();
+    let mut defaults = HashMap::new();
+    defaults.insert("gns-master".to_string(), "master".to_string());
+    let service = identity(vec![("master".to_string(), sk)], defaults);
+
+    TestPeer::with_service(service, IdentityService::connect, |mut is, t| {
+        let ego = t.wait(is.get_default_ego(Rc::new("gns-master".to_string()), None)).unwrap();
+        assert_eq!(ego.get_name(), Some("master".to_string()));
+        match t.wait(is.get_default_ego(Rc::new("fs".to_string()), None)) {
+            Err(GetDefaultEgoError::ServiceResponse { .. }) => (),
+            _ => panic!("got a default ego for an unknown service"),
+        }
+    });
+}
+
+#[test]
+fn test_replay_gns() {
+    use EcdsaPrivateKey;
+    use gns::{GNS, LocalOptions, RecordType};
+    use service::trace::Recording;
+
+    let text = "send gns 500 GNS_LOOKUP 4 \n\
+                recv gns 501 GNS_LOOKUP_RESULT 12 0000000000000000\n";
+    let recording = Recording::deserialize(Cursor::new(text.as_bytes().to_vec())).unwrap();
+
+    TestPeer::with_service(replay(&recording, "gns"), GNS::connect, |mut gns, t| {
+        let lookup = gns.lookup(Rc::new("www.gnu".to_string()),
+                                EcdsaPrivateKey::zeros().get_public(),
+                                RecordType::A,
+                                LocalOptions::Default,
+                                None,
+                                None);
+        assert!(t.wait(lookup).unwrap().is_empty());
+    });
+}
@@ -0,0 +1,143 @@
+//! Ready-made `MockService`s for the services this crate has clients for.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::rc::Rc;
+
+use EcdsaPrivateKey;
+use Hello;
+use PeerIdentity;
+use gns::{LookupMessage, LookupResultMessage, Record, RecordType, RECORD_TYPE_ANY};
+use identity::{StartMessage, UpdateMessage, GetDefaultMessage, SetDefaultMessage, ResultCodeMessage};
+use peerinfo::peerinfo::{ListAllPeersMessage, ListPeerMessage, InfoMessage, InfoEndMessage};
+use transport;
+use service::message::MessageError;
+use service::trace::{Direction, Entry, Recording};
+use super::MockService;
+
+/// A GNS service which knows the records in `records`, indexed by name.
+///
+/// Every lookup is answered with the records of the requested type stored under the requested
+/// name, regardless of the zone it is made in. Unknown names get an empty result.
+pub fn gns(records: HashMap<String, Vec<Record>>) -> MockService {
+    let mut service = MockService::new("gns");
+    service.on_message(move |msg: LookupMessage, replies| {
+        let found = match records.get(&msg.name) {
+            Some(rs) => rs.iter().filter(|r| {
+                msg.record_type == RecordType::Other(RECORD_TYPE_ANY) || r.record_type() == msg.record_type
+            }).cloned().collect(),
+            None     => Vec::new(),
+        };
+        replies.send(&LookupResultMessage {
+            id: msg.id,
+            records: found,
+        })
+    });
+    service
+}
+
+/// An identity service with the egos `egos`, given as pairs of names and private keys.
+///
+/// `defaults` maps service names, eg. `"gns-master"`, to the name of their default ego. Asking for
+/// the default ego of any other service fails, as it does with the real service.
+pub fn identity(egos: Vec<(String, EcdsaPrivateKey)>, defaults: HashMap<String, String>) -> MockService {
+    let mut service = MockService::new("identity");
+    let keys: HashMap<String, EcdsaPrivateKey> = egos.iter().cloned().collect();
+    service.on_message(move |_: StartMessage, replies| {
+        for &(ref name, private_key) in egos.iter() {
+            try!(replies.send(&UpdateMessage {
+                end_of_list: false,
+                private_key: private_key,
+                name: name.clone(),
+            }));
+        }
+        replies.send(&UpdateMessage {
+            end_of_list: true,
+            private_key: EcdsaPrivateKey::zeros(),
+            name: String::new(),
+        })
+    });
+    service.on_message(move |msg: GetDefaultMessage, replies| {
+        match defaults.get(&msg.name).and_then(|ego| keys.get(ego)) {
+            Some(&private_key) => replies.send(&SetDefaultMessage {
+                private_key: private_key,
+                name: msg.name,
+            }),
+            None => replies.send(&ResultCodeMessage {
+                result_code: 1,
+                message: "Unknown ego specified for service (internal error)".to_string(),
+            }),
+        }
+    });
+    service
+}
+
+/// A peerinfo service which knows about the peers in `peers`.
+pub fn peerinfo(peers: Vec<(PeerIdentity, Option<Hello>)>) -> MockService {
+    let mut service = MockService::new("peerinfo");
+    let all = peers.clone();
+    service.on_message(move |_: ListAllPeersMessage, replies| {
+        for &(peer, ref hello) in all.iter() {
+            try!(replies.send(&InfoMessage {
+                peer: peer,
+                hello: hello.clone(),
+            }));
+        }
+        replies.send(&InfoEndMessage)
+    });
+    service.on_message(move |msg: ListPeerMessage, replies| {
+        let wanted = msg.peer.to_string();
+        for &(peer, ref hello) in peers.iter().filter(|&&(p, _)| p.to_string() == wanted) {
+            try!(replies.send(&InfoMessage {
+                peer: peer,
+                hello: hello.clone(),
+            }));
+        }
+        replies.send(&InfoEndMessage)
+    });
+    service
+}
+
+/// A transport service which introduces itself with `hello`.
+pub fn transport(hello: Hello) -> MockService {
+    let mut service = MockService::new("transport");
+    service.on_message(move |_: transport::StartMessage, replies| {
+        replies.send(&hello)
+    });
+    service
+}
+
+/// A service which plays back the part of `recording` exchanged with `service`.
+///
+/// Whenever the client sends the next message the recording expects, every message the service
+/// sent in reply is sent again. Only the types of the client's messages are checked, not their
+/// bodies. A client deviating from the recording is disconnected.
+pub fn replay(recording: &Recording, service: &str) -> MockService {
+    let entries: VecDeque<Entry> = recording.service(service).entries.into_iter().collect();
+    let sent: HashSet<u16> = entries.iter()
+                                    .filter(|e| e.direction == Direction::Sent)
+                                    .map(|e| e.tpe)
+                                    .collect();
+    let entries = Rc::new(RefCell::new(entries));
+    let mut mock = MockService::new(service);
+    for tpe in sent {
+        let entries = entries.clone();
+        mock.on(tpe, Box::new(move |tpe, _, replies| {
+            let mut entries = entries.borrow_mut();
+            match entries.pop_front() {
+                Some(ref e) if e.direction == Direction::Sent && e.tpe == tpe => (),
+                Some(e) => return Err(MessageError::UnexpectedType { expected: e.tpe, received: tpe }),
+                None    => return Err(MessageError::Io {
+                    cause: io::Error::new(io::ErrorKind::UnexpectedEof, "the recording has ended"),
+                }),
+            }
+            while entries.front().map(|e| e.direction == Direction::Received).unwrap_or(false) {
+                let e = entries.pop_front().unwrap();
+                try!(replies.send_raw(e.tpe, &e.body[..]));
+            }
+            Ok(())
+        }));
+    }
+    mock
+}
@@ -1,12 +1,89 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::str::FromStr;
 use std::{u32, u64};
 use util;
 
+/// A span of time with microsecond precision, as used by GNUnet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Relative {
     micros: u64,
 }
 
+impl Relative {
+    /// A span of `micros` microseconds.
+    pub fn from_micros(micros: u64) -> Relative {
+        Relative {
+            micros: micros,
+        }
+    }
+
+    /// The length of the span in microseconds.
+    pub fn as_micros(&self) -> u64 {
+        self.micros
+    }
+
+    /// The span that never ends.
+    pub fn forever() -> Relative {
+        Relative::from_micros(u64::MAX)
+    }
+
+    /// Whether this is the span that never ends.
+    pub fn is_forever(&self) -> bool {
+        self.micros == u64::MAX
+    }
+}
+
+/// A point in time with microsecond precision, counted from the UNIX epoch.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Absolute {
+    micros: u64,
+}
+
+impl Absolute {
+    /// The current time.
+    pub fn now() -> Absolute {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        Absolute::from_micros(Relative::from(since_epoch).as_micros())
+    }
+
+    /// The time `micros` microseconds after the UNIX epoch.
+    pub fn from_micros(micros: u64) -> Absolute {
+        Absolute {
+            micros: micros,
+        }
+    }
+
+    /// The number of microseconds since the UNIX epoch.
+    pub fn as_micros(&self) -> u64 {
+        self.micros
+    }
+
+    /// The time that never comes.
+    pub fn forever() -> Absolute {
+        Absolute::from_micros(u64::MAX)
+    }
+
+    /// Whether this is the time that never comes.
+    pub fn is_forever(&self) -> bool {
+        self.micros == u64::MAX
+    }
+
+    /// The time `r` after this one. Saturates at `Absolute::forever()`.
+    pub fn add(&self, r: Relative) -> Absolute {
+        Absolute::from_micros(self.micros.saturating_add(r.micros))
+    }
+
+    /// The time left until this time, zero if it has passed.
+    pub fn remaining(&self, now: Absolute) -> Relative {
+        if self.is_forever() {
+            Relative::forever()
+        }
+        else {
+            Relative::from_micros(self.micros.saturating_sub(now.micros))
+        }
+    }
+}
+
 static RELATIVE_UNITS: [(&'static str, u64); 17] = [
     ("us", 1 ),
     ("ms", 1000 ),
@@ -41,7 +118,7 @@ impl From<Duration> for Relative {
     fn from(d: Duration) -> Relative {
         Relative {
             micros: d.as_secs().checked_mul(1000000)
-                               .and_then(|n| n.checked_add(d.subsec_nanos() as u64))
+                               .and_then(|n| n.checked_add(d.subsec_nanos() as u64 / 1000))
                                .unwrap_or(u64::MAX),
         }
     }
@@ -50,7 +127,7 @@ impl From<Duration> for Relative {
 impl From<Relative> for Duration {
     fn from(r: Relative) -> Duration {
         if r.micros == u64::MAX {
-            Duration::new(u64::MAX, u32::MAX)
+            Duration::new(u64::MAX, 999_999_999)
         }
         else {
             Duration::new(r.micros / 1000000, ((r.micros % 1000000) as u32) * 1000)
@@ -1,8 +1,11 @@
-use std::io;
-use service::{self, ReadMessageError, MessageHeader, MessageTrait};
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use service::{self, Deadline, ReadMessageError};
+use service::message::{self, Message, MessageError};
 use hello::HelloDeserializeError;
 use Hello;
 use Cfg;
+use PeerIdentity;
 use ll;
 use gj::{Promise};
 use gjio::{Network};
@@ -24,6 +27,10 @@ error_def! TransportServiceInitError {
         => "Failed to connect to the transport service" ("Reason: {}", cause),
     HelloDeserialize { #[from] cause: HelloDeserializeError }
         => "Failed to serialize the hello message from the service" ("Reason {}", cause),
+    Message { #[from] cause: MessageError }
+        => "Failed to encode or decode a message exchanged with the service" ("Reason: {}", cause),
+    TimedOut
+        => "The transport service did not send its HELLO before the deadline",
 }
 
 impl TransportService {
@@ -31,24 +38,22 @@ impl TransportService {
         service::connect(cfg, "transport", network)
             .lift()
             .then(move |(sr, mut sw)| {
-                let id = ll::Struct_GNUNET_PeerIdentity {
-                    public_key: ll::Struct_GNUNET_CRYPTO_EddsaPublicKey {
-                        q_y: [0; 32]
-                    }
+                let msg = StartMessage {
+                    options: 0,
+                    myself: None,
                 };
-                let msg = StartMessage::new(0, id);
-                sw.send(msg)
+                sw.send(&msg)
                     .lift()
                     .map(|_| {
                         Ok(sr)
                     })
             })
             .then(move |mut sr| { sr.read_message().lift() })
-            .map(move |(ty, mut mr)| {
+            .map(move |(ty, mr)| {
                 if ty != ll::GNUNET_MESSAGE_TYPE_HELLO {
                     return Err(TransportServiceInitError::NonHelloMessage { ty: ty });
                 }
-                let hello = try!(Hello::deserialize(&mut mr));
+                let hello: Hello = try!(message::decode(ty, mr));
                 Ok(TransportService {
                     our_hello: hello,
                 })
@@ -56,37 +61,79 @@ impl TransportService {
     }
 }
 
-pub fn self_hello(cfg: &Cfg, network: &Network) -> Promise<Hello, TransportServiceInitError> {
-    TransportService::init(cfg, network)
+/// Get the HELLO of our own peer. If `deadline` passes before the transport service has sent
+/// it, counting from this call, the promise is rejected with `TransportServiceInitError::TimedOut`.
+pub fn self_hello(cfg: &Cfg, network: &Network, deadline: Option<Deadline>)
+                  -> Promise<Hello, TransportServiceInitError> {
+    let hello = TransportService::init(cfg, network)
         .map(|ts| {
             Ok(ts.our_hello)
-        })
+        });
+    match deadline {
+        Some(deadline) => {
+            let expired = deadline.timer().after_delay(deadline.timeout())
+                .then_else(|_| Promise::err(TransportServiceInitError::TimedOut));
+            hello.exclusive_join(expired)
+        },
+        None => hello,
+    }
 }
 
 /// Representing StartMessage in transport.
-#[repr(C, packed)]
-struct StartMessage {
-    header: MessageHeader,
-    options: u32,
-    myself: ll::Struct_GNUNET_PeerIdentity,
+pub struct StartMessage {
+    /// Bit 0: check `myself`, bit 1: also receive the messages sent to us by other peers.
+    pub options: u32,
+    /// The identity we expect the service to have, the service checks it if `options & 1` is set.
+    pub myself: Option<PeerIdentity>,
 }
 
-impl StartMessage {
-    fn new(options: u32, peer: ll::Struct_GNUNET_PeerIdentity) -> StartMessage {
-        let len = ::std::mem::size_of::<StartMessage>();
-        StartMessage {
-            header: MessageHeader {
-                len: (len as u16).to_be(),
-                tpe: ll::GNUNET_MESSAGE_TYPE_TRANSPORT_START.to_be(),
-            },
-            options: options.to_be(),
-            myself: peer,
-        }
+impl Message for StartMessage {
+    fn message_type() -> u16 {
+        ll::GNUNET_MESSAGE_TYPE_TRANSPORT_START
     }
-}
 
-impl MessageTrait for StartMessage {
-    fn into_slice(&self) -> &[u8] {
-        message_to_slice!(StartMessage, self)
+    fn write_body<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        try!(w.write_u32::<BigEndian>(self.options));
+        match self.myself {
+            Some(ref id) => try!(id.serialize(w)),
+            None         => try!(w.write_all(&[0u8; 32])),
+        };
+        Ok(())
+    }
+
+    fn read_body<R: Read>(r: &mut R, _len: usize) -> Result<StartMessage, MessageError> {
+        let options = try!(r.read_u32::<BigEndian>());
+        let mut id = [0u8; 32];
+        try!(r.read_exact(&mut id[..]));
+        let myself = match id.iter().all(|&b| b == 0) {
+            true  => None,
+            false => Some(try!(PeerIdentity::deserialize(&mut &id[..]))),
+        };
+        Ok(StartMessage {
+            options: options,
+            myself: myself,
+        })
     }
 }
+
+#[test]
+fn test_self_hello() {
+    use std::time::Duration;
+    use testing::{self, TestPeer};
+
+    let id = PeerIdentity::deserialize(&mut &[7u8; 32][..]).unwrap();
+    let hello = Hello { friend_only: false, id: id, addresses: vec![1, 2, 3, 4] };
+
+    TestPeer::with_service(testing::transport(hello), TransportService::init, |ts, t| {
+        assert_eq!(ts.our_hello.id, id);
+
+        let hello = self_hello(t.peer.cfg(), &t.network, None);
+        let hello = t.wait(hello).unwrap();
+        assert_eq!(hello.id, id);
+        assert_eq!(hello.addresses, vec![1, 2, 3, 4]);
+
+        let deadline = Deadline::new(&t.timer, Duration::from_secs(10).into());
+        let self_id = ::peerinfo::get_self_id(t.peer.cfg(), &t.network, deadline);
+        assert_eq!(t.wait(self_id).unwrap(), id);
+    });
+}
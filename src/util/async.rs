@@ -1,6 +1,8 @@
 use std::io::{Error, ErrorKind};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
 use gj::{self};
-use gjio::{self, AsyncRead, SocketStream};
+use gjio::{self, AsyncRead, Network, SocketStream};
 use byteorder::{BigEndian, ByteOrder};
 
 pub type EventLoop = gj::EventLoop;
@@ -14,6 +16,18 @@ pub fn cancel<T, E: From<Error>>(p: Promise<T, E>) -> Promise<T, E> {
     err.lift().eagerly_evaluate().exclusive_join(p)
 }
 
+/// A connected pair of sockets for waking up an event loop from another thread.
+///
+/// The `SocketStream` is registered with `network` and reads the bytes written to the
+/// `UnixStream`, which can be sent to any thread. Dropping the `UnixStream` ends the stream.
+pub fn wake_pair(network: &Network) -> Result<(UnixStream, SocketStream), Error> {
+    let (notify, wake) = try!(UnixStream::pair());
+    try!(wake.set_nonblocking(true));
+    // SAFETY: the descriptor is taken out of `wake`, so the `SocketStream` is its only owner
+    let wake = try!(unsafe { network.wrap_raw_socket_descriptor(wake.into_raw_fd()) });
+    Ok((notify, wake))
+}
+
 impl PromiseReader for SocketStream {
     fn read_u16(&mut self) -> Promise<u16, Error> {
         self.read(vec![0;2], 2).map(|(buf, len)| {
@@ -46,3 +60,23 @@ fn test_gjio_cancel() {
         Ok(())
     }).expect("top level");
 }
+
+#[test]
+fn test_wake_pair() {
+    use std::io::Write;
+    use std::thread;
+
+    EventLoop::top_level(move |wait_scope| -> Result<(), Error> {
+        let mut event_port = EventPort::new().unwrap();
+        let network = event_port.get_network();
+        let (mut notify, mut wake) = wake_pair(&network).unwrap();
+        let t = thread::spawn(move || notify.write_all(&[7]).unwrap());
+        let (buf, n) = wake.read(vec![0; 2], 1).wait(wait_scope, &mut event_port).unwrap();
+        assert_eq!(&buf[..n], &[7]);
+        t.join().unwrap();
+        // the UnixStream was dropped by the thread
+        let (_, n) = wake.read(vec![0; 1], 1).wait(wait_scope, &mut event_port).unwrap();
+        assert_eq!(n, 0);
+        Ok(())
+    }).expect("top level");
+}